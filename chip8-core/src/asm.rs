@@ -0,0 +1,735 @@
+//! An assembler accepting the syntax printed by the tracer and disassembler.
+//!
+//! ```text
+//! ; Draws a sprite in the middle of the screen
+//! X = 1c
+//!
+//! start:
+//!     LD V0, X
+//!     LD V1, 0c
+//!     LD I, sprite
+//!     DRW V0, V1, 4
+//! loop:
+//!     JMP loop
+//!
+//! sprite:
+//!     db %11110000, 90, 90, f0
+//!
+//! include "more.asm"
+//! ```
+//!
+//! - Numbers are hex by default, as the disassembler prints them (`0a`, `0300`),
+//!   but can also be written as `0x0a`, or in binary as `%00001010`.
+//! - Registers are written `V0` to `V15`, or `VA` to `VF`.
+//! - `name:` defines a label at the current address, and `name = value` a constant.
+//!   Either can be used wherever a number is expected, along with `+` and `-`.
+//! - `db` and `dw` emit bytes and big-endian words.
+//! - `include "file"` assembles another file in place, relative to the including file.
+//! - Everything after a `;` is a comment.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// An error in the source, at `line` (counting from 1) of `file`, or 0 if the
+/// file couldn't be read at all.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}: {}", self.file, self.message)
+        } else {
+            write!(f, "{}:{}: {}", self.file, self.line, self.message)
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// Assembles the file at `path` into a program to be loaded at `origin`.
+pub fn assemble_file<P: AsRef<Path>>(path: P, origin: u16) -> Result<Vec<u8>, AsmError> {
+    let path = path.as_ref();
+    let source = fs::read_to_string(path).map_err(|err| AsmError {
+        file: path.display().to_string(),
+        line: 0,
+        message: err.to_string(),
+    })?;
+
+    let mut assembler = Assembler::new(origin);
+    assembler.parse_file(&source, path)?;
+    assembler.emit()
+}
+
+/// Assembles `source` into a program to be loaded at `origin`. Included files
+/// are looked up relative to the current directory.
+pub fn assemble(source: &str, origin: u16) -> Result<Vec<u8>, AsmError> {
+    let mut assembler = Assembler::new(origin);
+    assembler.parse_file(source, Path::new("<input>"))?;
+    assembler.emit()
+}
+
+#[derive(Debug, Clone)]
+struct Location {
+    file: String,
+    line: usize,
+}
+
+impl Location {
+    fn error<S: Into<String>>(&self, message: S) -> AsmError {
+        AsmError { file: self.file.clone(), line: self.line, message: message.into() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Operand {
+    Reg(u16),
+    RegRange(u16, u16),
+    I,
+    // `[I]`, the memory pointed to by I
+    IndirectI,
+    // `LONG nnnn`, the 16-bit address of XO-CHIP's `F000 nnnn`
+    Long(String),
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    R,
+    Audio,
+    Pitch,
+    Value(String),
+}
+
+#[derive(Debug)]
+enum Statement {
+    Instruction { mnemonic: String, operands: Vec<Operand> },
+    Data { width: usize, values: Vec<String> },
+}
+
+struct Assembler {
+    origin: u16,
+    // Address of the next statement
+    addr: u32,
+    statements: Vec<(Location, Statement)>,
+    symbols: HashMap<String, u16>,
+    // Constants are evaluated once all labels are known, so they can refer to them
+    constants: Vec<(Location, String, String)>,
+    // Files currently being parsed, to catch recursive includes
+    include_stack: Vec<PathBuf>,
+}
+
+impl Assembler {
+    fn new(origin: u16) -> Assembler {
+        Assembler {
+            origin,
+            addr: origin as u32,
+            statements: Vec::new(),
+            symbols: HashMap::new(),
+            constants: Vec::new(),
+            include_stack: Vec::new(),
+        }
+    }
+
+    // First pass: parses every statement and assigns addresses to labels
+    fn parse_file(&mut self, source: &str, path: &Path) -> Result<(), AsmError> {
+        self.include_stack.push(path.to_path_buf());
+
+        for (idx, line) in source.lines().enumerate() {
+            let loc = Location { file: path.display().to_string(), line: idx + 1 };
+            self.parse_line(line, &loc, path)?;
+        }
+
+        self.include_stack.pop();
+        Ok(())
+    }
+
+    fn parse_line(&mut self, line: &str, loc: &Location, path: &Path) -> Result<(), AsmError> {
+        let mut line = strip_comment(line).trim();
+
+        if let Some(idx) = line.find(':') {
+            let label = line[..idx].trim();
+            if is_identifier(label) {
+                self.define(loc, label, self.addr as u16)?;
+                line = line[idx + 1..].trim();
+            }
+        }
+
+        if line.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(idx) = line.find('=') {
+            let name = line[..idx].trim();
+            if !is_identifier(name) {
+                return Err(loc.error(format!("invalid constant name '{}'", name)));
+            }
+
+            self.constants.push((loc.clone(), name.to_string(), line[idx + 1..].trim().to_string()));
+            return Ok(());
+        }
+
+        let (mnemonic, rest) = match line.find(char::is_whitespace) {
+            Some(idx) => (&line[..idx], line[idx..].trim()),
+            None => (line, ""),
+        };
+        let mnemonic = mnemonic.to_uppercase();
+
+        let statement = match mnemonic.as_str() {
+            "INCLUDE" => return self.include(rest, loc, path),
+            "DB" | "DW" => Statement::Data {
+                width: if mnemonic == "DB" { 1 } else { 2 },
+                values: split_operands(rest, loc)?,
+            },
+            _ => Statement::Instruction {
+                operands: split_operands(rest, loc)?
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, op)| parse_operand(op, idx == 0))
+                    .collect(),
+                mnemonic,
+            },
+        };
+
+        let len = match &statement {
+            Statement::Instruction { operands, .. } if operands.iter().any(|op| matches!(op, Operand::Long(_))) => 4,
+            Statement::Instruction { .. } => 2,
+            Statement::Data { width, values } => width * values.len(),
+        };
+
+        if self.addr + len as u32 > 0x10000 {
+            return Err(loc.error("program doesn't fit in memory"));
+        }
+
+        self.statements.push((loc.clone(), statement));
+        self.addr += len as u32;
+        Ok(())
+    }
+
+    fn include(&mut self, operand: &str, loc: &Location, path: &Path) -> Result<(), AsmError> {
+        let name = match operand.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+            Some(name) => name,
+            None => return Err(loc.error("expected a quoted file name after include")),
+        };
+
+        let included = path.parent().unwrap_or_else(|| Path::new("")).join(name);
+        if self.include_stack.contains(&included) {
+            return Err(loc.error(format!("'{}' includes itself", name)));
+        }
+
+        let source = fs::read_to_string(&included)
+            .map_err(|err| loc.error(format!("failed to include '{}': {}", name, err)))?;
+        self.parse_file(&source, &included)
+    }
+
+    fn define(&mut self, loc: &Location, name: &str, value: u16) -> Result<(), AsmError> {
+        if self.symbols.insert(name.to_string(), value).is_some() {
+            return Err(loc.error(format!("'{}' is already defined", name)));
+        }
+
+        Ok(())
+    }
+
+    // Second pass: evaluates constants and operands, and encodes every statement
+    fn emit(mut self) -> Result<Vec<u8>, AsmError> {
+        for (loc, name, expr) in std::mem::take(&mut self.constants) {
+            let value = evaluate(&expr, &self.symbols, 0xFFFF).map_err(|err| loc.error(err))?;
+            self.define(&loc, &name, value)?;
+        }
+
+        let mut program = Vec::with_capacity((self.addr - self.origin as u32) as usize);
+        for (loc, statement) in &self.statements {
+            let bytes = match statement {
+                Statement::Instruction { mnemonic, operands } => encode(mnemonic, operands, &self.symbols),
+                Statement::Data { width, values } => values.iter()
+                    .map(|value| {
+                        let value = evaluate(value, &self.symbols, if *width == 1 { 0xFF } else { 0xFFFF })?;
+                        Ok(value.to_be_bytes()[2 - width..].to_vec())
+                    })
+                    .collect::<Result<Vec<Vec<u8>>, String>>()
+                    .map(|values| values.concat()),
+            };
+
+            program.extend(bytes.map_err(|err| loc.error(err))?);
+        }
+
+        Ok(program)
+    }
+}
+
+const MNEMONICS: &[&str] = &[
+    "ADD", "AND", "CALL", "CLS", "DRW", "EXIT", "HIGH", "JMP", "JP", "LD", "LOW", "OR", "PLANE", "RET",
+    "RND", "SCD", "SCL", "SCR", "SCU", "SE", "SHL", "SHR", "SKNP", "SKP", "SNE", "SUB", "SUBN", "SYS", "XOR",
+];
+
+// Encodes an instruction, returning its bytes
+fn encode(mnemonic: &str, operands: &[Operand], symbols: &HashMap<String, u16>) -> Result<Vec<u8>, String> {
+    use Operand::*;
+
+    let value = |expr: &str, max: u16| evaluate(expr, symbols, max);
+
+    // Nibbles are written as a single hex digit, so `f` and `b` aren't the
+    // F and B operands here
+    let nibble = |operand: &Operand| match operand {
+        Value(n) => value(n, 0xF),
+        F => Ok(0xF),
+        B => Ok(0xB),
+        _ => Err("expected a number".to_string()),
+    };
+
+    let opcode = match (mnemonic, operands) {
+        ("CLS", []) => 0x00E0,
+        ("RET", []) => 0x00EE,
+        ("SCD", [n]) => 0x00C0 | nibble(n)?,
+        ("SCU", [n]) => 0x00D0 | nibble(n)?,
+        ("SCR", []) => 0x00FB,
+        ("SCL", []) => 0x00FC,
+        ("EXIT", []) => 0x00FD,
+        ("LOW", []) => 0x00FE,
+        ("HIGH", []) => 0x00FF,
+        ("SYS", [Value(addr)]) => value(addr, 0xFFF)?,
+        ("JMP", [Value(addr)]) | ("JP", [Value(addr)]) => 0x1000 | value(addr, 0xFFF)?,
+        ("CALL", [Value(addr)]) => 0x2000 | value(addr, 0xFFF)?,
+        ("SE", [Reg(x), Value(byte)]) => 0x3000 | x << 8 | value(byte, 0xFF)?,
+        ("SNE", [Reg(x), Value(byte)]) => 0x4000 | x << 8 | value(byte, 0xFF)?,
+        ("SE", [Reg(x), Reg(y)]) => 0x5000 | x << 8 | y << 4,
+        ("LD", [IndirectI, RegRange(x, y)]) => 0x5002 | x << 8 | y << 4,
+        ("LD", [RegRange(x, y), IndirectI]) => 0x5003 | x << 8 | y << 4,
+        ("LD", [Reg(x), Value(byte)]) => 0x6000 | x << 8 | value(byte, 0xFF)?,
+        ("ADD", [Reg(x), Value(byte)]) => 0x7000 | x << 8 | value(byte, 0xFF)?,
+        ("LD", [Reg(x), Reg(y)]) => 0x8000 | x << 8 | y << 4,
+        ("OR", [Reg(x), Reg(y)]) => 0x8001 | x << 8 | y << 4,
+        ("AND", [Reg(x), Reg(y)]) => 0x8002 | x << 8 | y << 4,
+        ("XOR", [Reg(x), Reg(y)]) => 0x8003 | x << 8 | y << 4,
+        ("ADD", [Reg(x), Reg(y)]) => 0x8004 | x << 8 | y << 4,
+        ("SUB", [Reg(x), Reg(y)]) => 0x8005 | x << 8 | y << 4,
+        ("SHR", [Reg(x)]) => 0x8006 | x << 8,
+        ("SHR", [Reg(x), Reg(y)]) => 0x8006 | x << 8 | y << 4,
+        ("SUBN", [Reg(x), Reg(y)]) => 0x8007 | x << 8 | y << 4,
+        ("SHL", [Reg(x)]) => 0x800E | x << 8,
+        ("SHL", [Reg(x), Reg(y)]) => 0x800E | x << 8 | y << 4,
+        ("SNE", [Reg(x), Reg(y)]) => 0x9000 | x << 8 | y << 4,
+        ("LD", [I, Value(addr)]) => 0xA000 | value(addr, 0xFFF)?,
+        ("JP", [Reg(x), Value(addr)]) => {
+            // With the jump quirk the register is taken from the address, so
+            // anything but V0 has to agree with it
+            let addr = value(addr, 0xFFF)?;
+            if *x != 0 && addr >> 8 != *x {
+                return Err(format!("JP V{} needs an address from {:x}00 to {:x}ff", x, x, x));
+            }
+            0xB000 | addr
+        },
+        ("RND", [Reg(x), Value(byte)]) => 0xC000 | x << 8 | value(byte, 0xFF)?,
+        ("DRW", [Reg(x), Reg(y), n]) => 0xD000 | x << 8 | y << 4 | nibble(n)?,
+        ("SKP", [Reg(x)]) => 0xE09E | x << 8,
+        ("SKNP", [Reg(x)]) => 0xE0A1 | x << 8,
+        ("LD", [I, Long(addr)]) => {
+            let addr = value(addr, 0xFFFF)?;
+            return Ok(vec![0xF0, 0x00, (addr >> 8) as u8, addr as u8]);
+        },
+        ("PLANE", [n]) => 0xF001 | nibble(n)? << 8,
+        ("LD", [Audio, IndirectI]) => 0xF002,
+        ("LD", [Reg(x), Dt]) => 0xF007 | x << 8,
+        ("LD", [Reg(x), K]) => 0xF00A | x << 8,
+        ("LD", [Dt, Reg(x)]) => 0xF015 | x << 8,
+        ("LD", [St, Reg(x)]) => 0xF018 | x << 8,
+        ("ADD", [I, Reg(x)]) => 0xF01E | x << 8,
+        ("LD", [F, Reg(x)]) => 0xF029 | x << 8,
+        ("LD", [Hf, Reg(x)]) => 0xF030 | x << 8,
+        ("LD", [B, Reg(x)]) => 0xF033 | x << 8,
+        ("LD", [Pitch, Reg(x)]) => 0xF03A | x << 8,
+        ("LD", [IndirectI, Reg(x)]) => 0xF055 | x << 8,
+        ("LD", [Reg(x), IndirectI]) => 0xF065 | x << 8,
+        ("LD", [R, Reg(x)]) => 0xF075 | x << 8,
+        ("LD", [Reg(x), R]) => 0xF085 | x << 8,
+        _ if MNEMONICS.contains(&mnemonic) => return Err(format!("invalid operands for {}", mnemonic)),
+        _ => return Err(format!("unknown instruction '{}'", mnemonic)),
+    };
+
+    Ok(opcode.to_be_bytes().to_vec())
+}
+
+// Evaluates a sum of numbers and symbols, e.g. `sprite + 0a`
+fn evaluate(expr: &str, symbols: &HashMap<String, u16>, max: u16) -> Result<u16, String> {
+    let mut total: i64 = 0;
+    let mut sign = 1;
+    let mut term = String::new();
+
+    for c in expr.chars().chain(std::iter::once('+')) {
+        if c == '+' || c == '-' {
+            let term = std::mem::take(&mut term);
+            total += sign * term_value(term.trim(), symbols)? as i64;
+            sign = if c == '+' { 1 } else { -1 };
+        } else {
+            term.push(c);
+        }
+    }
+
+    if total < 0 || total > max as i64 {
+        return Err(format!("'{}' is out of range, it must be at most {:x}", expr, max));
+    }
+
+    Ok(total as u16)
+}
+
+fn term_value(term: &str, symbols: &HashMap<String, u16>) -> Result<u32, String> {
+    if term.is_empty() {
+        return Err("expected a number".to_string());
+    }
+
+    if let Some(value) = symbols.get(term) {
+        return Ok(*value as u32);
+    }
+
+    let (digits, radix) = if let Some(digits) = term.strip_prefix("0x").or_else(|| term.strip_prefix("0X")) {
+        (digits, 16)
+    } else if let Some(digits) = term.strip_prefix('%') {
+        (digits, 2)
+    } else {
+        (term, 16)
+    };
+
+    match u32::from_str_radix(digits, radix) {
+        Ok(value) => Ok(value),
+        Err(_) if is_identifier(term) => Err(format!("unknown symbol '{}'", term)),
+        Err(_) => Err(format!("invalid number '{}'", term)),
+    }
+}
+
+// F and B only ever come first, in `LD F, Vx` and `LD B, Vx`, so anywhere
+// else `f` and `b` are the hex numbers
+fn parse_operand(operand: &str, first: bool) -> Operand {
+    let upper = operand.to_uppercase();
+
+    match upper.as_str() {
+        "I" => return Operand::I,
+        "[I]" => return Operand::IndirectI,
+        "DT" => return Operand::Dt,
+        "ST" => return Operand::St,
+        "K" => return Operand::K,
+        "F" if first => return Operand::F,
+        "HF" => return Operand::Hf,
+        "B" if first => return Operand::B,
+        "R" => return Operand::R,
+        "AUDIO" => return Operand::Audio,
+        "PITCH" => return Operand::Pitch,
+        _ => (),
+    }
+
+    if upper.starts_with("LONG ") {
+        return Operand::Long(operand[5..].trim().to_string());
+    }
+
+    if let Some(idx) = operand.find('-') {
+        if let (Some(x), Some(y)) = (parse_register(&operand[..idx]), parse_register(&operand[idx + 1..])) {
+            return Operand::RegRange(x, y);
+        }
+    }
+
+    match parse_register(operand) {
+        Some(x) => Operand::Reg(x),
+        None => Operand::Value(operand.to_string()),
+    }
+}
+
+// Parses `V0` to `V15`, or `VA` to `VF`
+fn parse_register(name: &str) -> Option<u16> {
+    let name = name.trim();
+    let idx = name.strip_prefix('V').or_else(|| name.strip_prefix('v'))?;
+
+    let reg = if idx.len() == 1 {
+        u16::from_str_radix(idx, 16).ok()?
+    } else {
+        idx.parse().ok()?
+    };
+
+    if reg < 16 {
+        Some(reg)
+    } else {
+        None
+    }
+}
+
+fn split_operands(operands: &str, loc: &Location) -> Result<Vec<String>, AsmError> {
+    if operands.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    operands.split(',')
+        .map(|op| match op.trim() {
+            "" => Err(loc.error("missing operand")),
+            op => Ok(op.to_string()),
+        })
+        .collect()
+}
+
+fn strip_comment(line: &str) -> &str {
+    match line.find(';') {
+        Some(idx) => &line[..idx],
+        None => line,
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm;
+    use crate::quirks::Quirks;
+
+    fn assemble_ok(source: &str) -> Vec<u8> {
+        assemble(source, 0x200).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    fn assemble_err(source: &str) -> AsmError {
+        assemble(source, 0x200).expect_err("source should fail to assemble")
+    }
+
+    // A temporary directory for include tests, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!("chip8-core-asm-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn write(&self, name: &str, source: &str) -> PathBuf {
+            let path = self.0.join(name);
+            fs::write(&path, source).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn encodes_every_instruction() {
+        let cases: &[(&str, &[u8])] = &[
+            ("CLS", &[0x00, 0xE0]),
+            ("RET", &[0x00, 0xEE]),
+            ("SCD 4", &[0x00, 0xC4]),
+            ("SCU f", &[0x00, 0xDF]),
+            ("SCR", &[0x00, 0xFB]),
+            ("SCL", &[0x00, 0xFC]),
+            ("EXIT", &[0x00, 0xFD]),
+            ("LOW", &[0x00, 0xFE]),
+            ("HIGH", &[0x00, 0xFF]),
+            ("SYS 123", &[0x01, 0x23]),
+            ("JMP 0456", &[0x14, 0x56]),
+            ("JP 0456", &[0x14, 0x56]),
+            ("CALL 0789", &[0x27, 0x89]),
+            ("SE V1, 0a", &[0x31, 0x0A]),
+            ("SNE V2, ff", &[0x42, 0xFF]),
+            ("SE V3, V4", &[0x53, 0x40]),
+            ("LD [I], V1-V3", &[0x51, 0x32]),
+            ("LD V1-V3, [I]", &[0x51, 0x33]),
+            ("LD V5, 12", &[0x65, 0x12]),
+            ("ADD V6, 1", &[0x76, 0x01]),
+            ("LD V7, V8", &[0x87, 0x80]),
+            ("OR V7, V8", &[0x87, 0x81]),
+            ("AND V7, V8", &[0x87, 0x82]),
+            ("XOR V7, V8", &[0x87, 0x83]),
+            ("ADD V7, V8", &[0x87, 0x84]),
+            ("SUB V7, V8", &[0x87, 0x85]),
+            ("SHR V7", &[0x87, 0x06]),
+            ("SHR V7, V8", &[0x87, 0x86]),
+            ("SUBN V7, V8", &[0x87, 0x87]),
+            ("SHL V7", &[0x87, 0x0E]),
+            ("SHL V7, V8", &[0x87, 0x8E]),
+            ("SNE V9, VA", &[0x99, 0xA0]),
+            ("LD I, 0abc", &[0xAA, 0xBC]),
+            ("JP V0, 0300", &[0xB3, 0x00]),
+            ("JP V3, 0345", &[0xB3, 0x45]),
+            ("RND VB, 0f", &[0xCB, 0x0F]),
+            ("DRW V0, V1, 5", &[0xD0, 0x15]),
+            ("DRW V0, V1, f", &[0xD0, 0x1F]),
+            ("SKP VC", &[0xEC, 0x9E]),
+            ("SKNP VD", &[0xED, 0xA1]),
+            ("LD I, LONG 1234", &[0xF0, 0x00, 0x12, 0x34]),
+            ("PLANE 3", &[0xF3, 0x01]),
+            ("LD AUDIO, [I]", &[0xF0, 0x02]),
+            ("LD VE, DT", &[0xFE, 0x07]),
+            ("LD VE, K", &[0xFE, 0x0A]),
+            ("LD DT, VE", &[0xFE, 0x15]),
+            ("LD ST, VE", &[0xFE, 0x18]),
+            ("ADD I, VE", &[0xFE, 0x1E]),
+            ("LD F, VE", &[0xFE, 0x29]),
+            ("LD HF, VE", &[0xFE, 0x30]),
+            ("LD B, VE", &[0xFE, 0x33]),
+            ("LD PITCH, VE", &[0xFE, 0x3A]),
+            ("LD [I], VE", &[0xFE, 0x55]),
+            ("LD VE, [I]", &[0xFE, 0x65]),
+            ("LD R, VE", &[0xFE, 0x75]),
+            ("LD VE, R", &[0xFE, 0x85]),
+        ];
+
+        for (source, bytes) in cases {
+            assert_eq!(assemble(source, 0x200).as_deref(), Ok(*bytes), "{}", source);
+        }
+
+        // Every mnemonic is covered above
+        for mnemonic in MNEMONICS {
+            assert!(cases.iter().any(|(source, _)| source.split(' ').next() == Some(*mnemonic)), "{}", mnemonic);
+        }
+    }
+
+    #[test]
+    fn registers_and_numbers() {
+        assert_eq!(assemble_ok("ld v15, 0x0a\nld VA, %1010\nld v0, 0a"), vec![0x6F, 0x0A, 0x6A, 0x0A, 0x60, 0x0A]);
+    }
+
+    #[test]
+    fn single_digit_f_and_b_are_numbers() {
+        assert_eq!(
+            assemble_ok("LD V1, f\nADD V1, b\nSE V1, b\nDRW V1, V2, f"),
+            vec![0x61, 0x0F, 0x71, 0x0B, 0x31, 0x0B, 0xD1, 0x2F]
+        );
+        assert_eq!(assemble_ok("LD F, V1\nLD b, V1"), vec![0xF1, 0x29, 0xF1, 0x33]);
+    }
+
+    #[test]
+    fn forward_labels_and_constants() {
+        let source = "\
+start:
+    JP end
+    LD I, sprite + 2
+END_X = end - start
+    LD V0, END_X
+sprite: db f0, 90
+end: JP start
+";
+        assert_eq!(
+            assemble_ok(source),
+            vec![0x12, 0x08, 0xA2, 0x08, 0x60, 0x08, 0xF0, 0x90, 0x12, 0x00],
+        );
+    }
+
+    #[test]
+    fn data() {
+        assert_eq!(assemble_ok("db 1, ff, %10000001\ndw 1234, label\nlabel:"), vec![1, 0xFF, 0x81, 0x12, 0x34, 0x02, 0x07]);
+    }
+
+    #[test]
+    fn include() {
+        let dir = TempDir::new("include");
+        dir.write("sprites.asm", "sprite:\n    db 3c, 42\n");
+        let main = dir.write("main.asm", "    LD I, sprite\ninclude \"sprites.asm\"\n    JP sprite\n");
+
+        assert_eq!(assemble_file(&main, 0x200), Ok(vec![0xA2, 0x02, 0x3C, 0x42, 0x12, 0x02]));
+    }
+
+    #[test]
+    fn include_errors() {
+        let dir = TempDir::new("include-errors");
+        let looped = dir.write("loop.asm", "CLS\ninclude \"loop.asm\"\n");
+        let err = assemble_file(&looped, 0x200).unwrap_err();
+        assert_eq!(err.line, 2);
+        assert!(err.message.contains("includes itself"), "{}", err);
+
+        // Errors in an included file point into it
+        let bad = dir.write("bad.asm", "CLS\n\nBOGUS\n");
+        let main = dir.write("main.asm", "include \"bad.asm\"\n");
+        let err = assemble_file(&main, 0x200).unwrap_err();
+        assert_eq!((err.file, err.line), (bad.display().to_string(), 3));
+
+        let missing = dir.write("missing.asm", "CLS\ninclude \"nowhere.asm\"\n");
+        assert_eq!(assemble_file(&missing, 0x200).unwrap_err().line, 2);
+
+        let err = assemble_file(dir.0.join("nowhere.asm"), 0x200).unwrap_err();
+        assert_eq!(err.line, 0);
+    }
+
+    #[test]
+    fn errors_report_their_line() {
+        let cases: &[(&str, usize, &str)] = &[
+            ("CLS\nFOO V0\n", 2, "unknown instruction 'FOO'"),
+            ("CLS\n\nLD V0\n", 3, "invalid operands for LD"),
+            ("CLS\nJP nowhere\n", 2, "unknown symbol 'nowhere'"),
+            ("a:\nCLS\na:\n", 3, "'a' is already defined"),
+            ("LD V0, 100\n", 1, "out of range"),
+            ("CLS\nLD V0, 1g\n", 2, "invalid number '1g'"),
+            ("CLS\n\n\nLD V0,\n", 4, "missing operand"),
+            ("CLS\n1x = 2\n", 2, "invalid constant name '1x'"),
+            ("; comment\nX = Y\nY = 1\n", 2, "unknown symbol 'Y'"),
+            ("JP V2, 0345\n", 1, "JP V2 needs an address"),
+            ("db 100\n", 1, "out of range"),
+        ];
+
+        for (source, line, message) in cases {
+            let err = assemble_err(source);
+            assert_eq!(err.line, *line, "{:?}: {}", source, err);
+            assert!(err.message.contains(message), "{:?}: {}", source, err);
+            assert_eq!(err.to_string(), format!("<input>:{}: {}", line, err.message));
+        }
+    }
+
+    #[test]
+    fn program_must_fit_in_memory() {
+        let source = format!("dw {}", vec!["0"; 0x7F00].join(", "));
+        assert_eq!(assemble(&source, 0x200).map(|program| program.len()), Ok(0xFE00));
+
+        let err = assemble_err(&format!("{}\nCLS", source));
+        assert_eq!((err.line, err.message.as_str()), (2, "program doesn't fit in memory"));
+    }
+
+    fn assert_round_trip(name: &str, program: &[u8], origin: u16) {
+        let platform = disasm::detect_platform(program, origin);
+        let listing = disasm::disassemble(program, origin, platform, &Quirks::default()).to_string();
+        match assemble(&listing, origin) {
+            Ok(assembled) => assert!(assembled == program, "{} doesn't reassemble to the same bytes", name),
+            Err(err) => panic!("{}: {}\n{}", name, err, listing),
+        }
+    }
+
+    #[test]
+    fn disassembled_roms_reassemble() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../roms");
+        let mut roms = 0;
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext == "ch8") {
+                assert_round_trip(&path.display().to_string(), &fs::read(&path).unwrap(), 0x200);
+                roms += 1;
+            }
+        }
+        assert!(roms > 0);
+    }
+
+    #[test]
+    fn disassembled_noise_reassembles() {
+        // Arbitrary bytes exercise every opcode, including invalid ones left as data
+        let mut state: u32 = 0x1234_5678;
+        let noise: Vec<u8> = (0..0x4000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+
+        assert_round_trip("noise", &noise, 0x200);
+        assert_round_trip("noise at 600", &noise[..0x800], 0x600);
+    }
+}
@@ -0,0 +1,301 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// Pitch of the beeper when the program hasn't supplied its own audio pattern
+const TONE_HZ: f64 = 440.0;
+const VOLUME: f32 = 0.25;
+
+// Rate at which the audio is generated, matching the display refresh rate
+const FRAMES_PER_SECOND: u32 = 60;
+
+/// Something that can play back the beeper's output.
+pub trait AudioSink {
+    /// Number of samples per second the sink expects.
+    fn sample_rate(&self) -> u32;
+
+    /// Plays mono samples in the range -1.0 to 1.0.
+    fn play(&mut self, samples: &[f32]);
+
+    /// Finishes playing or writing the audio, returning the first error that
+    /// occurred while doing so.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Generates the beeper's output, one display refresh at a time. CHIP-8 only has a
+/// fixed tone, which is generated as a square wave, while XO-CHIP programs provide
+/// a 128-bit pattern to loop over.
+pub struct Beeper {
+    sample_rate: u32,
+    // Position within the current waveform, in cycles or pattern bits
+    phase: f64,
+    // Samples owed from previous frames, since a frame isn't always a whole
+    // number of samples long
+    remainder: f64,
+}
+
+impl Beeper {
+    pub fn new(sample_rate: u32) -> Beeper {
+        Beeper { sample_rate, phase: 0.0, remainder: 0.0 }
+    }
+
+    /// Generates one frame's worth of samples. The beeper is silent unless
+    /// `active`, i.e. the sound timer is non-zero.
+    pub fn frame(&mut self, active: bool, pattern: Option<(&[u8; 16], u8)>) -> Vec<f32> {
+        let samples_per_frame = self.sample_rate as f64 / FRAMES_PER_SECOND as f64 + self.remainder;
+        let len = samples_per_frame as usize;
+        self.remainder = samples_per_frame - len as f64;
+
+        if !active {
+            self.phase = 0.0;
+            return vec![0.0; len];
+        }
+
+        match pattern {
+            Some((pattern, pitch)) => self.pattern_wave(len, pattern, pitch),
+            None => self.square_wave(len),
+        }
+    }
+
+    fn square_wave(&mut self, len: usize) -> Vec<f32> {
+        let step = TONE_HZ / self.sample_rate as f64;
+
+        (0..len)
+            .map(|_| {
+                let high = self.phase < 0.5;
+                self.phase = (self.phase + step) % 1.0;
+
+                if high { VOLUME } else { -VOLUME }
+            })
+            .collect()
+    }
+
+    fn pattern_wave(&mut self, len: usize, pattern: &[u8; 16], pitch: u8) -> Vec<f32> {
+        // XO-CHIP plays the pattern back at 4000 bits per second at the default pitch
+        // of 64, with every 48 steps of pitch being an octave
+        let bits_per_second = 4000.0 * 2f64.powf((pitch as f64 - 64.0) / 48.0);
+        let step = bits_per_second / self.sample_rate as f64;
+        let pattern_bits = (pattern.len() * 8) as f64;
+
+        (0..len)
+            .map(|_| {
+                let bit = self.phase as usize;
+                let high = pattern[bit / 8] & (0x80 >> (bit % 8)) != 0;
+                self.phase = (self.phase + step) % pattern_bits;
+
+                if high { VOLUME } else { -VOLUME }
+            })
+            .collect()
+    }
+}
+
+/// Discards all audio, for when there is no audio device available.
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn sample_rate(&self) -> u32 {
+        44100
+    }
+
+    fn play(&mut self, _samples: &[f32]) {}
+}
+
+/// Records audio to a 16-bit mono PCM WAV file, so the output can be checked
+/// without an audio device.
+pub struct WavSink {
+    file: BufWriter<File>,
+    sample_rate: u32,
+    samples_written: u32,
+    // The first error writing samples, after which no more are written
+    error: Option<io::Error>,
+}
+
+impl WavSink {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32) -> io::Result<WavSink> {
+        let mut sink = WavSink {
+            file: BufWriter::new(File::create(path)?),
+            sample_rate,
+            samples_written: 0,
+            error: None,
+        };
+
+        // The header's sizes are filled in once the length of the audio is known
+        sink.write_header()?;
+
+        Ok(sink)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let data_len = self.samples_written * 2;
+        let file = &mut self.file;
+
+        file.write_all(b"RIFF")?;
+        file.write_all(&(36 + data_len).to_le_bytes())?;
+        file.write_all(b"WAVE")?;
+
+        file.write_all(b"fmt ")?;
+        file.write_all(&16u32.to_le_bytes())?; // size of this chunk
+        file.write_all(&1u16.to_le_bytes())?; // PCM
+        file.write_all(&1u16.to_le_bytes())?; // mono
+        file.write_all(&self.sample_rate.to_le_bytes())?;
+        file.write_all(&(self.sample_rate * 2).to_le_bytes())?; // bytes per second
+        file.write_all(&2u16.to_le_bytes())?; // bytes per sample
+        file.write_all(&16u16.to_le_bytes())?; // bits per sample
+
+        file.write_all(b"data")?;
+        file.write_all(&data_len.to_le_bytes())?;
+
+        Ok(())
+    }
+}
+
+impl AudioSink for WavSink {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn play(&mut self, samples: &[f32]) {
+        if self.error.is_some() {
+            return;
+        }
+
+        for sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            if let Err(err) = self.file.write_all(&value.to_le_bytes()) {
+                self.error = Some(err);
+                return;
+            }
+
+            self.samples_written += 1;
+        }
+    }
+
+    /// Updates the header with the final length of the audio, or returns the
+    /// first error writing samples.
+    fn finish(&mut self) -> io::Result<()> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+
+        self.file.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+}
+
+impl Drop for WavSink {
+    /// Finishes the file if [`AudioSink::finish`] wasn't called, ignoring any
+    /// errors, which can only be reported by calling it.
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_carry_over_fractional_samples() {
+        let mut beeper = Beeper::new(44100);
+        assert!((0..60).all(|_| beeper.frame(false, None).len() == 735));
+
+        // 880Hz is 14 2/3 samples per frame, which adds up over a second, give
+        // or take a sample owed to the next frame
+        let mut beeper = Beeper::new(880);
+        let lens: Vec<usize> = (0..60).map(|_| beeper.frame(false, None).len()).collect();
+        assert!(lens.iter().all(|len| *len == 14 || *len == 15));
+        assert!((879..=880).contains(&lens.iter().sum::<usize>()));
+    }
+
+    #[test]
+    fn silent_unless_active() {
+        let mut beeper = Beeper::new(44100);
+        assert!(beeper.frame(false, None).iter().all(|sample| *sample == 0.0));
+    }
+
+    #[test]
+    fn square_wave() {
+        // At 880Hz the 440Hz tone alternates every sample
+        let mut beeper = Beeper::new(880);
+        let samples = beeper.frame(true, None);
+        assert_eq!(samples.len(), 14);
+        for (idx, sample) in samples.iter().enumerate() {
+            assert_eq!(*sample, if idx % 2 == 0 { VOLUME } else { -VOLUME });
+        }
+    }
+
+    #[test]
+    fn pattern_wave() {
+        // At the default pitch the pattern plays at 4000 bits per second, so
+        // one bit per sample at 4000Hz
+        let mut pattern = [0; 16];
+        pattern[0] = 0xF0;
+        pattern[15] = 0x01;
+
+        let mut beeper = Beeper::new(4000);
+        let samples: Vec<f32> = (0..3).flat_map(|_| beeper.frame(true, Some((&pattern, 64)))).collect();
+        assert_eq!(samples.len(), 200);
+
+        let high: Vec<usize> = (0..samples.len()).filter(|idx| samples[*idx] == VOLUME).collect();
+        assert_eq!(high, vec![0, 1, 2, 3, 127, 128, 129, 130, 131]);
+        assert!(samples.iter().all(|sample| *sample == VOLUME || *sample == -VOLUME));
+    }
+
+    #[test]
+    fn pattern_pitch_doubles_every_48_steps() {
+        // An octave up plays two bits per sample
+        let mut pattern = [0; 16];
+        pattern[0] = 0xAA;
+
+        let mut beeper = Beeper::new(4000);
+        let samples = beeper.frame(true, Some((&pattern, 112)));
+        assert_eq!(&samples[..4], &[VOLUME, VOLUME, VOLUME, VOLUME]);
+        assert_eq!(samples[4], -VOLUME);
+    }
+
+    #[test]
+    fn wav_header() {
+        let path = std::env::temp_dir().join(format!("chip8-core-wav-test-{}.wav", std::process::id()));
+        let mut sink = WavSink::create(&path, 22050).unwrap();
+        sink.play(&[1.0, -1.0, 0.0, 2.0]);
+        sink.finish().unwrap();
+        drop(sink);
+        let wav = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let u16_at = |idx: usize| u16::from_le_bytes([wav[idx], wav[idx + 1]]);
+        let u32_at = |idx: usize| u32::from_le_bytes([wav[idx], wav[idx + 1], wav[idx + 2], wav[idx + 3]]);
+
+        assert_eq!(wav.len(), 44 + 8);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(u32_at(4), 36 + 8);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(16), 16);
+        assert_eq!(u16_at(20), 1);
+        assert_eq!(u16_at(22), 1);
+        assert_eq!(u32_at(24), 22050);
+        assert_eq!(u32_at(28), 44100);
+        assert_eq!(u16_at(32), 2);
+        assert_eq!(u16_at(34), 16);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(u32_at(40), 8);
+
+        let samples: Vec<i16> = wav[44..].chunks(2).map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]])).collect();
+        assert_eq!(samples, vec![i16::MAX, -i16::MAX, 0, i16::MAX]);
+    }
+
+    // Errors are kept for `finish` to return, rather than reported by the sink
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn wav_write_errors() {
+        let mut sink = WavSink::create("/dev/full", 44100).unwrap();
+        // More than fits in the write buffer, so the samples reach the file
+        sink.play(&[0.5; 10000]);
+        sink.play(&[0.5; 10000]);
+        assert!(sink.finish().is_err());
+    }
+}
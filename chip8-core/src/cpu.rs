@@ -0,0 +1,1197 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::ops::Range;
+
+use crate::disasm::{self, Instruction};
+use crate::display::Display;
+use crate::keypad::{KeyEvent, Keypad};
+use crate::platform::Platform;
+use crate::quirks::{LoadStoreIncrement, Quirks};
+use crate::random::Random;
+use crate::rom::{self, RomError, RomWarning};
+use crate::trace::{TraceEntry, Tracer};
+
+mod state;
+
+pub use state::StateError;
+
+/// What happened during a call to [`Cpu::cycle`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CycleOutcome {
+    /// An instruction was executed, and the display is unchanged.
+    Executed,
+    /// An instruction was executed, and the display needs redrawing.
+    Redraw,
+    /// Execution is blocked on `Fx0A` until a key is pressed, or released with
+    /// the `key_wait_release` quirk. The timers keep counting down meanwhile.
+    AwaitingKeyPress,
+    /// Execution is blocked after `Dxyn` until the next display refresh.
+    AwaitingRefresh,
+    /// The program has exited with `00FD`.
+    Exited,
+}
+
+// Start of the SUPER-CHIP 8x10 font in memory, placed right after the 8x5 font
+const BIG_FONT_START: usize = 0x50;
+
+/// A fault raised while executing the instruction at `pc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuError {
+    /// The opcode isn't an instruction on the cpu's platform.
+    UnknownOpcode { pc: u16, opcode: u16 },
+    /// `2nnn` was called with all 16 stack entries in use.
+    StackOverflow { pc: u16 },
+    /// `00EE` was called with an empty stack.
+    StackUnderflow { pc: u16 },
+    /// The instruction accessed `addr`, which lies outside of memory.
+    MemoryOutOfBounds { pc: u16, addr: usize },
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuError::UnknownOpcode { pc, opcode } => {
+                write!(f, "unknown opcode {:04x} at {:#05x}", opcode, pc)
+            },
+            CpuError::StackOverflow { pc } => write!(f, "stack overflow at {:#05x}", pc),
+            CpuError::StackUnderflow { pc } => write!(f, "stack underflow at {:#05x}", pc),
+            CpuError::MemoryOutOfBounds { pc, addr } => {
+                write!(f, "memory access out of bounds ({:#x}) at {:#05x}", addr, pc)
+            },
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
+
+/// How an instruction accessed memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    /// Fetching the instruction itself, or the address following `F000`.
+    Fetch,
+    Read,
+    Write,
+}
+
+/// A range of memory accessed by the instruction at `pc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub pc: u16,
+    pub kind: AccessKind,
+    pub addr: u16,
+    pub len: usize,
+}
+
+impl MemoryAccess {
+    /// Whether the access covers `addr`.
+    pub fn contains(&self, addr: u16) -> bool {
+        (self.addr as usize..self.addr as usize + self.len).contains(&(addr as usize))
+    }
+}
+
+/// A CHIP-8 interpreter, along with the display and keypad it is attached to.
+///
+/// The frontend drives the cpu by calling [`Cpu::cycle`] to execute instructions
+/// at the desired clock speed, [`Cpu::timer_interrupt`] once per display refresh
+/// at 60Hz, and [`Cpu::update_keyboard`] with the keys currently held down.
+pub struct Cpu {
+    memory: Vec<u8>,
+    pc: u16,
+    i: u16,
+    regs: [u8; 16],
+    display: Display,
+    delay_timer: u8,
+    sound_timer: u8,
+    stack: [u16; 16],
+    sp: u8,
+    keypad: Keypad,
+    awaiting_key_press: bool,
+    current_key_pressed: Option<u8>,
+    // Key events since `Fx0A` started waiting, not yet handled by `cycle`
+    key_events: VecDeque<KeyEvent>,
+    // With the `key_wait_release` quirk, the key pressed while waiting, which
+    // completes `Fx0A` once it is released
+    key_down_while_waiting: Option<u8>,
+    awaiting_refresh: bool,
+    exited: bool,
+    // SUPER-CHIP "RPL user flags", saved and restored by `Fx75`/`Fx85`
+    rpl_flags: [u8; 16],
+    // XO-CHIP 1-bit audio samples, played back at a rate set by `pitch`
+    audio_pattern: [u8; 16],
+    pitch: u8,
+    platform: Platform,
+    quirks: Quirks,
+    random: Random,
+    tracer: Option<Box<dyn Tracer>>,
+    // Memory accessed during the last cycle, if recording is enabled
+    memory_accesses: Option<Vec<MemoryAccess>>,
+}
+
+impl Cpu {
+    /// Creates a cpu for `platform`, with the font loaded into memory and the
+    /// program counter at `0x200`.
+    pub fn new(platform: Platform, quirks: Quirks) -> Cpu {
+        let mut cpu = Cpu {
+            memory: vec![0; platform.memory_size()],
+            pc: 0x200,
+            i: 0,
+            regs: [0; 16],
+            display: Display::new(),
+            delay_timer: 0,
+            sound_timer: 0,
+            stack: [0; 16],
+            sp: 0,
+            keypad: Keypad::new(),
+            awaiting_key_press: false,
+            current_key_pressed: None,
+            key_events: VecDeque::new(),
+            key_down_while_waiting: None,
+            awaiting_refresh: false,
+            exited: false,
+            rpl_flags: [0; 16],
+            audio_pattern: [0; 16],
+            pitch: 64,
+            platform,
+            quirks,
+            random: Random::default(),
+            tracer: None,
+            memory_accesses: None,
+        };
+
+        cpu.load_fontset();
+
+        cpu
+    }
+
+    /// Copies a program into memory at `0x200`, returning anything unusual
+    /// about it. Memory is left untouched if the program can't be loaded.
+    pub fn load_binary(&mut self, binary: &[u8]) -> Result<Vec<RomWarning>, RomError> {
+        self.load_binary_at(binary, rom::DEFAULT_LOAD_ADDRESS)
+    }
+
+    /// Copies a program into memory at `addr` and starts running it from
+    /// there, e.g. at `0x600` for programs written for the ETI-660.
+    pub fn load_binary_at(&mut self, binary: &[u8], addr: u16) -> Result<Vec<RomWarning>, RomError> {
+        let warnings = rom::validate(binary.len(), addr, self.platform)?;
+
+        let start = addr as usize;
+        let binary_area = &mut self.memory[start..start+binary.len()];
+        binary_area.copy_from_slice(binary);
+        self.pc = addr;
+
+        Ok(warnings)
+    }
+
+    /// Decrements the delay and sound timers. Called once per display refresh, at 60Hz.
+    pub fn timer_interrupt(&mut self) {
+        self.awaiting_refresh = false;
+
+        if self.delay_timer > 0 {
+            self.delay_timer -= 1;
+        }
+
+        if self.sound_timer > 0 {
+            self.sound_timer -= 1;
+        }
+    }
+
+    /// Completes an `Fx0A` instruction waiting for a key straight away, with
+    /// `key`, whatever the keypad is doing. Has no effect if the cpu isn't
+    /// waiting for a key. Frontends don't normally need this, as `Fx0A` sees
+    /// the keys pressed and released through [`Cpu::update_keyboard`].
+    pub fn key_press_interrupt(&mut self, key: u8) {
+        if self.awaiting_key_press {
+            self.current_key_pressed = Some(key);
+            self.awaiting_key_press = false;
+            self.key_down_while_waiting = None;
+        }
+    }
+
+    /// Sets the keys currently held down on the keypad. The keys that went down
+    /// or came up since the last call are queued for an `Fx0A` waiting for a
+    /// key, in ascending order.
+    pub fn update_keyboard(&mut self, keys: &[u8]) {
+        let events = self.keypad.set_pressed(keys);
+        if self.awaiting_key_press {
+            self.key_events.extend(events);
+        }
+    }
+
+    /// Presses or releases a single key, for frontends that receive key events
+    /// rather than polling the keys held down. The event is queued for an
+    /// `Fx0A` waiting for a key, unless the key was already up or down.
+    pub fn key_event(&mut self, event: KeyEvent) {
+        if self.keypad.apply(event) && self.awaiting_key_press {
+            self.key_events.push_back(event);
+        }
+    }
+
+    pub fn keypad(&self) -> &Keypad {
+        &self.keypad
+    }
+
+    /// Passes each instruction executed from now on to `tracer`. No tracer is
+    /// attached by default.
+    pub fn set_tracer<T: Tracer + 'static>(&mut self, tracer: T) {
+        self.tracer = Some(Box::new(tracer));
+    }
+
+    /// Detaches the current tracer, if any, returning the first error it had
+    /// writing the trace.
+    pub fn clear_tracer(&mut self) -> io::Result<()> {
+        match self.tracer.take() {
+            Some(mut tracer) => tracer.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// Replaces the random number generator used by `Cxkk`. By default it is
+    /// seeded from the operating system, so every run is different.
+    pub fn set_random(&mut self, random: Random) {
+        self.random = random;
+    }
+
+    pub fn random(&self) -> &Random {
+        &self.random
+    }
+
+    /// Starts or stops recording the memory accessed by each cycle, e.g. to
+    /// implement watchpoints. Recording is off by default.
+    pub fn record_memory_accesses(&mut self, enabled: bool) {
+        self.memory_accesses = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    /// The memory accessed during the last call to [`Cpu::cycle`], if recording
+    /// is enabled with [`Cpu::record_memory_accesses`].
+    pub fn memory_accesses(&self) -> &[MemoryAccess] {
+        self.memory_accesses.as_deref().unwrap_or(&[])
+    }
+
+    /// Registers V0 through VF.
+    pub fn regs(&self) -> &[u8; 16] {
+        &self.regs
+    }
+
+    /// Registers V0 through VF, for a debugger to modify.
+    pub fn regs_mut(&mut self) -> &mut [u8; 16] {
+        &mut self.regs
+    }
+
+    /// The `I` register, used to hold memory addresses.
+    pub fn i(&self) -> u16 {
+        self.i
+    }
+
+    pub fn set_i(&mut self, i: u16) {
+        self.i = i;
+    }
+
+    /// Address of the next instruction to execute.
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    /// Number of return addresses on the stack.
+    pub fn sp(&self) -> u8 {
+        self.sp
+    }
+
+    /// The return addresses on the stack, innermost last.
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..self.sp as usize]
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Memory, for a debugger to modify.
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    pub fn platform(&self) -> Platform {
+        self.platform
+    }
+
+    pub fn quirks(&self) -> &Quirks {
+        &self.quirks
+    }
+
+    /// Decodes the instruction in memory at `addr`, returning None if it isn't
+    /// a valid instruction.
+    pub fn instruction_at(&self, addr: u16) -> Option<Instruction> {
+        let opcode = self.word_at(addr)?;
+        disasm::decode(opcode, self.word_at(addr.wrapping_add(2)), self.platform, &self.quirks)
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.delay_timer = value;
+    }
+
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.sound_timer = value;
+    }
+
+    pub fn display(&self) -> &Display {
+        &self.display
+    }
+
+    /// Pixels of the display, row by row. See [`Display::buffer`].
+    pub fn display_buffer(&self) -> &[u8] {
+        self.display.buffer()
+    }
+
+    /// The current (width, height) of the display, which SUPER-CHIP programs
+    /// can change at runtime.
+    pub fn display_size(&self) -> (usize, usize) {
+        (self.display.width(), self.display.height())
+    }
+
+    /// Whether the beeper is sounding, which it does for as long as the sound
+    /// timer is non-zero.
+    pub fn sound_active(&self) -> bool {
+        self.sound_timer > 0
+    }
+
+    /// The audio pattern and pitch register for XO-CHIP programs, which play
+    /// back the pattern instead of a fixed tone.
+    pub fn audio_pattern(&self) -> Option<(&[u8; 16], u8)> {
+        if self.platform.has_xochip_instructions() {
+            Some((&self.audio_pattern, self.pitch))
+        } else {
+            None
+        }
+    }
+
+    /// Fetches and executes a single instruction.
+    pub fn cycle(&mut self) -> Result<CycleOutcome, CpuError> {
+        if self.exited {
+            return Ok(CycleOutcome::Exited);
+        }
+
+        if self.awaiting_key_press {
+            self.handle_key_events();
+            if self.awaiting_key_press {
+                return Ok(CycleOutcome::AwaitingKeyPress);
+            }
+        }
+        // Keys pressed before `Fx0A` started waiting, or after it completed,
+        // don't count
+        self.key_events.clear();
+
+        if self.awaiting_refresh {
+            return Ok(CycleOutcome::AwaitingRefresh);
+        }
+
+        if let Some(accesses) = self.memory_accesses.as_mut() {
+            accesses.clear();
+        }
+
+        let pc = self.pc;
+        let opcode = self.fetch_opcode()?;
+        // A faulting instruction is traced too, as it's the one most worth seeing
+        let executed = self.execute(opcode);
+        self.trace(pc, opcode);
+        executed?;
+
+        if self.exited {
+            Ok(CycleOutcome::Exited)
+        } else if self.awaiting_key_press {
+            Ok(CycleOutcome::AwaitingKeyPress)
+        } else if Self::modifies_display(opcode) {
+            Ok(CycleOutcome::Redraw)
+        } else {
+            Ok(CycleOutcome::Executed)
+        }
+    }
+
+    // Looks for the key press, or press and release, that completes `Fx0A`
+    fn handle_key_events(&mut self) {
+        while let Some(event) = self.key_events.pop_front() {
+            let key = match event {
+                KeyEvent::Pressed(key) if !self.quirks.key_wait_release => key,
+                KeyEvent::Pressed(key) => {
+                    self.key_down_while_waiting.get_or_insert(key);
+                    continue;
+                },
+                KeyEvent::Released(key) if self.key_down_while_waiting == Some(key) => key,
+                KeyEvent::Released(_) => continue,
+            };
+
+            self.current_key_pressed = Some(key);
+            self.awaiting_key_press = false;
+            self.key_down_while_waiting = None;
+            return;
+        }
+    }
+
+    fn load_fontset(&mut self) {
+        let fontset = [
+            0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+            0x20, 0x60, 0x20, 0x20, 0x70, // 1
+            0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+            0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+            0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+            0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+            0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+            0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+            0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+            0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+            0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+            0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+            0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+            0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+            0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+            0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+        ];
+
+        let fontset_area = &mut self.memory[0..fontset.len()];
+        fontset_area.copy_from_slice(&fontset);
+
+        let big_fontset = [
+            0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+            0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+            0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+            0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+            0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+            0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+            0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+            0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+            0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+            0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+            0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+            0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+        ];
+
+        let big_fontset_area = &mut self.memory[BIG_FONT_START..BIG_FONT_START + big_fontset.len()];
+        big_fontset_area.copy_from_slice(&big_fontset);
+    }
+
+    // Whether the instruction can change what is shown on the display
+    fn modifies_display(opcode: u16) -> bool {
+        match opcode {
+            0x00C0..=0x00DF | 0x00E0 | 0x00FB | 0x00FC | 0x00FE | 0x00FF => true,
+            _ => opcode & 0xF000 == 0xD000,
+        }
+    }
+
+    fn fetch_opcode(&mut self) -> Result<u16, CpuError> {
+        let range = self.access_memory(self.pc, self.pc as usize, 2, AccessKind::Fetch)?;
+        let opcode_bytes = &self.memory[range];
+        Ok((opcode_bytes[0] as u16) << 8 | opcode_bytes[1] as u16)
+    }
+
+    // Reads the big-endian word at `addr`, if it lies within memory
+    fn word_at(&self, addr: u16) -> Option<u16> {
+        let bytes = self.memory.get(addr as usize..addr as usize + 2)?;
+        Some((bytes[0] as u16) << 8 | bytes[1] as u16)
+    }
+
+    // Returns the range of `len` bytes of memory starting at `start`, or an error on
+    // behalf of the instruction at `pc` if any of them lie outside of memory. All
+    // memory accesses by instructions go through here, so they can be recorded.
+    fn access_memory(&mut self, pc: u16, start: usize, len: usize, kind: AccessKind) -> Result<Range<usize>, CpuError> {
+        if start + len > self.memory.len() {
+            return Err(CpuError::MemoryOutOfBounds { pc, addr: start + len - 1 });
+        }
+
+        if let Some(accesses) = self.memory_accesses.as_mut() {
+            accesses.push(MemoryAccess { pc, kind, addr: start as u16, len });
+        }
+
+        Ok(start..start + len)
+    }
+
+    fn execute(&mut self, opcode: u16) -> Result<(), CpuError> {
+        // Address of the current instruction, for debugging and error reporting
+        let old = self.pc;
+
+        self.pc = self.pc.wrapping_add(2);
+
+        let superchip = self.platform.has_superchip_instructions();
+        let xochip = self.platform.has_xochip_instructions();
+
+        match opcode & 0xF000 {
+            0x0000 => match opcode {
+                0x00C0..=0x00CF if superchip => {
+                    // 00Cn - SCD nibble
+                    // Scroll display n lines down.
+                    let n = opcode & 0x000F;
+                    self.display.scroll_down(n as usize);
+                },
+                0x00D0..=0x00DF if xochip => {
+                    // 00Dn - SCU nibble
+                    // Scroll display n lines up.
+                    let n = opcode & 0x000F;
+                    self.display.scroll_up(n as usize);
+                },
+                0x00FB if superchip => {
+                    // 00FB - SCR
+                    // Scroll display 4 pixels right.
+                    self.display.scroll_right(4);
+                },
+                0x00FC if superchip => {
+                    // 00FC - SCL
+                    // Scroll display 4 pixels left.
+                    self.display.scroll_left(4);
+                },
+                0x00FD if superchip => {
+                    // 00FD - EXIT
+                    // Exit the interpreter.
+                    self.exited = true;
+                },
+                0x00FE if superchip => {
+                    // 00FE - LOW
+                    // Disable high-resolution mode.
+                    self.display.set_hires(false);
+                },
+                0x00FF if superchip => {
+                    // 00FF - HIGH
+                    // Enable high-resolution mode.
+                    self.display.set_hires(true);
+                },
+                0x00E0 => {
+                    // 00E0 - CLS
+                    // Clear the display.
+                    self.display.clear();
+                },
+                0x00EE => {
+                    // 00EE - RET
+                    // Return from a subroutine.
+                    if self.sp == 0 {
+                        return Err(CpuError::StackUnderflow { pc: old });
+                    }
+
+                    self.sp -= 1;
+                    self.pc = self.stack[self.sp as usize];
+                }
+                _ => {
+                    // This instruction is SYS nnn, which calls a subroutine
+                    // only needed by older computers. Can be ignored.
+                },
+            },
+            0x1000 => {
+                // 1nnn - JP addr
+                // Jump to location nnn.
+                let addr = opcode & 0x0FFF;
+                self.pc = addr;
+            },
+            0x2000 => {
+                // 2nnn - CALL addr
+                // Call subroutine at nnn.
+                let addr = opcode & 0x0FFF;
+
+                if self.sp as usize == self.stack.len() {
+                    return Err(CpuError::StackOverflow { pc: old });
+                }
+
+                self.stack[self.sp as usize] = self.pc;
+                self.sp += 1;
+                self.pc = addr;
+            },
+            0x3000 => {
+                // 3xkk - SE Vx, byte
+                // Skip next instruction if Vx == kk.
+                let idx = (opcode & 0x0F00) >> 8;
+                let byte = opcode & 0x00FF;
+                if self.regs[idx as usize] == byte as u8 {
+                    self.skip_instruction();
+                }
+            },
+            0x4000 => {
+                // 4xkk - SNE Vx, byte
+                // Skip next instruction if Vx != kk.
+                let idx = (opcode & 0x0F00) >> 8;
+                let byte = opcode & 0x00FF;
+                if self.regs[idx as usize] != byte as u8 {
+                    self.skip_instruction();
+                }
+            },
+            0x6000 => {
+                // 6xkk - LD Vx, byte
+                // Set Vx = kk.
+                let idx = (opcode & 0x0F00) >> 8;
+                let byte = opcode & 0x00FF;
+                self.regs[idx as usize] = byte as u8;
+            },
+            0x7000 => {
+                // 7xkk - ADD Vx, byte
+                // Set Vx = Vx + kk.
+                let idx = (opcode & 0x0F00) >> 8;
+                let byte = opcode & 0x00FF;
+                self.regs[idx as usize] = self.regs[idx as usize].wrapping_add(byte as u8);
+            }
+            0x5000 | 0x8000 | 0x9000  => {
+                let x = ((opcode & 0x0F00) >> 8) as usize;
+                let y = ((opcode & 0x00F0) >> 4) as usize;
+
+                self.execute_two_reg_opcode(x, y, opcode, old)?;
+            },
+            0xA000 => {
+                // Annn - LD I, addr
+                // Set I = nnn.
+                let addr = opcode & 0x0FFF;
+                self.i = addr;
+            },
+            0xB000 => {
+                // Bnnn - JP V0, addr
+                // Jump to location nnn + V0.
+                let addr = opcode & 0x0FFF;
+
+                // Some interpreters instead treat this as Bxnn, jumping to xnn + Vx
+                let idx = if self.quirks.jump_with_vx { (addr >> 8) as usize } else { 0 };
+                self.pc = addr + self.regs[idx] as u16;
+            },
+            0xC000 => {
+                // Cxkk - RND Vx, byte
+                // Set Vx = random byte AND kk.
+                let idx = (opcode & 0x0F00) >> 8;
+                let byte = (opcode & 0x00FF) as u8;
+                let rand_byte = self.random.next_byte(&self.memory);
+                self.regs[idx as usize] = rand_byte & byte;
+            },
+            0xD000 => {
+                // Dxyn - DRW Vx, Vy, nibble
+                // Display n-byte sprite starting at memory location I at (Vx, Vy), set VF = collision.
+                let x_idx = ((opcode & 0x0F00) >> 8) as usize;
+                let y_idx = ((opcode & 0x00F0) >> 4) as usize;
+                let n = (opcode & 0x000F) as usize;
+
+                let (vx, vy) = (self.regs[x_idx] as u32, self.regs[y_idx] as u32);
+                let pixel_erased = if n == 0 && superchip {
+                    // Dxy0 - DRW Vx, Vy, 0
+                    // Display 16x16 sprite starting at memory location I at (Vx, Vy), set VF = collision.
+                    let len = 32 * self.display.selected_plane_count();
+                    let sprite = self.access_memory(old, self.i as usize, len, AccessKind::Read)?;
+                    self.display.draw_wide(vx, vy, &self.memory[sprite], self.quirks.clipping)
+                } else {
+                    // With several bitplanes selected, a sprite is drawn to each in turn
+                    let len = n * self.display.selected_plane_count();
+                    let sprite = self.access_memory(old, self.i as usize, len, AccessKind::Read)?;
+                    self.display.draw(vx, vy, &self.memory[sprite], self.quirks.clipping)
+                };
+                self.regs[0xF] = pixel_erased as u8;
+                self.awaiting_refresh = self.quirks.display_wait;
+            },
+            0xE000 => match opcode & 0xF0FF {
+                0xE09E => {
+                    // Ex9E - SKP Vx
+                    // Skip next instruction if key with the value of Vx is pressed.
+                    let x = ((opcode & 0x0F00) >> 8) as usize;
+                    if self.keypad.is_pressed(self.regs[x]) {
+                        self.skip_instruction();
+                    }
+                }
+                0xE0A1 => {
+                    // ExA1 - SKNP Vx
+                    // Skip next instruction if key with the value of Vx is not pressed.
+                    let x = ((opcode & 0x0F00) >> 8) as usize;
+                    if !self.keypad.is_pressed(self.regs[x]) {
+                        self.skip_instruction();
+                    }
+                }
+                _ => return Err(CpuError::UnknownOpcode { pc: old, opcode }),
+            },
+            0xF000 => match opcode & 0xF0FF {
+                0xF000 if xochip && opcode == 0xF000 => {
+                    // F000 nnnn - LD I, LONG addr
+                    // Set I = nnnn, the 16-bit address following this instruction.
+                    let addr = self.fetch_opcode()?;
+                    self.i = addr;
+                    self.pc = self.pc.wrapping_add(2);
+                },
+                0xF001 if xochip => {
+                    // Fn01 - PLANE n
+                    // Select the bitplanes used for drawing, clearing and scrolling.
+                    let n = ((opcode & 0x0F00) >> 8) as u8;
+                    self.display.select_planes(n);
+                },
+                0xF002 if xochip && opcode == 0xF002 => {
+                    // F002 - LD AUDIO, [I]
+                    // Load the 16 byte audio pattern buffer from memory starting at location I.
+                    let area = self.access_memory(old, self.i as usize, 16, AccessKind::Read)?;
+                    self.audio_pattern.copy_from_slice(&self.memory[area]);
+                },
+                0xF007 => {
+                    // Fx07 - LD Vx, DT
+                    // Set Vx = delay timer value.
+                    let x = ((opcode & 0x0F00) >> 8) as usize;
+                    self.regs[x] = self.delay_timer;
+                },
+                0xF00A => {
+                    // Fx0A - LD Vx, K
+                    // Wait for a key press, store the value of the key in Vx.
+
+                    // Since this is a blocking instruction, we will loop on this
+                    // instruction until a key is pressed. Meanwhile, the calling code
+                    // keeps updating the keypad and the timers, and `cycle()` looks
+                    // through the queued key events, or the key is passed to
+                    // `key_press_interrupt()`. Hence when we hit this instruction,
+                    // there are two possible scenarios:
+                    //  1. This isn't the first iteration of the loop, and a key has
+                    //     has already been pressed (`cycle()` returns early if
+                    //     there hasn't been a key press yet). In that case we can
+                    //     reset the flag and progress.
+                    //  2. This is the first iteration of the loop. In that case we need
+                    //     to indicate that we need to loop, and rollback the program
+                    //     counter to the current instruction so that we don't progress.
+                    //     Only keys pressed from now on count, so a key still held
+                    //     from the last `Fx0A` doesn't complete this one.
+                    if let Some(key) = self.current_key_pressed {
+                        let x = ((opcode & 0x0F00) >> 8) as usize;
+                        self.regs[x] = key;
+                        self.current_key_pressed = None;
+                    } else {
+                        self.awaiting_key_press = true;
+                        self.key_down_while_waiting = None;
+                        self.pc = self.pc.wrapping_sub(2);
+                    }
+                },
+                0xF015 => {
+                    // Fx15 - LD DT, Vx
+                    // Set delay timer = Vx.
+                    let x = ((opcode & 0x0F00) >> 8) as usize;
+                    self.delay_timer = self.regs[x];
+                },
+                0xF018 => {
+                    // Fx18 - LD ST, Vx
+                    // Set sound timer = Vx.
+                    let x = ((opcode & 0x0F00) >> 8) as usize;
+                    self.sound_timer = self.regs[x];
+                },
+                0xF01E => {
+                    // Fx1E - ADD I, Vx
+                    // Set I = I + Vx.
+                    let idx = (opcode & 0x0F00) >> 8;
+                    self.i = self.i.wrapping_add(self.regs[idx as usize] as u16);
+                },
+                0xF029 => {
+                    // Fx29 - LD F, Vx
+                    // Set I = location of sprite for digit Vx.
+                    let x = ((opcode & 0x0F00) >> 8) as usize;
+
+                    // Each digit's sprite is 5 bytes long
+                    self.i = self.regs[x] as u16 * 5;
+                }
+                0xF030 if superchip => {
+                    // Fx30 - LD HF, Vx
+                    // Set I = location of 8x10 sprite for digit Vx.
+                    let x = ((opcode & 0x0F00) >> 8) as usize;
+
+                    // Each digit's sprite is 10 bytes long
+                    self.i = (BIG_FONT_START + (self.regs[x] & 0x0F) as usize * 10) as u16;
+                }
+                0xF03A if xochip => {
+                    // Fx3A - LD PITCH, Vx
+                    // Set the audio pitch register = Vx.
+                    let x = ((opcode & 0x0F00) >> 8) as usize;
+                    self.pitch = self.regs[x];
+                }
+                0xF033 => {
+                    // Fx33 - LD B, Vx
+                    // Store BCD representation of Vx in memory locations I, I+1, and I+2.
+                    let x = ((opcode & 0x0F00) >> 8) as usize;
+                    let vx = self.regs[x];
+                    let i = self.access_memory(old, self.i as usize, 3, AccessKind::Write)?.start;
+
+                    self.memory[i] = vx / 100; // hundreds digit
+                    self.memory[i + 1] = (vx / 10) % 10; // tens digit
+                    self.memory[i + 2] = (vx % 100) % 10; // ones digit
+                },
+                0xF055 => {
+                    // Fx55 - LD [I], Vx
+                    // Store registers V0 through Vx in memory starting at location I.
+                    let idx = ((opcode & 0x0F00) >> 8) as usize;
+
+                    let area = self.access_memory(old, self.i as usize, idx + 1, AccessKind::Write)?;
+                    self.memory[area].copy_from_slice(&self.regs[0..idx+1]);
+                    self.increment_i_after_load_store(idx);
+                },
+                0xF065 => {
+                    // Fx65 - LD Vx, [I]
+                    // Read registers V0 through Vx from memory starting at location I.
+                    let idx = ((opcode & 0x0F00) >> 8) as usize;
+
+                    let area = self.access_memory(old, self.i as usize, idx + 1, AccessKind::Read)?;
+                    self.regs[0..idx+1].copy_from_slice(&self.memory[area]);
+                    self.increment_i_after_load_store(idx);
+                },
+                0xF075 if superchip => {
+                    // Fx75 - LD R, Vx
+                    // Store registers V0 through Vx in the RPL user flags.
+                    let idx = ((opcode & 0x0F00) >> 8) as usize;
+                    self.rpl_flags[0..idx+1].copy_from_slice(&self.regs[0..idx+1]);
+                },
+                0xF085 if superchip => {
+                    // Fx85 - LD Vx, R
+                    // Read registers V0 through Vx from the RPL user flags.
+                    let idx = ((opcode & 0x0F00) >> 8) as usize;
+                    self.regs[0..idx+1].copy_from_slice(&self.rpl_flags[0..idx+1]);
+                },
+                _ => return Err(CpuError::UnknownOpcode { pc: old, opcode }),
+            },
+            _ => return Err(CpuError::UnknownOpcode { pc: old, opcode }),
+        };
+
+        Ok(())
+    }
+
+    fn execute_two_reg_opcode(&mut self, x: usize, y: usize, opcode: u16, old: u16) -> Result<(), CpuError> {
+        let xochip = self.platform.has_xochip_instructions();
+
+        match opcode & 0xF00F {
+            0x5000 => {
+                // 5xy0 - SE Vx, Vy
+                // Skip next instruction if Vx == Vy.
+                if self.regs[x] == self.regs[y] {
+                    self.skip_instruction();
+                }
+            },
+            0x5002 if xochip => {
+                // 5xy2 - LD [I], Vx-Vy
+                // Store registers Vx through Vy in memory starting at location I.
+                let regs = Self::register_range(x, y);
+                let area = self.access_memory(old, self.i as usize, regs.len(), AccessKind::Write)?;
+                for (addr, reg) in area.zip(regs) {
+                    self.memory[addr] = self.regs[reg];
+                }
+            },
+            0x5003 if xochip => {
+                // 5xy3 - LD Vx-Vy, [I]
+                // Read registers Vx through Vy from memory starting at location I.
+                let regs = Self::register_range(x, y);
+                let area = self.access_memory(old, self.i as usize, regs.len(), AccessKind::Read)?;
+                for (addr, reg) in area.zip(regs) {
+                    self.regs[reg] = self.memory[addr];
+                }
+            },
+            0x8000 => {
+                // 8xy0 - LD Vx, Vy
+                // Set Vx = Vy.
+                self.regs[x] = self.regs[y];
+            },
+            0x8001 => {
+                // 8xy1 - OR Vx, Vy
+                // Set Vx = Vx OR Vy.
+                self.regs[x] |= self.regs[y];
+                if self.quirks.vf_reset {
+                    self.regs[0xF] = 0;
+                }
+            },
+            0x8002 => {
+                // 8xy2 - AND Vx, Vy
+                // Set Vx = Vx AND Vy.
+                self.regs[x] &= self.regs[y];
+                if self.quirks.vf_reset {
+                    self.regs[0xF] = 0;
+                }
+            },
+            0x8003 => {
+                // 8xy3 - XOR Vx, Vy
+                // Set Vx = Vx XOR Vy.
+                self.regs[x] ^= self.regs[y];
+                if self.quirks.vf_reset {
+                    self.regs[0xF] = 0;
+                }
+            }
+            0x8004 => {
+                // 8xy4 - ADD Vx, Vy
+                // Set Vx = Vx + Vy, set VF = carry.
+                let vx = self.regs[x] as u32;
+                let vy = self.regs[y] as u32;
+                let result = vx + vy;
+
+                self.regs[0xF] = (result > 0xFF) as u8;
+                self.regs[x] = result as u8;
+            },
+            0x8005 => {
+                // 8xy5 - SUB Vx, Vy
+                // Set Vx = Vx - Vy, set VF = NOT borrow.
+                let vx = self.regs[x];
+                let vy = self.regs[y];
+
+                // Set if NO borrow
+                self.regs[0xF] = (vx >= vy) as u8;
+                self.regs[x] = vx.wrapping_sub(vy);
+            },
+            0x8006 => {
+                // 8xy6 - SHR Vx {, Vy}
+                // Set Vx = Vx SHR 1.
+                let value = self.shift_operand(x, y);
+                self.regs[x] = value >> 1;
+                self.regs[0xF] = value & 0x01;
+            },
+            0x8007 => {
+                // 8xy7 - SUBN Vx, Vy
+                // Set Vx = Vy - Vx, set VF = NOT borrow.
+                let vx = self.regs[x];
+                let vy = self.regs[y];
+
+                // Set if NO borrow
+                self.regs[0xF] = (vy >= vx) as u8;
+                self.regs[x] = vy.wrapping_sub(vx);
+            },
+            0x800E => {
+                // 8xyE - SHL Vx {, Vy}
+                // Set Vx = Vx SHL 1.
+                let value = self.shift_operand(x, y);
+                self.regs[x] = value << 1;
+                self.regs[0xF] = ((value & 0x80) == 0x80) as u8;
+            },
+            0x9000 => {
+                // 9xy0 - SNE Vx, Vy
+                // Skip next instruction if Vx != Vy.
+                if self.regs[x] != self.regs[y] {
+                    self.skip_instruction();
+                }
+            },
+            _ => return Err(CpuError::UnknownOpcode { pc: old, opcode }),
+        }
+
+        Ok(())
+    }
+
+    // Advances past the next instruction, which is 4 bytes long if it is
+    // XO-CHIP's `F000 nnnn`
+    fn skip_instruction(&mut self) {
+        let long = self.platform.has_xochip_instructions() && self.word_at(self.pc) == Some(0xF000);
+        let len = if long { 4 } else { 2 };
+        self.pc = self.pc.wrapping_add(len);
+    }
+
+    // Registers Vx through Vy, in that order, which may be descending
+    fn register_range(x: usize, y: usize) -> Vec<usize> {
+        if x <= y {
+            (x..=y).collect()
+        } else {
+            (y..=x).rev().collect()
+        }
+    }
+
+    // The value shifted by `8xy6`/`8xyE`
+    fn shift_operand(&self, x: usize, y: usize) -> u8 {
+        if self.quirks.shift {
+            self.regs[x]
+        } else {
+            self.regs[y]
+        }
+    }
+
+    fn increment_i_after_load_store(&mut self, x: usize) {
+        match self.quirks.load_store_increment {
+            LoadStoreIncrement::Unchanged => (),
+            LoadStoreIncrement::ByX => self.i = self.i.wrapping_add(x as u16),
+            LoadStoreIncrement::ByXPlusOne => self.i = self.i.wrapping_add(x as u16 + 1),
+        }
+    }
+
+    // Passes the executed instruction to the tracer. The mnemonic is only
+    // decoded if there is a tracer attached, as this is done for every instruction.
+    fn trace(&mut self, pc: u16, opcode: u16) {
+        if self.tracer.is_none() {
+            return;
+        }
+
+        let next = self.word_at(pc.wrapping_add(2));
+        let mnemonic = disasm::decode(opcode, next, self.platform, &self.quirks)
+            .map(|instruction| instruction.mnemonic())
+            .unwrap_or_else(|| "???".to_string());
+
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.trace(&TraceEntry {
+                pc,
+                opcode,
+                mnemonic,
+                regs: self.regs,
+                i: self.i,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn cpu_with(platform: Platform, program: &[u8]) -> Cpu {
+        let mut cpu = Cpu::new(platform, Quirks::default());
+        cpu.load_binary(program).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn stack_overflow() {
+        // Calls itself
+        let mut cpu = cpu_with(Platform::Chip8, &[0x22, 0x00]);
+        for depth in 1..=16 {
+            cpu.cycle().unwrap();
+            assert_eq!(cpu.sp(), depth);
+        }
+        assert_eq!(cpu.cycle(), Err(CpuError::StackOverflow { pc: 0x200 }));
+        assert_eq!(cpu.sp(), 16);
+    }
+
+    #[test]
+    fn return_with_an_empty_stack() {
+        let mut cpu = cpu_with(Platform::Chip8, &[0x00, 0xEE]);
+        assert_eq!(cpu.cycle(), Err(CpuError::StackUnderflow { pc: 0x200 }));
+    }
+
+    #[test]
+    fn unknown_opcodes() {
+        let mut cpu = cpu_with(Platform::Chip8, &[0x60, 0x01, 0x50, 0x01]);
+        cpu.cycle().unwrap();
+        assert_eq!(cpu.cycle(), Err(CpuError::UnknownOpcode { pc: 0x202, opcode: 0x5001 }));
+
+        // Only an instruction on later platforms
+        let mut cpu = cpu_with(Platform::Chip8, &[0xF0, 0x75]);
+        assert_eq!(cpu.cycle(), Err(CpuError::UnknownOpcode { pc: 0x200, opcode: 0xF075 }));
+        let mut cpu = cpu_with(Platform::SuperChip, &[0xF0, 0x75]);
+        assert!(cpu.cycle().is_ok());
+    }
+
+    #[test]
+    fn memory_accesses_past_the_end() {
+        // I = 0xFFF, then each instruction touches the byte after it
+        let cases: [(&[u8], usize); 3] = [
+            (&[0xAF, 0xFF, 0xF1, 0x55], 0x1000),
+            (&[0xAF, 0xFF, 0xF1, 0x65], 0x1000),
+            (&[0xAF, 0xFE, 0xD0, 0x15], 0x1002),
+        ];
+
+        for (program, addr) in cases.iter() {
+            let mut cpu = cpu_with(Platform::Chip8, program);
+            cpu.cycle().unwrap();
+            let memory = cpu.memory().to_vec();
+            assert_eq!(cpu.cycle(), Err(CpuError::MemoryOutOfBounds { pc: 0x202, addr: *addr }));
+            assert_eq!(cpu.memory(), &memory[..]);
+        }
+
+        // The last byte itself is fine
+        let mut cpu = cpu_with(Platform::Chip8, &[0xAF, 0xFF, 0xF0, 0x55]);
+        cpu.cycle().unwrap();
+        assert!(cpu.cycle().is_ok());
+    }
+
+    #[test]
+    fn faulting_instructions_are_traced() {
+        let traced = Rc::new(RefCell::new(Vec::new()));
+        let mut cpu = cpu_with(Platform::Chip8, &[0x60, 0x01, 0x00, 0xEE]);
+        let entries = traced.clone();
+        cpu.set_tracer(move |entry: &TraceEntry| entries.borrow_mut().push((entry.pc, entry.opcode)));
+
+        cpu.cycle().unwrap();
+        assert!(cpu.cycle().is_err());
+        assert_eq!(*traced.borrow(), [(0x200, 0x6001), (0x202, 0x00EE)]);
+    }
+
+    // `LD V3, K`, then loops
+    fn waiting_for_a_key(key_wait_release: bool) -> Cpu {
+        let quirks = Quirks { key_wait_release, ..Quirks::default() };
+        let mut cpu = Cpu::new(Platform::Chip8, quirks);
+        cpu.load_binary(&[0xF3, 0x0A, 0x12, 0x02]).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn key_waits_complete_on_release() {
+        let mut cpu = waiting_for_a_key(true);
+        assert_eq!(cpu.cycle(), Ok(CycleOutcome::AwaitingKeyPress));
+
+        cpu.update_keyboard(&[0x5]);
+        assert_eq!(cpu.cycle(), Ok(CycleOutcome::AwaitingKeyPress));
+        assert_eq!(cpu.cycle(), Ok(CycleOutcome::AwaitingKeyPress));
+
+        cpu.update_keyboard(&[]);
+        assert_eq!(cpu.cycle(), Ok(CycleOutcome::Executed));
+        assert_eq!(cpu.regs()[3], 0x5);
+        assert_eq!(cpu.pc(), 0x202);
+    }
+
+    #[test]
+    fn key_waits_use_the_first_key_pressed() {
+        let mut cpu = waiting_for_a_key(true);
+        cpu.cycle().unwrap();
+
+        // Pressed together, the lower key comes first, and releasing the other
+        // doesn't count
+        cpu.update_keyboard(&[0x9, 0x4]);
+        cpu.update_keyboard(&[0x4]);
+        assert_eq!(cpu.cycle(), Ok(CycleOutcome::AwaitingKeyPress));
+
+        cpu.update_keyboard(&[]);
+        assert_eq!(cpu.cycle(), Ok(CycleOutcome::Executed));
+        assert_eq!(cpu.regs()[3], 0x4);
+    }
+
+    #[test]
+    fn keys_held_before_a_key_wait_are_ignored() {
+        let mut cpu = waiting_for_a_key(true);
+        cpu.update_keyboard(&[0x7]);
+        assert_eq!(cpu.cycle(), Ok(CycleOutcome::AwaitingKeyPress));
+
+        // Releasing it doesn't complete the wait, as it wasn't pressed during it
+        cpu.update_keyboard(&[]);
+        assert_eq!(cpu.cycle(), Ok(CycleOutcome::AwaitingKeyPress));
+
+        cpu.update_keyboard(&[0x2]);
+        cpu.update_keyboard(&[]);
+        assert_eq!(cpu.cycle(), Ok(CycleOutcome::Executed));
+        assert_eq!(cpu.regs()[3], 0x2);
+
+        // Nor does a key still held from the last wait complete the next one
+        let mut cpu = waiting_for_a_key(false);
+        cpu.cycle().unwrap();
+        cpu.update_keyboard(&[0x2]);
+        assert_eq!(cpu.cycle(), Ok(CycleOutcome::Executed));
+        cpu.set_pc(0x200);
+        assert_eq!(cpu.cycle(), Ok(CycleOutcome::AwaitingKeyPress));
+        assert_eq!(cpu.cycle(), Ok(CycleOutcome::AwaitingKeyPress));
+    }
+
+    #[test]
+    fn key_waits_complete_on_press_without_the_quirk() {
+        let mut cpu = waiting_for_a_key(false);
+        assert_eq!(cpu.cycle(), Ok(CycleOutcome::AwaitingKeyPress));
+
+        cpu.key_event(KeyEvent::Pressed(0xB));
+        assert_eq!(cpu.cycle(), Ok(CycleOutcome::Executed));
+        assert_eq!(cpu.regs()[3], 0xB);
+        assert!(cpu.keypad().is_pressed(0xB));
+    }
+
+    #[test]
+    fn timers_run_while_waiting_for_a_key() {
+        let mut cpu = waiting_for_a_key(true);
+        cpu.set_delay_timer(10);
+        cpu.set_sound_timer(5);
+        cpu.cycle().unwrap();
+
+        for _ in 0..3 {
+            assert_eq!(cpu.cycle(), Ok(CycleOutcome::AwaitingKeyPress));
+            cpu.timer_interrupt();
+        }
+        assert_eq!(cpu.delay_timer(), 7);
+        assert_eq!(cpu.sound_timer(), 2);
+    }
+}
@@ -0,0 +1,557 @@
+//! Save states: a snapshot of the whole machine in a versioned binary format.
+//!
+//! A state is a header followed by the payload:
+//!
+//! | Field    | Size | Contents                                  |
+//! |----------|------|-------------------------------------------|
+//! | magic    | 4    | `C8ST`                                    |
+//! | version  | 2    | format version, currently 3               |
+//! | length   | 4    | length of the payload                     |
+//! | checksum | 4    | CRC-32 of the payload                     |
+//!
+//! All integers are big-endian.
+
+use std::fmt;
+
+use super::Cpu;
+use crate::display::Display;
+use crate::keypad::KeyEvent;
+use crate::platform::Platform;
+use crate::quirks::{LoadStoreIncrement, Quirks};
+use crate::random::{Random, RandomMode};
+
+const MAGIC: &[u8; 4] = b"C8ST";
+
+// Version of the format written by `Cpu::save_state`, increased whenever the
+// payload changes. Version 2 added the random number generator, and version 3
+// the `key_wait_release` quirk and the key events queued for `Fx0A`
+const VERSION: u16 = 3;
+
+const HEADER_LEN: usize = 14;
+
+/// Why a save state couldn't be loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateError {
+    /// The data doesn't start with the save state header.
+    NotAState,
+    /// The state was saved by a newer version of the emulator.
+    UnsupportedVersion(u16),
+    /// The checksum doesn't match, so the state has been damaged.
+    Corrupted,
+    /// The state ends before all of its fields.
+    Truncated,
+    /// A field holds a value the machine can't be in.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::NotAState => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => write!(f, "unsupported save state version {}", version),
+            StateError::Corrupted => write!(f, "save state is corrupted"),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::Invalid(field) => write!(f, "save state has an invalid {}", field),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+impl Cpu {
+    /// Captures the whole machine, including the display, keypad, quirks and
+    /// random number generator, so it can be restored later with
+    /// [`Cpu::load_state`]. The tracer isn't saved.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = Writer::default();
+
+        w.u8(match self.platform {
+            Platform::Chip8 => 0,
+            Platform::SuperChip => 1,
+            Platform::XoChip => 2,
+        });
+        w.bool(self.quirks.shift);
+        w.u8(match self.quirks.load_store_increment {
+            LoadStoreIncrement::Unchanged => 0,
+            LoadStoreIncrement::ByX => 1,
+            LoadStoreIncrement::ByXPlusOne => 2,
+        });
+        w.bool(self.quirks.jump_with_vx);
+        w.bool(self.quirks.vf_reset);
+        w.bool(self.quirks.clipping);
+        w.bool(self.quirks.display_wait);
+
+        w.bytes(&self.memory);
+        w.u16(self.pc);
+        w.u16(self.i);
+        w.raw(&self.regs);
+        w.u8(self.delay_timer);
+        w.u8(self.sound_timer);
+        for addr in &self.stack {
+            w.u16(*addr);
+        }
+        w.u8(self.sp);
+
+        let keys = self.keypad.pressed_keys();
+        w.u16(keys.iter().fold(0, |mask, key| mask | 1 << key));
+        w.bool(self.awaiting_key_press);
+        w.bool(self.current_key_pressed.is_some());
+        w.u8(self.current_key_pressed.unwrap_or(0));
+        w.bool(self.awaiting_refresh);
+        w.bool(self.exited);
+
+        w.raw(&self.rpl_flags);
+        w.raw(&self.audio_pattern);
+        w.u8(self.pitch);
+
+        w.u16(self.display.width() as u16);
+        w.u16(self.display.height() as u16);
+        w.u8(self.display.selected_planes());
+        w.bytes(self.display.buffer());
+
+        let (mode, state) = self.random.to_parts();
+        w.u8(match mode {
+            RandomMode::Xorshift => 0,
+            RandomMode::CosmacVip => 1,
+        });
+        w.u32(state);
+
+        w.bool(self.quirks.key_wait_release);
+        w.bool(self.key_down_while_waiting.is_some());
+        w.u8(self.key_down_while_waiting.unwrap_or(0));
+        w.u16(self.key_events.len() as u16);
+        for event in &self.key_events {
+            // Releases have the top bit set
+            w.u8(match event {
+                KeyEvent::Pressed(key) => *key,
+                KeyEvent::Released(key) => 0x80 | *key,
+            });
+        }
+
+        let payload = w.bytes;
+        let mut state = Vec::with_capacity(HEADER_LEN + payload.len());
+        state.extend_from_slice(MAGIC);
+        state.extend_from_slice(&VERSION.to_be_bytes());
+        state.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        state.extend_from_slice(&crc32(&payload).to_be_bytes());
+        state.extend_from_slice(&payload);
+        state
+    }
+
+    /// Restores a state saved with [`Cpu::save_state`], including the platform
+    /// and quirks it was saved with. The cpu is left unchanged if the state
+    /// can't be loaded.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        if state.len() < HEADER_LEN || &state[0..4] != MAGIC {
+            return Err(StateError::NotAState);
+        }
+
+        let mut header = Reader::new(&state[4..HEADER_LEN]);
+        let version = header.u16()?;
+        let len = header.u32()? as usize;
+        let checksum = header.u32()?;
+
+        if version > VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        let payload = state.get(HEADER_LEN..HEADER_LEN + len).ok_or(StateError::Truncated)?;
+        if crc32(payload) != checksum {
+            return Err(StateError::Corrupted);
+        }
+
+        let mut r = Reader::new(payload);
+
+        let platform = match r.u8()? {
+            0 => Platform::Chip8,
+            1 => Platform::SuperChip,
+            2 => Platform::XoChip,
+            _ => return Err(StateError::Invalid("platform")),
+        };
+        let mut quirks = Quirks {
+            shift: r.bool()?,
+            load_store_increment: match r.u8()? {
+                0 => LoadStoreIncrement::Unchanged,
+                1 => LoadStoreIncrement::ByX,
+                2 => LoadStoreIncrement::ByXPlusOne,
+                _ => return Err(StateError::Invalid("load/store quirk")),
+            },
+            jump_with_vx: r.bool()?,
+            vf_reset: r.bool()?,
+            clipping: r.bool()?,
+            display_wait: r.bool()?,
+            // Read with the fields added in version 3
+            key_wait_release: false,
+        };
+
+        let memory = r.bytes()?.to_vec();
+        if memory.len() != platform.memory_size() {
+            return Err(StateError::Invalid("memory size"));
+        }
+
+        let pc = r.u16()?;
+        let i = r.u16()?;
+        let regs = r.array()?;
+        let delay_timer = r.u8()?;
+        let sound_timer = r.u8()?;
+        let mut stack = [0; 16];
+        for addr in stack.iter_mut() {
+            *addr = r.u16()?;
+        }
+        let sp = r.u8()?;
+        if sp as usize > stack.len() {
+            return Err(StateError::Invalid("stack pointer"));
+        }
+
+        let keys = r.u16()?;
+        let awaiting_key_press = r.bool()?;
+        let has_key_pressed = r.bool()?;
+        let key_pressed = r.u8()?;
+        if has_key_pressed && key_pressed >= 16 {
+            return Err(StateError::Invalid("key"));
+        }
+        let awaiting_refresh = r.bool()?;
+        let exited = r.bool()?;
+
+        let rpl_flags = r.array()?;
+        let audio_pattern = r.array()?;
+        let pitch = r.u8()?;
+
+        let width = r.u16()? as usize;
+        let height = r.u16()? as usize;
+        let selected_planes = r.u8()?;
+        let buffer = r.bytes()?.to_vec();
+        let display = Display::from_parts(width, height, selected_planes, buffer)
+            .ok_or(StateError::Invalid("display"))?;
+
+        // Version 1 states don't have one, so the current generator carries on
+        let random = if version >= 2 {
+            let mode = match r.u8()? {
+                0 => RandomMode::Xorshift,
+                1 => RandomMode::CosmacVip,
+                _ => return Err(StateError::Invalid("random mode")),
+            };
+            Some(Random::from_parts(mode, r.u32()?).ok_or(StateError::Invalid("random state"))?)
+        } else {
+            None
+        };
+
+        // Before version 3, `Fx0A` completed as soon as a key was pressed
+        let mut key_down_while_waiting = None;
+        let mut key_events = Vec::new();
+        if version >= 3 {
+            quirks.key_wait_release = r.bool()?;
+            let has_key_down = r.bool()?;
+            let key_down = r.u8()?;
+            if has_key_down && key_down >= 16 {
+                return Err(StateError::Invalid("key"));
+            }
+            key_down_while_waiting = if has_key_down { Some(key_down) } else { None };
+            for _ in 0..r.u16()? {
+                let event = r.u8()?;
+                key_events.push(match event & 0x80 {
+                    0 => KeyEvent::Pressed(event & 0x0F),
+                    _ => KeyEvent::Released(event & 0x0F),
+                });
+            }
+        }
+
+        self.platform = platform;
+        self.quirks = quirks;
+        self.memory = memory;
+        self.pc = pc;
+        self.i = i;
+        self.regs = regs;
+        self.delay_timer = delay_timer;
+        self.sound_timer = sound_timer;
+        self.stack = stack;
+        self.sp = sp;
+        let keys: Vec<u8> = (0..16).filter(|key| keys & 1 << key != 0).collect();
+        self.keypad.set_pressed(&keys);
+        self.awaiting_key_press = awaiting_key_press;
+        self.current_key_pressed = if has_key_pressed { Some(key_pressed) } else { None };
+        self.key_down_while_waiting = key_down_while_waiting;
+        self.key_events = key_events.into();
+        self.awaiting_refresh = awaiting_refresh;
+        self.exited = exited;
+        self.rpl_flags = rpl_flags;
+        self.audio_pattern = audio_pattern;
+        self.pitch = pitch;
+        self.display = display;
+        if let Some(random) = random {
+            self.random = random;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn raw(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    // Writes bytes prefixed with their length
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.raw(bytes);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes }
+    }
+
+    fn raw(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if len > self.bytes.len() {
+            return Err(StateError::Truncated);
+        }
+
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.raw(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Invalid("flag")),
+        }
+    }
+
+    fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.raw(N)?);
+        Ok(array)
+    }
+
+    // Reads bytes prefixed with their length
+    fn bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let len = self.u32()? as usize;
+        self.raw(len)
+    }
+}
+
+// CRC-32 as used by zlib and PNG
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A Chip8 cpu that has drawn a sprite, called a subroutine and is holding
+    // key 5
+    fn running_cpu() -> Cpu {
+        let mut cpu = Cpu::new(Platform::Chip8, Quirks::default());
+        // LD V0, 7; LD DT, V0; LD F, V0; DRW V0, V0, 5; CALL 20a; JP 20a
+        cpu.load_binary(&[0x60, 0x07, 0xF0, 0x15, 0xF0, 0x29, 0xD0, 0x05, 0x22, 0x0A, 0x12, 0x0A]).unwrap();
+        for _ in 0..5 {
+            cpu.cycle().unwrap();
+        }
+        cpu.update_keyboard(&[0x5]);
+        cpu
+    }
+
+    // Wraps a payload in a header for `version`
+    fn seal(version: u16, payload: &[u8]) -> Vec<u8> {
+        let mut state = MAGIC.to_vec();
+        state.extend_from_slice(&version.to_be_bytes());
+        state.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        state.extend_from_slice(&crc32(payload).to_be_bytes());
+        state.extend_from_slice(payload);
+        state
+    }
+
+    // Offset in a Chip8 payload of the stack pointer, after the platform,
+    // quirks, memory, pc, I, registers, timers and stack
+    fn sp_offset() -> usize {
+        7 + 4 + Platform::Chip8.memory_size() + 2 + 2 + 16 + 1 + 1 + 32
+    }
+
+    #[test]
+    fn round_trip() {
+        let cpu = running_cpu();
+        let state = cpu.save_state();
+
+        let mut restored = Cpu::new(Platform::XoChip, Quirks::default());
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.platform(), Platform::Chip8);
+        assert_eq!(restored.quirks(), cpu.quirks());
+        assert_eq!(restored.pc(), 0x20A);
+        assert_eq!(restored.sp(), 1);
+        assert_eq!(restored.stack()[0], 0x20A);
+        assert_eq!(restored.regs(), cpu.regs());
+        assert_eq!(restored.delay_timer(), 7);
+        assert_eq!(restored.memory(), cpu.memory());
+        assert_eq!(restored.display_buffer(), cpu.display_buffer());
+        assert_eq!(restored.keypad().pressed_keys(), [0x5]);
+        assert_eq!(restored.save_state(), state);
+    }
+
+    #[test]
+    fn not_a_state() {
+        let mut cpu = running_cpu();
+        let before = cpu.save_state();
+
+        assert_eq!(cpu.load_state(b""), Err(StateError::NotAState));
+        assert_eq!(cpu.load_state(b"C8ST"), Err(StateError::NotAState));
+        assert_eq!(cpu.load_state(&before[..HEADER_LEN - 1]), Err(StateError::NotAState));
+
+        let mut state = before.clone();
+        state[0] = b'X';
+        assert_eq!(cpu.load_state(&state), Err(StateError::NotAState));
+        assert_eq!(cpu.save_state(), before);
+    }
+
+    #[test]
+    fn truncated() {
+        let mut cpu = running_cpu();
+        let state = cpu.save_state();
+        assert_eq!(cpu.load_state(&state[..state.len() - 1]), Err(StateError::Truncated));
+        assert_eq!(cpu.load_state(&state[..HEADER_LEN]), Err(StateError::Truncated));
+
+        // A payload whose fields run out before the end
+        let payload = &state[HEADER_LEN..state.len() - 1];
+        assert_eq!(cpu.load_state(&seal(VERSION, payload)), Err(StateError::Truncated));
+        assert_eq!(cpu.save_state(), state);
+    }
+
+    #[test]
+    fn corrupted() {
+        let mut cpu = running_cpu();
+        let before = cpu.save_state();
+
+        let mut state = before.clone();
+        state[HEADER_LEN + 0x300] ^= 0x01;
+        assert_eq!(cpu.load_state(&state), Err(StateError::Corrupted));
+
+        let mut state = before.clone();
+        state[10] ^= 0x80;
+        assert_eq!(cpu.load_state(&state), Err(StateError::Corrupted));
+        assert_eq!(cpu.save_state(), before);
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut cpu = running_cpu();
+        let state = cpu.save_state();
+        let payload = &state[HEADER_LEN..];
+
+        assert_eq!(cpu.load_state(&seal(VERSION + 1, payload)), Err(StateError::UnsupportedVersion(VERSION + 1)));
+        assert_eq!(cpu.load_state(&seal(0xFFFF, payload)), Err(StateError::UnsupportedVersion(0xFFFF)));
+        assert_eq!(cpu.save_state(), state);
+    }
+
+    #[test]
+    fn invalid_fields() {
+        let mut cpu = running_cpu();
+        let before = cpu.save_state();
+        let load = |cpu: &mut Cpu, patch: &dyn Fn(&mut Vec<u8>)| {
+            let mut payload = before[HEADER_LEN..].to_vec();
+            patch(&mut payload);
+            cpu.load_state(&seal(VERSION, &payload))
+        };
+
+        assert_eq!(load(&mut cpu, &|payload| payload[0] = 3), Err(StateError::Invalid("platform")));
+        assert_eq!(load(&mut cpu, &|payload| payload[1] = 2), Err(StateError::Invalid("flag")));
+
+        // More calls than the stack holds
+        let sp = sp_offset();
+        assert_eq!(load(&mut cpu, &|payload| payload[sp] = 17), Err(StateError::Invalid("stack pointer")));
+
+        // The key an `Fx0A` is waiting on, after the held keys and the waiting flag
+        let key = sp + 4;
+        let bad_key = |payload: &mut Vec<u8>| {
+            payload[key] = 1;
+            payload[key + 1] = 0x10;
+        };
+        assert_eq!(load(&mut cpu, &bad_key), Err(StateError::Invalid("key")));
+
+        // The key held down when `Fx0A` started, in the fields added by version 3
+        let end = before.len() - HEADER_LEN;
+        let bad_key_down = |payload: &mut Vec<u8>| {
+            payload[end - 4] = 1;
+            payload[end - 3] = 0x10;
+        };
+        assert_eq!(load(&mut cpu, &bad_key_down), Err(StateError::Invalid("key")));
+
+        assert_eq!(cpu.save_state(), before);
+    }
+
+    #[test]
+    fn older_versions() {
+        let quirks = Quirks { key_wait_release: true, ..Quirks::default() };
+        let mut cpu = Cpu::new(Platform::Chip8, quirks);
+        cpu.load_binary(&[0x12, 0x00]).unwrap();
+        cpu.set_random(Random::new(RandomMode::CosmacVip, 42));
+        let state = cpu.save_state();
+
+        // Version 2 states end before the `key_wait_release` quirk, the key held
+        // down when `Fx0A` started and the queued key events
+        let v2 = seal(2, &state[HEADER_LEN..state.len() - 5]);
+        let mut restored = Cpu::new(Platform::SuperChip, Quirks::default());
+        restored.load_state(&v2).unwrap();
+        assert_eq!(restored.platform(), Platform::Chip8);
+        assert!(!restored.quirks().key_wait_release);
+        assert_eq!(restored.random().mode(), RandomMode::CosmacVip);
+        assert_eq!(restored.memory(), cpu.memory());
+
+        // Version 1 states also have no random number generator, so the
+        // current one carries on
+        let v1 = seal(1, &state[HEADER_LEN..state.len() - 10]);
+        let mut restored = Cpu::new(Platform::SuperChip, Quirks::default());
+        restored.set_random(Random::new(RandomMode::Xorshift, 7));
+        restored.load_state(&v1).unwrap();
+        assert_eq!(restored.random().mode(), RandomMode::Xorshift);
+        assert_eq!(restored.pc(), 0x200);
+    }
+}
@@ -0,0 +1,400 @@
+//! Decoding of instructions into assembly mnemonics, and static disassembly of
+//! whole programs.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::platform::Platform;
+use crate::quirks::Quirks;
+
+/// How an instruction affects the flow of execution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    /// Continues with the next instruction.
+    Next,
+    /// Continues with the next instruction, or skips over it.
+    Skip,
+    /// Jumps to an address.
+    Jump(u16),
+    /// Calls the subroutine at an address, later returning to the next instruction.
+    Call(u16),
+    /// Jumps to an address only known at runtime (`Bnnn`).
+    JumpIndirect,
+    /// Doesn't continue with the next instruction (`00EE` and `00FD`).
+    Stop,
+}
+
+/// A decoded instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub opcode: u16,
+    /// Length in bytes, which is 4 for XO-CHIP's `F000 nnnn` and 2 otherwise.
+    pub len: u16,
+    /// The mnemonic up to the address operand, if there is one, e.g. `JP V0, `.
+    pub operation: String,
+    /// The address operand, always the last operand of the instruction.
+    pub addr: Option<u16>,
+    pub flow: Flow,
+}
+
+impl Instruction {
+    /// The instruction in assembly syntax, e.g. `LD I, 022a`.
+    pub fn mnemonic(&self) -> String {
+        match self.addr {
+            Some(addr) => format!("{}{:04x}", self.operation, addr),
+            None => self.operation.clone(),
+        }
+    }
+
+    /// Like [`Instruction::mnemonic`], but referring to the address operand by `label`.
+    pub fn mnemonic_with_label(&self, label: &str) -> String {
+        match self.addr {
+            Some(_) => format!("{}{}", self.operation, label),
+            None => self.operation.clone(),
+        }
+    }
+}
+
+/// Decodes `opcode` into an instruction, returning None if it isn't an
+/// instruction on `platform`. `next` is the word following the opcode, which
+/// is only needed for XO-CHIP's 4 byte `F000 nnnn`.
+///
+/// The mnemonics follow [Cowgod's Chip-8 reference](http://devernay.free.fr/hacks/chip8/C8TECH10.HTM),
+/// with register numbers in decimal and other operands in hex, e.g. `LD V10, 0a`.
+pub fn decode(opcode: u16, next: Option<u16>, platform: Platform, quirks: &Quirks) -> Option<Instruction> {
+    let superchip = platform.has_superchip_instructions();
+    let xochip = platform.has_xochip_instructions();
+
+    let x = (opcode & 0x0F00) >> 8;
+    let y = (opcode & 0x00F0) >> 4;
+    let n = opcode & 0x000F;
+    let kk = opcode & 0x00FF;
+    let nnn = opcode & 0x0FFF;
+
+    let simple = |operation: String, flow: Flow| Some((operation, None, flow));
+    let with_addr = |operation: &str, addr: u16, flow: Flow| Some((operation.to_string(), Some(addr), flow));
+
+    let decoded = match opcode & 0xF000 {
+        0x0000 => match opcode {
+            0x00C0..=0x00CF if superchip => simple(format!("SCD {:x}", n), Flow::Next),
+            0x00D0..=0x00DF if xochip => simple(format!("SCU {:x}", n), Flow::Next),
+            0x00E0 => simple("CLS".to_string(), Flow::Next),
+            0x00EE => simple("RET".to_string(), Flow::Stop),
+            0x00FB if superchip => simple("SCR".to_string(), Flow::Next),
+            0x00FC if superchip => simple("SCL".to_string(), Flow::Next),
+            0x00FD if superchip => simple("EXIT".to_string(), Flow::Stop),
+            0x00FE if superchip => simple("LOW".to_string(), Flow::Next),
+            0x00FF if superchip => simple("HIGH".to_string(), Flow::Next),
+            _ => with_addr("SYS ", nnn, Flow::Next),
+        },
+        0x1000 => with_addr("JMP ", nnn, Flow::Jump(nnn)),
+        0x2000 => with_addr("CALL ", nnn, Flow::Call(nnn)),
+        0x3000 => simple(format!("SE V{}, {:02x}", x, kk), Flow::Skip),
+        0x4000 => simple(format!("SNE V{}, {:02x}", x, kk), Flow::Skip),
+        0x5000 => match n {
+            0x0 => simple(format!("SE V{}, V{}", x, y), Flow::Skip),
+            0x2 if xochip => simple(format!("LD [I], V{}-V{}", x, y), Flow::Next),
+            0x3 if xochip => simple(format!("LD V{}-V{}, [I]", x, y), Flow::Next),
+            _ => None,
+        },
+        0x6000 => simple(format!("LD V{}, {:02x}", x, kk), Flow::Next),
+        0x7000 => simple(format!("ADD V{}, {:02x}", x, kk), Flow::Next),
+        0x8000 => match n {
+            0x0 => simple(format!("LD V{}, V{}", x, y), Flow::Next),
+            0x1 => simple(format!("OR V{}, V{}", x, y), Flow::Next),
+            0x2 => simple(format!("AND V{}, V{}", x, y), Flow::Next),
+            0x3 => simple(format!("XOR V{}, V{}", x, y), Flow::Next),
+            0x4 => simple(format!("ADD V{}, V{}", x, y), Flow::Next),
+            0x5 => simple(format!("SUB V{}, V{}", x, y), Flow::Next),
+            0x6 => simple(shift("SHR", x, y), Flow::Next),
+            0x7 => simple(format!("SUBN V{}, V{}", x, y), Flow::Next),
+            0xE => simple(shift("SHL", x, y), Flow::Next),
+            _ => None,
+        },
+        0x9000 if n == 0 => simple(format!("SNE V{}, V{}", x, y), Flow::Skip),
+        0xA000 => with_addr("LD I, ", nnn, Flow::Next),
+        0xB000 => {
+            let idx = if quirks.jump_with_vx { x } else { 0 };
+            with_addr(&format!("JP V{}, ", idx), nnn, Flow::JumpIndirect)
+        },
+        0xC000 => simple(format!("RND V{}, {:02x}", x, kk), Flow::Next),
+        0xD000 => simple(format!("DRW V{}, V{}, {:x}", x, y, n), Flow::Next),
+        0xE000 => match kk {
+            0x9E => simple(format!("SKP V{}", x), Flow::Skip),
+            0xA1 => simple(format!("SKNP V{}", x), Flow::Skip),
+            _ => None,
+        },
+        0xF000 => match kk {
+            0x00 if xochip && x == 0 => match next {
+                Some(addr) => with_addr("LD I, LONG ", addr, Flow::Next),
+                None => None,
+            },
+            0x01 if xochip => simple(format!("PLANE {:x}", x), Flow::Next),
+            0x02 if xochip && x == 0 => simple("LD AUDIO, [I]".to_string(), Flow::Next),
+            0x07 => simple(format!("LD V{}, DT", x), Flow::Next),
+            0x0A => simple(format!("LD V{}, K", x), Flow::Next),
+            0x15 => simple(format!("LD DT, V{}", x), Flow::Next),
+            0x18 => simple(format!("LD ST, V{}", x), Flow::Next),
+            0x1E => simple(format!("ADD I, V{}", x), Flow::Next),
+            0x29 => simple(format!("LD F, V{}", x), Flow::Next),
+            0x30 if superchip => simple(format!("LD HF, V{}", x), Flow::Next),
+            0x33 => simple(format!("LD B, V{}", x), Flow::Next),
+            0x3A if xochip => simple(format!("LD PITCH, V{}", x), Flow::Next),
+            0x55 => simple(format!("LD [I], V{}", x), Flow::Next),
+            0x65 => simple(format!("LD V{}, [I]", x), Flow::Next),
+            0x75 if superchip => simple(format!("LD R, V{}", x), Flow::Next),
+            0x85 if superchip => simple(format!("LD V{}, R", x), Flow::Next),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    decoded.map(|(operation, addr, flow)| Instruction {
+        opcode,
+        len: if opcode == 0xF000 && xochip { 4 } else { 2 },
+        operation,
+        addr,
+        flow,
+    })
+}
+
+// `8xy6`/`8xyE` are usually written without Vy, which most interpreters ignore
+fn shift(operation: &str, x: u16, y: u16) -> String {
+    if y == 0 {
+        format!("{} V{}", operation, x)
+    } else {
+        format!("{} V{}, V{}", operation, x, y)
+    }
+}
+
+/// A line of a disassembly listing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Line {
+    Code { addr: u16, instruction: Instruction },
+    Data { addr: u16, bytes: Vec<u8> },
+}
+
+/// The disassembly of a program, which can be printed as a listing with
+/// [`fmt::Display`]. The listing is valid input for the assembler.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listing {
+    pub lines: Vec<Line>,
+    /// Labels generated for jump, call and `LD I` targets.
+    pub labels: BTreeMap<u16, String>,
+    bytes: Vec<u8>,
+    origin: u16,
+}
+
+// Most bytes of data shown on a single line
+const DATA_BYTES_PER_LINE: usize = 8;
+
+/// Statically disassembles a program loaded at `origin`.
+///
+/// Code is told apart from data by following every path of execution from the
+/// start of the program, through jumps, calls and skips. Anything not reached
+/// this way, such as sprites, is treated as data. Targets of `Bnnn` jumps can't
+/// be known without running the program, so code only reached through them is
+/// also shown as data.
+pub fn disassemble(program: &[u8], origin: u16, platform: Platform, quirks: &Quirks) -> Listing {
+    let end = origin as usize + program.len();
+    let in_program = |addr: u16| (origin as usize..end).contains(&(addr as usize));
+
+    let word_at = |addr: u16| -> Option<u16> {
+        let idx = addr.checked_sub(origin)? as usize;
+        program.get(idx..idx + 2).map(|bytes| (bytes[0] as u16) << 8 | bytes[1] as u16)
+    };
+
+    let decode_at = |addr: u16| -> Option<Instruction> {
+        let opcode = word_at(addr)?;
+        decode(opcode, addr.checked_add(2).and_then(word_at), platform, quirks)
+    };
+
+    // Addresses of decoded instructions, and of every byte they cover
+    let mut instructions: BTreeMap<u16, Instruction> = BTreeMap::new();
+    let mut code_bytes: BTreeSet<u16> = BTreeSet::new();
+
+    let mut labels = BTreeMap::new();
+    let mut data_refs = BTreeSet::new();
+
+    let mut pending = vec![origin];
+    while let Some(addr) = pending.pop() {
+        if !in_program(addr) || code_bytes.contains(&addr) {
+            continue;
+        }
+
+        let instruction = match decode_at(addr) {
+            Some(instruction) => instruction,
+            None => continue,
+        };
+
+        // Don't decode instructions overlapping ones already found. Decoded
+        // instructions lie within the program, so the bytes they cover fit in a u16
+        let covered: Vec<u16> = (addr as u32..addr as u32 + instruction.len as u32).map(|a| a as u16).collect();
+        if covered.iter().any(|a| code_bytes.contains(a)) {
+            continue;
+        }
+        code_bytes.extend(covered);
+
+        // Execution can't continue past the end of 64K memory
+        let next = addr.checked_add(instruction.len);
+        match instruction.flow {
+            Flow::Next => pending.extend(next),
+            Flow::Skip => {
+                let skipped_len = next.and_then(decode_at).map(|i| i.len).unwrap_or(2);
+                pending.extend(next);
+                pending.extend(next.and_then(|next| next.checked_add(skipped_len)));
+            },
+            Flow::Jump(target) => {
+                labels.entry(target).or_insert_with(|| format!("label_{:04x}", target));
+                pending.push(target);
+            },
+            Flow::Call(target) => {
+                labels.insert(target, format!("sub_{:04x}", target));
+                pending.push(target);
+                pending.extend(next);
+            },
+            Flow::JumpIndirect | Flow::Stop => (),
+        }
+
+        if instruction.opcode & 0xF000 == 0xA000 || instruction.len == 4 {
+            data_refs.extend(instruction.addr);
+        }
+
+        instructions.insert(addr, instruction);
+    }
+
+    for addr in data_refs {
+        if in_program(addr) {
+            labels.entry(addr).or_insert_with(|| format!("data_{:04x}", addr));
+        }
+    }
+
+    // Labels are only useful if they can be placed at the start of a line, so
+    // drop those pointing into the middle of an instruction or outside the program
+    labels.retain(|addr, _| {
+        in_program(*addr) && (instructions.contains_key(addr) || !code_bytes.contains(addr))
+    });
+
+    // The cursor is wider than an address, as a program can end at the very
+    // top of 64K memory
+    let mut lines = Vec::new();
+    let mut cursor = origin as usize;
+    while cursor < end {
+        let addr = cursor as u16;
+        if let Some(instruction) = instructions.get(&addr) {
+            lines.push(Line::Code { addr, instruction: instruction.clone() });
+            cursor += instruction.len as usize;
+            continue;
+        }
+
+        // Data runs until the next code, label, or the line is full
+        let start = addr;
+        let mut bytes = Vec::new();
+        while cursor < end
+            && !code_bytes.contains(&(cursor as u16))
+            && bytes.len() < DATA_BYTES_PER_LINE
+            && (cursor == start as usize || !labels.contains_key(&(cursor as u16)))
+        {
+            bytes.push(program[cursor - origin as usize]);
+            cursor += 1;
+        }
+
+        lines.push(Line::Data { addr: start, bytes });
+    }
+
+    Listing { lines, labels, bytes: program.to_vec(), origin }
+}
+
+/// Guesses the platform a program loaded at `origin` was written for, from the
+/// instructions reachable from its start: SUPER-CHIP if any of them only exist
+/// there, and XO-CHIP if any only exist there or the program doesn't fit in
+/// the 4K of memory the other platforms have.
+pub fn detect_platform(program: &[u8], origin: u16) -> Platform {
+    if origin as usize + program.len() > Platform::SuperChip.memory_size() {
+        return Platform::XoChip;
+    }
+
+    let quirks = Quirks::default();
+    let mut platform = Platform::Chip8;
+
+    for line in disassemble(program, origin, Platform::XoChip, &quirks).lines {
+        let (addr, opcode) = match line {
+            Line::Code { addr, instruction } => (addr, instruction.opcode),
+            Line::Data { .. } => continue,
+        };
+        // The word after the opcode, which `F000 nnnn` needs to decode
+        let offset = (addr - origin) as usize + 2;
+        let next = program.get(offset..offset + 2).map(|word| u16::from_be_bytes([word[0], word[1]]));
+
+        // Opcodes either don't exist on the older platforms, or mean something
+        // else, like `00FF` being `SYS 0ff` on CHIP-8 and `HIGH` on SUPER-CHIP
+        let decode_on = |platform| decode(opcode, next, platform, &quirks);
+        if decode_on(Platform::SuperChip) != decode_on(Platform::XoChip) {
+            return Platform::XoChip;
+        }
+        if decode_on(Platform::Chip8) != decode_on(Platform::SuperChip) {
+            platform = Platform::SuperChip;
+        }
+    }
+
+    platform
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in &self.lines {
+            let addr = match line {
+                Line::Code { addr, .. } | Line::Data { addr, .. } => *addr,
+            };
+
+            if let Some(label) = self.labels.get(&addr) {
+                writeln!(f, "{}:", label)?;
+            }
+
+            let (text, len) = match line {
+                Line::Code { instruction, .. } => {
+                    let label = instruction.addr.and_then(|target| self.labels.get(&target));
+                    let text = match label {
+                        Some(label) => instruction.mnemonic_with_label(label),
+                        None => instruction.mnemonic(),
+                    };
+                    (text, instruction.len as usize)
+                },
+                Line::Data { bytes, .. } => {
+                    let bytes: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                    (format!("db {}", bytes.join(", ")), bytes.len())
+                },
+            };
+
+            // Addresses and raw bytes go in a comment, so the listing can be reassembled
+            let start = (addr - self.origin) as usize;
+            let raw: Vec<String> = self.bytes[start..start + len].iter()
+                .map(|b| format!("{:02x}", b))
+                .collect();
+            writeln!(f, "    {:<36}; {:04x}: {}", text, addr, raw.join(" "))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_platforms() {
+        assert_eq!(detect_platform(&[0x00, 0xE0, 0x12, 0x00], 0x200), Platform::Chip8);
+        // HIGH, then EXIT
+        assert_eq!(detect_platform(&[0x00, 0xFF, 0x00, 0xFD], 0x200), Platform::SuperChip);
+        // LD I, LONG 1234 is the only XO-CHIP instruction
+        assert_eq!(detect_platform(&[0xF0, 0x00, 0x12, 0x34, 0x00, 0xFD], 0x200), Platform::XoChip);
+        assert_eq!(detect_platform(&[0xF1, 0x01, 0x12, 0x00], 0x200), Platform::XoChip);
+        // Too big for 4K of memory
+        assert_eq!(detect_platform(&[0; 0xE01], 0x200), Platform::XoChip);
+        assert_eq!(detect_platform(&[0; 0xA01], 0x600), Platform::XoChip);
+
+        // Unreachable bytes are only data, whatever they would decode to
+        assert_eq!(detect_platform(&[0x12, 0x00, 0x00, 0xFF], 0x200), Platform::Chip8);
+    }
+}
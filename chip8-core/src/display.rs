@@ -0,0 +1,234 @@
+/// Width of the original CHIP-8 display.
+pub const LORES_WIDTH: usize = 64;
+/// Height of the original CHIP-8 display.
+pub const LORES_HEIGHT: usize = 32;
+
+/// Width of the SUPER-CHIP high-resolution mode.
+pub const HIRES_WIDTH: usize = 128;
+/// Height of the SUPER-CHIP high-resolution mode.
+pub const HIRES_HEIGHT: usize = 64;
+
+/// Number of bitplanes. Each pixel holds one bit per plane: CHIP-8 and
+/// SUPER-CHIP programs only use the first plane, while XO-CHIP programs can
+/// draw to both, giving 4 colours.
+pub const PLANE_COUNT: usize = 2;
+
+/// A monochrome (or with XO-CHIP, 4 colour) display whose resolution can be
+/// switched at runtime.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Display {
+    buffer: Vec<u8>,
+    width: usize,
+    height: usize,
+    // Bitmask of the planes affected by drawing, clearing and scrolling
+    selected_planes: u8,
+}
+
+impl Default for Display {
+    fn default() -> Display {
+        Display::new()
+    }
+}
+
+impl Display {
+    /// Creates a blank low-resolution display, with the first plane selected.
+    pub fn new() -> Display {
+        Display {
+            buffer: vec![0; LORES_WIDTH * LORES_HEIGHT],
+            width: LORES_WIDTH,
+            height: LORES_HEIGHT,
+            selected_planes: 0b01,
+        }
+    }
+
+    /// Pixels of the display, row by row. Each pixel is the bitmask of the
+    /// planes it is set in, i.e. a colour index between 0 and 3.
+    pub fn buffer(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // Bitmask of the selected planes, for save states
+    pub(crate) fn selected_planes(&self) -> u8 {
+        self.selected_planes
+    }
+
+    // Rebuilds a display from a save state, returning None if the parts don't
+    // make up a valid display
+    pub(crate) fn from_parts(width: usize, height: usize, selected_planes: u8, buffer: Vec<u8>) -> Option<Display> {
+        let valid_size = (width, height) == (LORES_WIDTH, LORES_HEIGHT) || (width, height) == (HIRES_WIDTH, HIRES_HEIGHT);
+        if !valid_size || buffer.len() != width * height || selected_planes > 0b11 || buffer.iter().any(|p| *p > 0b11) {
+            return None;
+        }
+
+        Some(Display { buffer, width, height, selected_planes })
+    }
+
+    /// Switches between low and high resolution, which clears the display.
+    pub fn set_hires(&mut self, hires: bool) {
+        let (width, height) = if hires {
+            (HIRES_WIDTH, HIRES_HEIGHT)
+        } else {
+            (LORES_WIDTH, LORES_HEIGHT)
+        };
+
+        self.buffer = vec![0; width * height];
+        self.width = width;
+        self.height = height;
+    }
+
+    /// Selects the planes affected by drawing, clearing and scrolling, as a bitmask.
+    pub fn select_planes(&mut self, planes: u8) {
+        self.selected_planes = planes & 0b11;
+    }
+
+    pub fn selected_plane_count(&self) -> usize {
+        self.selected_planes.count_ones() as usize
+    }
+
+    /// Draws an 8 pixel wide sprite, one byte per row. When several planes are
+    /// selected, `sprite` holds the data for each plane one after the other.
+    ///
+    /// Returns true if any pixel was un-set while drawing. The sprite's position
+    /// always wraps around the screen, but if `clip` is set any part of the sprite
+    /// that goes past the edge of the screen is not drawn.
+    pub fn draw(&mut self, x: u32, y: u32, sprite: &[u8], clip: bool) -> bool {
+        self.draw_planes(x, y, sprite, false, clip)
+    }
+
+    /// Draws a 16 pixel wide sprite, two bytes per row. Otherwise the same as
+    /// [`Display::draw`].
+    pub fn draw_wide(&mut self, x: u32, y: u32, sprite: &[u8], clip: bool) -> bool {
+        self.draw_planes(x, y, sprite, true, clip)
+    }
+
+    fn draw_planes(&mut self, x: u32, y: u32, sprite: &[u8], wide: bool, clip: bool) -> bool {
+        let plane_count = self.selected_plane_count();
+        if sprite.is_empty() || plane_count == 0 {
+            return false;
+        }
+
+        let planes: Vec<u8> = (0..PLANE_COUNT)
+            .map(|n| 1 << n)
+            .filter(|plane| self.selected_planes & plane != 0)
+            .collect();
+        let plane_sprites = sprite.chunks(sprite.len() / plane_count);
+
+        let erased_pixels: Vec<bool> = planes.into_iter()
+            .zip(plane_sprites)
+            .map(|(plane, data)| {
+                if wide {
+                    let rows = data.chunks(2).map(|pair| (pair[0] as u16) << 8 | pair[1] as u16);
+                    self.draw_rows(x, y, rows, 16, plane, clip)
+                } else {
+                    let rows = data.iter().map(|byte| (*byte as u16) << 8);
+                    self.draw_rows(x, y, rows, 8, plane, clip)
+                }
+            })
+            .collect();
+
+        erased_pixels.iter().any(|x| *x)
+    }
+
+    fn draw_rows<I>(&mut self, x: u32, y: u32, rows: I, width: u32, plane: u8, clip: bool) -> bool
+    where
+        I: Iterator<Item = u16>,
+    {
+        let x = x % self.width as u32;
+        let y = y % self.height as u32;
+
+        let erased_pixels: Vec<bool> = rows
+            .enumerate()
+            .map(|(offset, row)| self.draw_row(x, y + (offset as u32), row, width, plane, clip))
+            .collect();
+
+        erased_pixels.iter().any(|x| *x)
+    }
+
+    // Draws the leftmost `width` bits of `row` onto `plane`.
+    // Returns true if any pixel was un-set while drawing
+    fn draw_row(&mut self, x: u32, y: u32, row: u16, width: u32, plane: u8, clip: bool) -> bool {
+        let mut erased = false;
+
+        let (screen_width, screen_height) = (self.width as u32, self.height as u32);
+
+        if clip && y >= screen_height {
+            return false;
+        }
+
+        let j = y % screen_height;
+        for n in 0..width {
+            if clip && x + n >= screen_width {
+                break;
+            }
+
+            let bit = ((1 << (16-n-1)) & row) > 0;
+            let i = (x + n) % screen_width;
+            let idx = (j * screen_width + i) as usize;
+
+            if bit {
+                // If pixel was already set, this will cause it to be erased
+                erased |= self.buffer[idx] & plane != 0;
+
+                self.buffer[idx] ^= plane;
+            }
+        }
+
+        erased
+    }
+
+    /// Scrolls the selected planes down by `n` pixels.
+    pub fn scroll_down(&mut self, n: usize) {
+        self.scroll(0, n as isize);
+    }
+
+    /// Scrolls the selected planes up by `n` pixels.
+    pub fn scroll_up(&mut self, n: usize) {
+        self.scroll(0, -(n as isize));
+    }
+
+    /// Scrolls the selected planes right by `n` pixels.
+    pub fn scroll_right(&mut self, n: usize) {
+        self.scroll(n as isize, 0);
+    }
+
+    /// Scrolls the selected planes left by `n` pixels.
+    pub fn scroll_left(&mut self, n: usize) {
+        self.scroll(-(n as isize), 0);
+    }
+
+    // Moves the selected planes by (dx, dy), filling the uncovered area with
+    // unset pixels
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let previous = self.buffer.clone();
+        let (width, height) = (self.width as isize, self.height as isize);
+
+        for j in 0..height {
+            for i in 0..width {
+                let (src_i, src_j) = (i - dx, j - dy);
+                let moved = if (0..width).contains(&src_i) && (0..height).contains(&src_j) {
+                    previous[(src_j * width + src_i) as usize] & self.selected_planes
+                } else {
+                    0
+                };
+
+                let idx = (j * width + i) as usize;
+                self.buffer[idx] = (self.buffer[idx] & !self.selected_planes) | moved;
+            }
+        }
+    }
+
+    /// Clears the selected planes.
+    pub fn clear(&mut self) {
+        for pixel in self.buffer.iter_mut() {
+            *pixel &= !self.selected_planes;
+        }
+    }
+}
@@ -0,0 +1,67 @@
+/// Number of keys on the keypad, labelled `0` to `F`.
+pub const KEY_COUNT: usize = 16;
+
+/// A key going down or coming back up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEvent {
+    Pressed(u8),
+    Released(u8),
+}
+
+/// State of the CHIP-8's 16-key hex keypad.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Keypad {
+    pressed: [bool; KEY_COUNT],
+}
+
+impl Keypad {
+    pub fn new() -> Keypad {
+        Keypad::default()
+    }
+
+    /// Replaces the set of pressed keys, returning the keys that went down or
+    /// came up, in ascending order. Keys outside of `0`-`F` are ignored.
+    pub fn set_pressed(&mut self, keys: &[u8]) -> Vec<KeyEvent> {
+        let mut pressed = [false; KEY_COUNT];
+        for key in keys.iter().filter(|key| (**key as usize) < KEY_COUNT) {
+            pressed[*key as usize] = true;
+        }
+
+        let events = (0..KEY_COUNT as u8)
+            .filter_map(|key| match (self.pressed[key as usize], pressed[key as usize]) {
+                (false, true) => Some(KeyEvent::Pressed(key)),
+                (true, false) => Some(KeyEvent::Released(key)),
+                _ => None,
+            })
+            .collect();
+
+        self.pressed = pressed;
+        events
+    }
+
+    /// Presses or releases a single key, returning false if it was already
+    /// down or up. Keys outside of `0`-`F` are ignored.
+    pub fn apply(&mut self, event: KeyEvent) -> bool {
+        let (key, pressed) = match event {
+            KeyEvent::Pressed(key) => (key as usize, true),
+            KeyEvent::Released(key) => (key as usize, false),
+        };
+
+        if key >= KEY_COUNT || self.pressed[key] == pressed {
+            return false;
+        }
+        self.pressed[key] = pressed;
+        true
+    }
+
+    /// Whether `key` is held down. Like the COSMAC VIP, only the low nibble
+    /// of `key` is used.
+    pub fn is_pressed(&self, key: u8) -> bool {
+        self.pressed[(key & 0x0F) as usize]
+    }
+
+    /// The keys currently held down, in ascending order.
+    pub fn pressed_keys(&self) -> Vec<u8> {
+        (0..KEY_COUNT as u8).filter(|key| self.is_pressed(*key)).collect()
+    }
+}
@@ -0,0 +1,50 @@
+//! An implementation of the [CHIP-8 interpreter](https://en.wikipedia.org/wiki/CHIP-8),
+//! along with the SUPER-CHIP and XO-CHIP extensions.
+//!
+//! The crate has no windowing or audio dependencies: a frontend owns a [`Cpu`],
+//! calls [`Cpu::cycle`] at the desired clock speed and [`Cpu::timer_interrupt`] at
+//! 60Hz, feeds it the keys held down, and presents [`Cpu::display_buffer`] however
+//! it likes.
+//!
+//! ```no_run
+//! use chip8_core::{Cpu, Platform, Quirks};
+//!
+//! let rom = std::fs::read("roms/breakout.ch8").unwrap();
+//!
+//! let mut cpu = Cpu::new(Platform::Chip8, Quirks::default());
+//! cpu.load_binary(&rom).expect("rom doesn't fit in memory");
+//!
+//! loop {
+//!     cpu.update_keyboard(&[0x4]);
+//!     for _ in 0..9 {
+//!         cpu.cycle().expect("cpu fault");
+//!     }
+//!     cpu.timer_interrupt();
+//!     // draw cpu.display_buffer() ...
+//! }
+//! ```
+
+pub mod asm;
+pub mod audio;
+pub mod cpu;
+pub mod disasm;
+pub mod display;
+pub mod keypad;
+pub mod movie;
+pub mod platform;
+pub mod quirks;
+pub mod random;
+pub mod rewind;
+pub mod rom;
+pub mod trace;
+
+pub use cpu::{AccessKind, Cpu, CpuError, CycleOutcome, MemoryAccess, StateError};
+pub use display::Display;
+pub use keypad::{KeyEvent, Keypad};
+pub use movie::{Movie, MovieError, MovieFrame};
+pub use platform::Platform;
+pub use quirks::{LoadStoreIncrement, Quirks};
+pub use random::{Random, RandomMode};
+pub use rewind::Rewind;
+pub use rom::{Rom, RomError, RomWarning};
+pub use trace::{TraceEntry, Tracer};
@@ -0,0 +1,346 @@
+//! Movies: a recording of the keypad input for every frame of a play session,
+//! which replays exactly given the same ROM, quirks and random seed.
+//!
+//! Movies are stored as text, a header followed by one line per frame:
+//!
+//! ```text
+//! chip8-movie 4
+//! rom 5ad3c7ca3b6d1bd5
+//! platform chip8
+//! quirks shift=1 load_store=unchanged jump_with_vx=0 vf_reset=0 clipping=0 display_wait=0 key_wait_release=1
+//! clock 540
+//! load 200
+//! random xorshift 42
+//! |
+//! 4|
+//! 4|
+//! |1f1d341cab07e169
+//! ```
+//!
+//! Each frame lists the hex keys held down, and optionally a checkpoint: the
+//! hash of the framebuffer at the end of the frame, from [`display_hash`].
+//!
+//! Before version 4, `Fx0A` completed as soon as a key was pressed, with the
+//! key passed to [`Cpu::key_press_interrupt`] listed between the two, as in
+//! `4|4|`.
+
+use std::fmt;
+
+use crate::cpu::Cpu;
+use crate::platform::Platform;
+use crate::quirks::{LoadStoreIncrement, Quirks};
+use crate::random::{Random, RandomMode};
+use crate::rom::DEFAULT_LOAD_ADDRESS;
+
+const VERSION: u32 = 4;
+
+// Version 1 movies have no clock speed, as it was always 540Hz
+const VERSION_1_CLOCK_HZ: u32 = 540;
+
+/// Number of frames between checkpoints when recording.
+pub const CHECKPOINT_INTERVAL: usize = 60;
+
+/// An error in a movie file, at `line` (counting from 1).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MovieError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+/// The input for one frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MovieFrame {
+    /// Keys held down, passed to [`Cpu::update_keyboard`].
+    pub keys: Vec<u8>,
+    /// The key passed to [`Cpu::key_press_interrupt`], in movies before
+    /// version 4. It isn't written out.
+    pub key_press: Option<u8>,
+    /// Hash of the framebuffer at the end of the frame, checked on playback.
+    pub checkpoint: Option<u64>,
+}
+
+impl MovieFrame {
+    /// Feeds the frame's input to `cpu`, before running the frame.
+    pub fn apply(&self, cpu: &mut Cpu) {
+        cpu.update_keyboard(&self.keys);
+        if let Some(key) = self.key_press {
+            cpu.key_press_interrupt(key);
+        }
+    }
+
+    /// Whether `cpu` matches the checkpoint, if there is one, after running
+    /// the frame.
+    pub fn matches(&self, cpu: &Cpu) -> bool {
+        self.checkpoint.map_or(true, |hash| hash == display_hash(cpu))
+    }
+}
+
+/// A recorded play session, along with everything needed to replay it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    /// Hash of the ROM the movie was recorded with, from [`fnv1a`].
+    pub rom_hash: u64,
+    pub platform: Platform,
+    pub quirks: Quirks,
+    /// Instructions run per second, spread evenly over the frames.
+    pub clock_hz: u32,
+    /// Address the ROM is loaded at, passed to [`Cpu::load_binary_at`].
+    pub load_address: u16,
+    pub random_mode: RandomMode,
+    pub seed: u64,
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    /// Starts an empty movie for `rom` running with the given settings.
+    pub fn new(
+        rom: &[u8],
+        platform: Platform,
+        quirks: Quirks,
+        load_address: u16,
+        clock_hz: u32,
+        random_mode: RandomMode,
+        seed: u64,
+    ) -> Movie {
+        Movie {
+            rom_hash: fnv1a(rom),
+            platform,
+            quirks,
+            clock_hz,
+            load_address,
+            random_mode,
+            seed,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Whether the movie was recorded with `rom`.
+    pub fn matches_rom(&self, rom: &[u8]) -> bool {
+        fnv1a(rom) == self.rom_hash
+    }
+
+    /// The random number generator to replay the movie with.
+    pub fn random(&self) -> Random {
+        Random::new(self.random_mode, self.seed)
+    }
+
+    /// Appends a frame after it has run, with the keys given to `cpu` at its
+    /// start. Every [`CHECKPOINT_INTERVAL`] frames a checkpoint of the display
+    /// is added.
+    pub fn record(&mut self, keys: &[u8], cpu: &Cpu) {
+        let checkpoint = if (self.frames.len() + 1) % CHECKPOINT_INTERVAL == 0 {
+            Some(display_hash(cpu))
+        } else {
+            None
+        };
+
+        self.frames.push(MovieFrame { keys: keys.to_vec(), key_press: None, checkpoint });
+    }
+
+    pub fn parse(source: &str) -> Result<Movie, MovieError> {
+        let error = |line: usize, message: &str| MovieError { line, message: message.to_string() };
+        let end = source.lines().count() + 1;
+        let mut lines = source.lines().enumerate().map(|(idx, line)| (idx + 1, line.trim()));
+
+        let version = match lines.next().map(|(line, text)| (line, text.strip_prefix("chip8-movie "))) {
+            Some((line, Some(version))) => match version.parse() {
+                Ok(version) if (1..=VERSION).contains(&version) => version,
+                _ => return Err(error(line, &format!("unsupported movie version {}", version))),
+            },
+            _ => return Err(error(1, "not a movie")),
+        };
+
+        let mut header = |name: &str| match lines.next() {
+            Some((line, text)) => match text.strip_prefix(name).and_then(|rest| rest.strip_prefix(' ')) {
+                Some(value) => Ok((line, value)),
+                None => Err(error(line, &format!("expected '{}'", name))),
+            },
+            None => Err(error(end, &format!("missing '{}'", name))),
+        };
+
+        let (line, rom_hash) = header("rom")?;
+        let rom_hash = u64::from_str_radix(rom_hash, 16).map_err(|_| error(line, "invalid rom hash"))?;
+
+        let (line, platform) = header("platform")?;
+        let platform = match platform {
+            "chip8" => Platform::Chip8,
+            "schip" => Platform::SuperChip,
+            "xochip" => Platform::XoChip,
+            _ => return Err(error(line, &format!("unknown platform '{}'", platform))),
+        };
+
+        let (line, quirks) = header("quirks")?;
+        let quirks = parse_quirks(quirks, version).map_err(|message| error(line, &message))?;
+
+        let clock_hz = if version >= 2 {
+            let (line, clock_hz) = header("clock")?;
+            match clock_hz.parse() {
+                Ok(clock_hz) if clock_hz > 0 => clock_hz,
+                _ => return Err(error(line, "invalid clock speed")),
+            }
+        } else {
+            VERSION_1_CLOCK_HZ
+        };
+
+        // Earlier movies were all loaded at the default address
+        let load_address = if version >= 3 {
+            let (line, load_address) = header("load")?;
+            u16::from_str_radix(load_address, 16).map_err(|_| error(line, "invalid load address"))?
+        } else {
+            DEFAULT_LOAD_ADDRESS
+        };
+
+        let (line, random) = header("random")?;
+        let (random_mode, seed) = match random.split_once(' ') {
+            Some(("xorshift", seed)) => (RandomMode::Xorshift, seed),
+            Some(("vip", seed)) => (RandomMode::CosmacVip, seed),
+            _ => return Err(error(line, "expected 'random xorshift|vip <seed>'")),
+        };
+        let seed = seed.parse().map_err(|_| error(line, "invalid seed"))?;
+
+        let mut frames = Vec::new();
+        for (line, text) in lines.filter(|(_, text)| !text.is_empty()) {
+            frames.push(parse_frame(text, version).map_err(|message| error(line, &message))?);
+        }
+
+        Ok(Movie { rom_hash, platform, quirks, clock_hz, load_address, random_mode, seed, frames })
+    }
+}
+
+impl fmt::Display for Movie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |value: bool| value as u8;
+
+        writeln!(f, "chip8-movie {}", VERSION)?;
+        writeln!(f, "rom {:016x}", self.rom_hash)?;
+        writeln!(f, "platform {}", match self.platform {
+            Platform::Chip8 => "chip8",
+            Platform::SuperChip => "schip",
+            Platform::XoChip => "xochip",
+        })?;
+        writeln!(
+            f,
+            "quirks shift={} load_store={} jump_with_vx={} vf_reset={} clipping={} display_wait={} key_wait_release={}",
+            flag(self.quirks.shift),
+            match self.quirks.load_store_increment {
+                LoadStoreIncrement::Unchanged => "unchanged",
+                LoadStoreIncrement::ByX => "x",
+                LoadStoreIncrement::ByXPlusOne => "x+1",
+            },
+            flag(self.quirks.jump_with_vx),
+            flag(self.quirks.vf_reset),
+            flag(self.quirks.clipping),
+            flag(self.quirks.display_wait),
+            flag(self.quirks.key_wait_release),
+        )?;
+        writeln!(f, "clock {}", self.clock_hz)?;
+        writeln!(f, "load {:x}", self.load_address)?;
+        writeln!(f, "random {} {}", match self.random_mode {
+            RandomMode::Xorshift => "xorshift",
+            RandomMode::CosmacVip => "vip",
+        }, self.seed)?;
+
+        for frame in &self.frames {
+            let keys: String = frame.keys.iter().map(|key| format!("{:x}", key)).collect();
+            let checkpoint = frame.checkpoint.map(|hash| format!("{:016x}", hash)).unwrap_or_default();
+            writeln!(f, "{}|{}", keys, checkpoint)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The 64-bit FNV-1a hash, used to identify ROMs and compare framebuffers.
+pub fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Hash of the pixels on the display, for comparing two runs.
+pub fn display_hash(cpu: &Cpu) -> u64 {
+    fnv1a(cpu.display_buffer())
+}
+
+fn parse_quirks(text: &str, version: u32) -> Result<Quirks, String> {
+    let mut quirks = Quirks::default();
+    // Earlier movies completed `Fx0A` when a key was pressed
+    if version < 4 {
+        quirks.key_wait_release = false;
+    }
+    let mut seen = Vec::new();
+
+    for field in text.split_whitespace() {
+        let (name, value) = field.split_once('=').ok_or(format!("expected NAME=VALUE, got '{}'", field))?;
+        let flag = || match value {
+            "0" => Ok(false),
+            "1" => Ok(true),
+            _ => Err(format!("invalid value '{}' for {}", value, name)),
+        };
+
+        match name {
+            "shift" => quirks.shift = flag()?,
+            "load_store" => {
+                quirks.load_store_increment = match value {
+                    "unchanged" => LoadStoreIncrement::Unchanged,
+                    "x" => LoadStoreIncrement::ByX,
+                    "x+1" => LoadStoreIncrement::ByXPlusOne,
+                    _ => return Err(format!("invalid value '{}' for {}", value, name)),
+                }
+            },
+            "jump_with_vx" => quirks.jump_with_vx = flag()?,
+            "vf_reset" => quirks.vf_reset = flag()?,
+            "clipping" => quirks.clipping = flag()?,
+            "display_wait" => quirks.display_wait = flag()?,
+            "key_wait_release" if version >= 4 => quirks.key_wait_release = flag()?,
+            _ => return Err(format!("unknown quirk '{}'", name)),
+        }
+
+        seen.push(name);
+    }
+
+    // A missing quirk would silently change how the movie plays back
+    let names = ["shift", "load_store", "jump_with_vx", "vf_reset", "clipping", "display_wait", "key_wait_release"];
+    // `key_wait_release` was added in version 4
+    let names = if version >= 4 { &names[..] } else { &names[..6] };
+    for name in names {
+        if !seen.contains(name) {
+            return Err(format!("missing quirk '{}'", name));
+        }
+    }
+
+    Ok(quirks)
+}
+
+fn parse_frame(text: &str, version: u32) -> Result<MovieFrame, String> {
+    let fields: Vec<&str> = text.split('|').collect();
+    let (keys, key_press, checkpoint) = match fields[..] {
+        [keys, checkpoint] if version >= 4 => (keys, "", checkpoint),
+        [keys, key_press, checkpoint] if version < 4 => (keys, key_press, checkpoint),
+        _ if version >= 4 => return Err(format!("expected KEYS|CHECKPOINT, got '{}'", text)),
+        _ => return Err(format!("expected KEYS|KEY_PRESS|CHECKPOINT, got '{}'", text)),
+    };
+
+    let key = |c: char| c.to_digit(16).map(|key| key as u8).ok_or(format!("invalid key '{}'", c));
+
+    let keys = keys.chars().map(key).collect::<Result<Vec<u8>, String>>()?;
+    let key_press = match key_press.len() {
+        0 => None,
+        1 => Some(key(key_press.chars().next().unwrap())?),
+        _ => return Err(format!("invalid key press '{}'", key_press)),
+    };
+    let checkpoint = match checkpoint {
+        "" => None,
+        hash => Some(u64::from_str_radix(hash, 16).map_err(|_| format!("invalid checkpoint '{}'", hash))?),
+    };
+
+    Ok(MovieFrame { keys, key_press, checkpoint })
+}
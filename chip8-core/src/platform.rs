@@ -0,0 +1,60 @@
+use std::fmt;
+
+use crate::quirks::Quirks;
+
+/// The instruction set a ROM is written for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    /// The original CHIP-8 instruction set.
+    Chip8,
+    /// SUPER-CHIP 1.1, adding a high-resolution mode, scrolling and large sprites.
+    SuperChip,
+    /// XO-CHIP, extending SUPER-CHIP with 64K of memory, a second bitplane and
+    /// programmable audio.
+    XoChip,
+}
+
+impl Platform {
+    pub fn has_superchip_instructions(self) -> bool {
+        match self {
+            Platform::Chip8 => false,
+            Platform::SuperChip | Platform::XoChip => true,
+        }
+    }
+
+    pub fn has_xochip_instructions(self) -> bool {
+        self == Platform::XoChip
+    }
+
+    /// Size of the addressable memory, in bytes.
+    pub fn memory_size(self) -> usize {
+        match self {
+            Platform::Chip8 | Platform::SuperChip => 0x1000,
+            Platform::XoChip => 0x10000,
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Platform::Chip8 => write!(f, "CHIP-8"),
+            Platform::SuperChip => write!(f, "SUPER-CHIP"),
+            Platform::XoChip => write!(f, "XO-CHIP"),
+        }
+    }
+}
+
+/// Looks up an interpreter by the name it is selected with on the command line
+/// (`vip`, `chip48`, `schip`, `xochip` or `default`), returning the platform it
+/// implements along with its quirks.
+pub fn preset(name: &str) -> Option<(Platform, Quirks)> {
+    match name {
+        "vip" => Some((Platform::Chip8, Quirks::cosmac_vip())),
+        "chip48" => Some((Platform::Chip8, Quirks::chip48())),
+        "schip" => Some((Platform::SuperChip, Quirks::superchip())),
+        "xochip" => Some((Platform::XoChip, Quirks::xochip())),
+        "default" => Some((Platform::Chip8, Quirks::default())),
+        _ => None,
+    }
+}
@@ -0,0 +1,104 @@
+/// How `Fx55`/`Fx65` leave `I` after storing/loading registers V0 through Vx.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadStoreIncrement {
+    /// `I` is left untouched (SUPER-CHIP).
+    Unchanged,
+    /// `I` is incremented by x (CHIP-48).
+    ByX,
+    /// `I` is incremented by x + 1, pointing just past the last register (COSMAC VIP).
+    ByXPlusOne,
+}
+
+/// Instructions whose behaviour differs between CHIP-8 interpreters. ROMs are
+/// usually written against one particular interpreter, and may not run correctly
+/// unless its interpretation of these instructions is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// `8xy6`/`8xyE` shift Vx in place, instead of setting Vx = Vy shifted.
+    pub shift: bool,
+    /// How `Fx55`/`Fx65` modify `I`.
+    pub load_store_increment: LoadStoreIncrement,
+    /// `Bnnn` jumps to nnn + Vx (where x is the highest nibble of nnn), instead of nnn + V0.
+    pub jump_with_vx: bool,
+    /// `8xy1`/`8xy2`/`8xy3` reset VF to 0.
+    pub vf_reset: bool,
+    /// Sprites drawn past the edge of the screen are clipped, instead of wrapping around.
+    pub clipping: bool,
+    /// `Dxyn` waits for the next display refresh before continuing execution.
+    pub display_wait: bool,
+    /// `Fx0A` completes once the key is released, instead of as soon as it is
+    /// pressed.
+    pub key_wait_release: bool,
+}
+
+impl Quirks {
+    /// The original interpreter on the RCA COSMAC VIP.
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift: false,
+            load_store_increment: LoadStoreIncrement::ByXPlusOne,
+            jump_with_vx: false,
+            vf_reset: true,
+            clipping: true,
+            display_wait: true,
+            key_wait_release: true,
+        }
+    }
+
+    /// The CHIP-48 interpreter for the HP-48 calculators.
+    pub fn chip48() -> Quirks {
+        Quirks {
+            shift: true,
+            load_store_increment: LoadStoreIncrement::ByX,
+            jump_with_vx: true,
+            vf_reset: false,
+            clipping: true,
+            display_wait: false,
+            key_wait_release: false,
+        }
+    }
+
+    /// SUPER-CHIP 1.1, the successor to CHIP-48.
+    pub fn superchip() -> Quirks {
+        Quirks {
+            shift: true,
+            load_store_increment: LoadStoreIncrement::Unchanged,
+            jump_with_vx: true,
+            vf_reset: false,
+            clipping: true,
+            display_wait: false,
+            key_wait_release: false,
+        }
+    }
+
+    /// XO-CHIP, as implemented by Octo.
+    pub fn xochip() -> Quirks {
+        Quirks {
+            shift: false,
+            load_store_increment: LoadStoreIncrement::ByXPlusOne,
+            jump_with_vx: false,
+            vf_reset: false,
+            clipping: false,
+            display_wait: false,
+            key_wait_release: true,
+        }
+    }
+}
+
+impl Default for Quirks {
+    /// The emulator's own mix of quirks, which most of the ROMs in the `roms`
+    /// folder run correctly with. `Fx0A` waits for the key to be released, as
+    /// on the COSMAC VIP, where it used to complete as soon as a key was
+    /// pressed; turning off `key_wait_release` brings that back.
+    fn default() -> Quirks {
+        Quirks {
+            shift: true,
+            load_store_increment: LoadStoreIncrement::Unchanged,
+            jump_with_vx: false,
+            vf_reset: false,
+            clipping: false,
+            display_wait: false,
+            key_wait_release: true,
+        }
+    }
+}
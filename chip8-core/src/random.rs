@@ -0,0 +1,178 @@
+//! The random number source behind `Cxkk`.
+
+use rand::Rng;
+
+/// The algorithm used to generate random numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RandomMode {
+    /// A 32-bit xorshift generator.
+    Xorshift,
+    /// Modelled on the COSMAC VIP interpreter, which keeps a 16-bit counter and
+    /// adds a byte of its own code, indexed by the low byte of the counter, to
+    /// the high byte, which is the result. There is no interpreter in memory
+    /// here, so the byte comes from the first page of the program at `0x200`
+    /// instead. The sequence is short and uneven, like the original's.
+    CosmacVip,
+}
+
+/// A seedable random number generator, so that runs can be reproduced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Random {
+    mode: RandomMode,
+    state: u32,
+}
+
+impl Random {
+    /// Creates a generator which always produces the same sequence for `seed`.
+    pub fn new(mode: RandomMode, seed: u64) -> Random {
+        let state = match mode {
+            // Xorshift gets stuck at zero
+            RandomMode::Xorshift => match (mix(seed) >> 32) as u32 {
+                0 => 0x2545F491,
+                state => state,
+            },
+            RandomMode::CosmacVip => seed as u16 as u32,
+        };
+
+        Random { mode, state }
+    }
+
+    /// Creates a generator with a seed from the operating system.
+    pub fn from_entropy(mode: RandomMode) -> Random {
+        Random::new(mode, seed_from_entropy())
+    }
+
+    pub fn mode(&self) -> RandomMode {
+        self.mode
+    }
+
+    // Generates the next byte, with `memory` being the cpu's memory
+    pub(crate) fn next_byte(&mut self, memory: &[u8]) -> u8 {
+        match self.mode {
+            RandomMode::Xorshift => {
+                let mut x = self.state;
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                self.state = x;
+                (x >> 24) as u8
+            },
+            RandomMode::CosmacVip => {
+                let counter = (self.state as u16).wrapping_add(1);
+                let [high, low] = counter.to_be_bytes();
+                let high = high.wrapping_add(memory[0x200 + low as usize]);
+                self.state = u16::from_be_bytes([high, low]) as u32;
+                high
+            },
+        }
+    }
+
+    // The whole state of the generator, for save states
+    pub(crate) fn to_parts(self) -> (RandomMode, u32) {
+        (self.mode, self.state)
+    }
+
+    pub(crate) fn from_parts(mode: RandomMode, state: u32) -> Option<Random> {
+        match mode {
+            RandomMode::Xorshift if state == 0 => None,
+            RandomMode::CosmacVip if state > 0xFFFF => None,
+            _ => Some(Random { mode, state }),
+        }
+    }
+}
+
+/// A seed from the operating system, for when a run doesn't need to be
+/// reproduced but the seed still needs recording.
+pub fn seed_from_entropy() -> u64 {
+    rand::thread_rng().gen()
+}
+
+// The SplitMix64 finalizer, so that similar seeds like 1 and 2 don't start off
+// with similar numbers
+fn mix(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ z >> 30).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ z >> 27).wrapping_mul(0x94D049BB133111EB);
+    z ^ z >> 31
+}
+
+impl Default for Random {
+    fn default() -> Random {
+        Random::from_entropy(RandomMode::Xorshift)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cpu, Platform, Quirks};
+
+    #[test]
+    fn xorshift_sequence() {
+        let memory = [0; 0x1000];
+
+        // The state is the top half of SplitMix64's first output for the seed
+        let mut random = Random::new(RandomMode::Xorshift, 0);
+        assert_eq!(random.to_parts(), (RandomMode::Xorshift, 0xE220A839));
+        let bytes: Vec<u8> = (0..8).map(|_| random.next_byte(&memory)).collect();
+        assert_eq!(bytes, [0x13, 0x43, 0x1E, 0xA6, 0x0D, 0xE1, 0xBC, 0x15]);
+
+        let mut random = Random::new(RandomMode::Xorshift, 1);
+        let bytes: Vec<u8> = (0..8).map(|_| random.next_byte(&memory)).collect();
+        assert_eq!(bytes, [0x42, 0x28, 0x30, 0x28, 0x99, 0xB2, 0x75, 0xBC]);
+    }
+
+    #[test]
+    fn cosmac_vip_sequence() {
+        let mut memory = [0; 0x1000];
+        for (idx, byte) in memory[0x200..0x300].iter_mut().enumerate() {
+            *byte = (idx * 7 + 3) as u8;
+        }
+
+        // The counter's low byte carries into the high byte, which is then
+        // added to the byte of the program it indexes
+        let mut random = Random::new(RandomMode::CosmacVip, 0x12FE);
+        let bytes: Vec<u8> = (0..6).map(|_| random.next_byte(&memory)).collect();
+        assert_eq!(bytes, [0x0E, 0x12, 0x1C, 0x2D, 0x45, 0x64]);
+        assert_eq!(random.to_parts(), (RandomMode::CosmacVip, 0x6404));
+
+        // Only the low 16 bits of the seed are kept
+        assert_eq!(Random::new(RandomMode::CosmacVip, 0x1_12FE), Random::new(RandomMode::CosmacVip, 0x12FE));
+    }
+
+    #[test]
+    fn invalid_states() {
+        assert_eq!(Random::from_parts(RandomMode::Xorshift, 0), None);
+        assert_eq!(Random::from_parts(RandomMode::CosmacVip, 0x10000), None);
+        assert!(Random::from_parts(RandomMode::CosmacVip, 0).is_some());
+    }
+
+    // Runs the `Cxkk` loop below 16 times, returning the bytes drawn
+    fn draw(cpu: &mut Cpu) -> Vec<u8> {
+        (0..16).map(|_| {
+            cpu.cycle().unwrap();
+            cpu.cycle().unwrap();
+            cpu.regs()[0]
+        }).collect()
+    }
+
+    #[test]
+    fn save_states_continue_the_sequence() {
+        for mode in [RandomMode::Xorshift, RandomMode::CosmacVip] {
+            let mut cpu = Cpu::new(Platform::Chip8, Quirks::default());
+            // Keeps drawing random bytes into v0
+            cpu.load_binary(&[0xC0, 0xFF, 0x12, 0x00]).unwrap();
+            cpu.set_random(Random::new(mode, 42));
+
+            draw(&mut cpu);
+            let state = cpu.save_state();
+            let expected = draw(&mut cpu);
+
+            let mut restored = Cpu::new(Platform::Chip8, Quirks::default());
+            restored.set_random(Random::new(mode, 7));
+            restored.load_state(&state).unwrap();
+            assert_eq!(restored.random().mode(), mode);
+            assert_eq!(draw(&mut restored), expected);
+        }
+    }
+}
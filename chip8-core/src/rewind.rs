@@ -0,0 +1,248 @@
+//! A bounded history of save states for stepping backwards through gameplay.
+
+use std::collections::VecDeque;
+
+use crate::cpu::{Cpu, StateError};
+
+/// Keeps the last `capacity` snapshots of a [`Cpu`], typically one per frame.
+///
+/// Only the newest snapshot is kept whole. Older ones are stored as the
+/// difference from the snapshot after them, XORed and run-length encoded, and
+/// as little changes from frame to frame each usually takes a few dozen bytes.
+/// A snapshot whose size differs from the one after it, e.g. as keys were
+/// queued, is kept whole instead.
+pub struct Rewind {
+    capacity: usize,
+    newest: Option<Vec<u8>>,
+    // Snapshots older than the newest, oldest first
+    older: VecDeque<Snapshot>,
+}
+
+enum Snapshot {
+    // The encoded difference from the snapshot after it
+    Delta(Vec<u8>),
+    Whole(Vec<u8>),
+}
+
+impl Snapshot {
+    fn len(&self) -> usize {
+        match self {
+            Snapshot::Delta(bytes) | Snapshot::Whole(bytes) => bytes.len(),
+        }
+    }
+}
+
+impl Rewind {
+    pub fn new(capacity: usize) -> Rewind {
+        Rewind {
+            capacity,
+            newest: None,
+            older: VecDeque::new(),
+        }
+    }
+
+    /// Number of snapshots that can be stepped back through.
+    pub fn len(&self) -> usize {
+        self.older.len() + self.newest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    /// Total size of the stored snapshots in bytes.
+    pub fn size(&self) -> usize {
+        self.newest.as_ref().map_or(0, Vec::len) + self.older.iter().map(Snapshot::len).sum::<usize>()
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.older.clear();
+    }
+
+    /// Takes a snapshot of `cpu`, dropping the oldest one if the history is full.
+    pub fn push(&mut self, cpu: &Cpu) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let state = cpu.save_state();
+
+        if let Some(newest) = self.newest.take() {
+            // Only states of the same size can be diffed
+            self.older.push_back(if newest.len() == state.len() {
+                Snapshot::Delta(encode_delta(&newest, &state))
+            } else {
+                Snapshot::Whole(newest)
+            });
+            if self.older.len() >= self.capacity {
+                self.older.pop_front();
+            }
+        }
+
+        self.newest = Some(state);
+    }
+
+    /// Restores `cpu` to the newest snapshot and removes it, returning false if
+    /// there are none left.
+    pub fn pop(&mut self, cpu: &mut Cpu) -> Result<bool, StateError> {
+        let newest = match self.newest.take() {
+            Some(newest) => newest,
+            None => return Ok(false),
+        };
+
+        cpu.load_state(&newest)?;
+        self.newest = self.older.pop_back().map(|snapshot| match snapshot {
+            Snapshot::Delta(delta) => apply_delta(&newest, &delta),
+            Snapshot::Whole(state) => state,
+        });
+
+        Ok(true)
+    }
+}
+
+// Encodes the XOR of two equally sized states as runs of unchanged bytes, each
+// followed by a run of changed ones: `[unchanged length] [changed length] [changed bytes]`,
+// with lengths as LEB128 varints
+fn encode_delta(older: &[u8], newer: &[u8]) -> Vec<u8> {
+    let xor: Vec<u8> = older.iter().zip(newer).map(|(a, b)| a ^ b).collect();
+    let mut delta = Vec::new();
+
+    let mut pos = 0;
+    while pos < xor.len() {
+        let unchanged = xor[pos..].iter().take_while(|byte| **byte == 0).count();
+        pos += unchanged;
+        let changed = xor[pos..].iter().take_while(|byte| **byte != 0).count();
+
+        write_varint(&mut delta, unchanged);
+        write_varint(&mut delta, changed);
+        delta.extend_from_slice(&xor[pos..pos + changed]);
+        pos += changed;
+    }
+
+    delta
+}
+
+// Recovers the older state from the newer one and their delta
+fn apply_delta(newer: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut state = newer.to_vec();
+    let mut pos = 0;
+    let mut bytes = delta;
+
+    while !bytes.is_empty() {
+        pos += read_varint(&mut bytes);
+        let changed = read_varint(&mut bytes);
+
+        for (byte, xor) in state[pos..pos + changed].iter_mut().zip(&bytes[..changed]) {
+            *byte ^= xor;
+        }
+
+        pos += changed;
+        bytes = &bytes[changed..];
+    }
+
+    state
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+
+        out.push(byte | 0x80);
+    }
+}
+
+fn read_varint(bytes: &mut &[u8]) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+
+    while let Some((byte, rest)) = bytes.split_first() {
+        *bytes = rest;
+        value |= ((byte & 0x7F) as usize) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Platform, Quirks};
+
+    #[test]
+    fn steps_back_through_every_snapshot() {
+        let mut cpu = Cpu::new(Platform::Chip8, Quirks::default());
+        // Counts up in v0 forever
+        cpu.load_binary(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+
+        let mut rewind = Rewind::new(10);
+        let mut states = Vec::new();
+        for _ in 0..5 {
+            rewind.push(&cpu);
+            states.push(cpu.save_state());
+            cpu.cycle().unwrap();
+            cpu.cycle().unwrap();
+        }
+        assert_eq!(rewind.len(), 5);
+
+        while let Some(state) = states.pop() {
+            assert!(rewind.pop(&mut cpu).unwrap());
+            assert_eq!(cpu.save_state(), state);
+        }
+        assert!(!rewind.pop(&mut cpu).unwrap());
+        assert!(rewind.is_empty());
+    }
+
+    #[test]
+    fn keeps_the_history_when_the_state_size_changes() {
+        let mut cpu = Cpu::new(Platform::Chip8, Quirks::default());
+        // Waits for a key, so key events are queued and saved with the state
+        cpu.load_binary(&[0xF0, 0x0A]).unwrap();
+        cpu.cycle().unwrap();
+
+        let mut rewind = Rewind::new(10);
+        let mut states = Vec::new();
+        for keys in [&[][..], &[1], &[1, 2], &[2], &[2]] {
+            cpu.update_keyboard(keys);
+            rewind.push(&cpu);
+            states.push(cpu.save_state());
+        }
+        assert_eq!(rewind.len(), 5);
+        assert_ne!(states[0].len(), states[1].len());
+
+        while let Some(state) = states.pop() {
+            assert!(rewind.pop(&mut cpu).unwrap());
+            assert_eq!(cpu.save_state(), state);
+        }
+        assert!(rewind.is_empty());
+    }
+
+    #[test]
+    fn drops_the_oldest_snapshots() {
+        let mut cpu = Cpu::new(Platform::Chip8, Quirks::default());
+        cpu.load_binary(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+
+        let mut rewind = Rewind::new(3);
+        for _ in 0..10 {
+            rewind.push(&cpu);
+            cpu.cycle().unwrap();
+        }
+        assert_eq!(rewind.len(), 3);
+
+        while rewind.pop(&mut cpu).unwrap() {}
+        // The oldest snapshot left was taken before the 8th cycle
+        assert_eq!(cpu.pc(), 0x202);
+        assert_eq!(cpu.regs()[0], 4);
+    }
+}
@@ -0,0 +1,282 @@
+//! ROM images, and the checks made when loading them into memory.
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::platform::Platform;
+
+/// The address programs are normally loaded at, and start running from.
+pub const DEFAULT_LOAD_ADDRESS: u16 = 0x200;
+
+/// Memory below this address holds the interpreter's fonts, so programs can't
+/// be loaded there.
+pub const MIN_LOAD_ADDRESS: u16 = 0x200;
+
+/// A reason a ROM can't be loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RomError {
+    /// The ROM has no bytes at all.
+    Empty,
+    /// The ROM doesn't fit in memory between the load address and the end of
+    /// memory, which holds `max_len` bytes.
+    TooLarge { len: usize, max_len: usize, platform: Platform },
+    /// The load address lies in the font area or past the end of memory.
+    InvalidLoadAddress { addr: u16, platform: Platform },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::Empty => write!(f, "ROM is empty"),
+            RomError::TooLarge { len, max_len, platform } => {
+                write!(f, "ROM is {} bytes, but only {} fit in {} memory", len, max_len, platform)
+            },
+            RomError::InvalidLoadAddress { addr, platform } => write!(
+                f,
+                "can't load a ROM at {:#05x}, it must be between {:#05x} and the end of {} memory",
+                addr, MIN_LOAD_ADDRESS, platform,
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
+/// Something unusual about a ROM that still loads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RomWarning {
+    /// Instructions are two bytes long, so a ROM with an odd number of bytes
+    /// either ends with data or was truncated.
+    OddLength { len: usize },
+}
+
+impl fmt::Display for RomWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomWarning::OddLength { len } => {
+                write!(f, "ROM is an odd number of bytes ({}), it may be truncated", len)
+            },
+        }
+    }
+}
+
+/// Checks that `len` bytes can be loaded at `addr` on `platform`, returning
+/// anything unusual about them.
+pub fn validate(len: usize, addr: u16, platform: Platform) -> Result<Vec<RomWarning>, RomError> {
+    let memory_size = platform.memory_size();
+    if addr < MIN_LOAD_ADDRESS || addr as usize >= memory_size {
+        return Err(RomError::InvalidLoadAddress { addr, platform });
+    }
+    if len == 0 {
+        return Err(RomError::Empty);
+    }
+
+    let max_len = memory_size - addr as usize;
+    if len > max_len {
+        return Err(RomError::TooLarge { len, max_len, platform });
+    }
+
+    let mut warnings = Vec::new();
+    if len % 2 == 1 {
+        warnings.push(RomWarning::OddLength { len });
+    }
+
+    Ok(warnings)
+}
+
+/// A SHA-1 hash, written out as 40 hex digits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Sha1(pub [u8; 20]);
+
+impl Sha1 {
+    /// Hashes `data`.
+    pub fn of(data: &[u8]) -> Sha1 {
+        let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+        // The message is padded with a 1 bit, then zeros up to 8 bytes short of
+        // a 64 byte block, then its length in bits
+        let mut message = data.to_vec();
+        message.push(0x80);
+        while message.len() % 64 != 56 {
+            message.push(0);
+        }
+        message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_be_bytes());
+
+        for block in message.chunks(64) {
+            let mut w = [0u32; 80];
+            for (idx, word) in block.chunks(4).enumerate() {
+                w[idx] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+            }
+            for idx in 16..80 {
+                w[idx] = (w[idx - 3] ^ w[idx - 8] ^ w[idx - 14] ^ w[idx - 16]).rotate_left(1);
+            }
+
+            let [mut a, mut b, mut c, mut d, mut e] = h;
+            for (idx, word) in w.iter().enumerate() {
+                let (f, k) = match idx {
+                    0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                    20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                    40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                    _ => (b ^ c ^ d, 0xCA62C1D6),
+                };
+
+                let temp = a.rotate_left(5)
+                    .wrapping_add(f)
+                    .wrapping_add(e)
+                    .wrapping_add(k)
+                    .wrapping_add(*word);
+                e = d;
+                d = c;
+                c = b.rotate_left(30);
+                b = a;
+                a = temp;
+            }
+
+            for (h, value) in h.iter_mut().zip([a, b, c, d, e].iter()) {
+                *h = h.wrapping_add(*value);
+            }
+        }
+
+        let mut hash = [0; 20];
+        for (bytes, value) in hash.chunks_mut(4).zip(h.iter()) {
+            bytes.copy_from_slice(&value.to_be_bytes());
+        }
+
+        Sha1(hash)
+    }
+}
+
+impl fmt::Display for Sha1 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// A ROM image, along with where it came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rom {
+    bytes: Vec<u8>,
+    path: Option<PathBuf>,
+    sha1: Sha1,
+}
+
+impl Rom {
+    /// Wraps a ROM that didn't come from a file, e.g. one just assembled.
+    pub fn new(bytes: Vec<u8>) -> Rom {
+        let sha1 = Sha1::of(&bytes);
+        Rom { bytes, path: None, sha1 }
+    }
+
+    /// Reads a ROM from a file.
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Rom> {
+        let path = path.as_ref();
+        let mut rom = Rom::new(std::fs::read(path)?);
+        rom.path = Some(path.to_path_buf());
+        Ok(rom)
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The file the ROM was read from, if any.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Hash of the ROM's contents, which identifies it in ROM databases.
+    pub fn sha1(&self) -> Sha1 {
+        self.sha1
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cpu, Quirks};
+
+    fn sha1(data: &[u8]) -> String {
+        Sha1::of(data).to_string()
+    }
+
+    #[test]
+    fn sha1_known_answers() {
+        assert_eq!(sha1(b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(sha1(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        // 56 bytes, leaving no room for the length in the first block
+        assert_eq!(
+            sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1",
+        );
+        assert_eq!(sha1(&[b'a'; 1_000_000]), "34aa973cd4c4daa4f61eeb2bdbad27316534016f");
+    }
+
+    #[test]
+    fn sha1_padding_boundaries() {
+        let data: Vec<u8> = (0..65).collect();
+        let cases = [
+            (55, "8ae2d46729cfe68ff927af5eec9c7d1b66d65ac2"),
+            (56, "636e2ec698dac903498e648bd2f3af641d3c88cb"),
+            (63, "6d942da0c4392b123528f2905c713a3ce28364bd"),
+            (64, "c6138d514ffa2135bfce0ed0b8fac65669917ec7"),
+            (65, "69bd728ad6e13cd76ff19751fde427b00e395746"),
+        ];
+        for (len, expected) in cases.iter() {
+            assert_eq!(sha1(&data[..*len]), *expected, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn validate_rejects_empty_roms() {
+        assert_eq!(validate(0, DEFAULT_LOAD_ADDRESS, Platform::Chip8), Err(RomError::Empty));
+        assert!(Cpu::new(Platform::Chip8, Quirks::default()).load_binary(&[]).is_err());
+    }
+
+    #[test]
+    fn validate_limits_the_size_to_memory() {
+        assert_eq!(validate(0xE00, 0x200, Platform::Chip8), Ok(vec![]));
+        assert_eq!(
+            validate(0xE01, 0x200, Platform::Chip8),
+            Err(RomError::TooLarge { len: 0xE01, max_len: 0xE00, platform: Platform::Chip8 }),
+        );
+        assert_eq!(
+            validate(0xA01, 0x600, Platform::SuperChip),
+            Err(RomError::TooLarge { len: 0xA01, max_len: 0xA00, platform: Platform::SuperChip }),
+        );
+        assert_eq!(validate(0xFE00, 0x200, Platform::XoChip), Ok(vec![]));
+        assert_eq!(
+            validate(0xFE01, 0x200, Platform::XoChip),
+            Err(RomError::TooLarge { len: 0xFE01, max_len: 0xFE00, platform: Platform::XoChip }),
+        );
+    }
+
+    #[test]
+    fn validate_checks_the_load_address() {
+        for addr in [0x000, 0x1FF, 0x1000].iter() {
+            assert_eq!(
+                validate(2, *addr, Platform::Chip8),
+                Err(RomError::InvalidLoadAddress { addr: *addr, platform: Platform::Chip8 }),
+            );
+        }
+        assert_eq!(validate(2, 0x1000, Platform::XoChip), Ok(vec![]));
+    }
+
+    #[test]
+    fn validate_warns_about_odd_lengths() {
+        assert_eq!(validate(1, 0x200, Platform::Chip8), Ok(vec![RomWarning::OddLength { len: 1 }]));
+        assert_eq!(validate(0xDFF, 0x200, Platform::Chip8), Ok(vec![RomWarning::OddLength { len: 0xDFF }]));
+        assert_eq!(validate(2, 0x200, Platform::Chip8), Ok(vec![]));
+    }
+}
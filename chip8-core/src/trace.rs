@@ -0,0 +1,143 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+/// An executed instruction, along with the register state after executing it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    /// Address of the instruction.
+    pub pc: u16,
+    pub opcode: u16,
+    /// The instruction in assembly syntax, e.g. `LD V1, 0a`.
+    pub mnemonic: String,
+    pub regs: [u8; 16],
+    pub i: u16,
+}
+
+impl TraceEntry {
+    /// Formats the entry along with the register state, e.g.
+    /// `0x200: (6a02) LD V10, 02    V0=00 ... VF=00 I=0000`.
+    pub fn with_state(&self) -> String {
+        let regs: Vec<String> = self.regs.iter()
+            .enumerate()
+            .map(|(idx, val)| format!("V{:X}={:02x}", idx, val))
+            .collect();
+
+        format!("{:<32}{} I={:04x}", self.to_string(), regs.join(" "), self.i)
+    }
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#03x}: ({:04x}) {}", self.pc, self.opcode, self.mnemonic)
+    }
+}
+
+/// Receives each instruction executed by a [`Cpu`](crate::Cpu) it is attached to
+/// with [`Cpu::set_tracer`](crate::Cpu::set_tracer).
+pub trait Tracer {
+    fn trace(&mut self, entry: &TraceEntry);
+
+    /// Finishes writing the trace, returning the first error that occurred
+    /// while doing so.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Any closure taking a [`TraceEntry`] can be used as a tracer.
+impl<F: FnMut(&TraceEntry)> Tracer for F {
+    fn trace(&mut self, entry: &TraceEntry) {
+        self(entry)
+    }
+}
+
+/// Prints each instruction to stdout.
+pub struct StdoutTracer;
+
+impl Tracer for StdoutTracer {
+    fn trace(&mut self, entry: &TraceEntry) {
+        println!("{}", entry);
+    }
+}
+
+/// Keeps the last `capacity` instructions, e.g. to show what led up to a crash.
+///
+/// Clones share the same buffer, so one can be attached to the cpu while
+/// another is kept to read the buffer back.
+#[derive(Clone)]
+pub struct RingBufferTracer {
+    capacity: usize,
+    entries: Rc<RefCell<VecDeque<TraceEntry>>>,
+}
+
+impl RingBufferTracer {
+    pub fn new(capacity: usize) -> RingBufferTracer {
+        RingBufferTracer {
+            capacity,
+            entries: Rc::new(RefCell::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    /// The buffered instructions, oldest first.
+    pub fn entries(&self) -> Vec<TraceEntry> {
+        self.entries.borrow().iter().cloned().collect()
+    }
+
+    /// Writes the buffered instructions, oldest first, one per line.
+    pub fn dump<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        for entry in self.entries.borrow().iter() {
+            writeln!(out, "{}", entry.with_state())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Tracer for RingBufferTracer {
+    fn trace(&mut self, entry: &TraceEntry) {
+        let mut entries = self.entries.borrow_mut();
+
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+
+        if self.capacity > 0 {
+            entries.push_back(entry.clone());
+        }
+    }
+}
+
+/// Writes each instruction to a file, along with the register state.
+pub struct FileTracer {
+    file: BufWriter<File>,
+    // The first error writing the file, after which nothing more is written
+    error: Option<io::Error>,
+}
+
+impl FileTracer {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<FileTracer> {
+        Ok(FileTracer { file: BufWriter::new(File::create(path)?), error: None })
+    }
+}
+
+impl Tracer for FileTracer {
+    fn trace(&mut self, entry: &TraceEntry) {
+        if self.error.is_none() {
+            if let Err(err) = writeln!(self.file, "{}", entry.with_state()) {
+                self.error = Some(err);
+            }
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => self.file.flush(),
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use chip8_core::audio::{AudioSink, NullSink};
+
+// Plays audio through the system's default output device
+#[cfg(feature = "audio")]
+pub struct SystemSink {
+    // Audio stops playing once the stream is dropped
+    _stream: cpal::Stream,
+    queue: std::sync::Arc<std::sync::Mutex<std::collections::VecDeque<f32>>>,
+    sample_rate: u32,
+}
+
+#[cfg(feature = "audio")]
+impl SystemSink {
+    pub fn new() -> Result<SystemSink, String> {
+        use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+        use std::collections::VecDeque;
+        use std::sync::{Arc, Mutex};
+
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or("no audio output device found")?;
+        let config: cpal::StreamConfig = device.default_output_config()
+            .map_err(|err| err.to_string())?
+            .into();
+
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        let stream_queue = Arc::clone(&queue);
+        let channels = config.channels as usize;
+
+        let stream = device.build_output_stream(
+            &config,
+            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                let mut queue = stream_queue.lock().unwrap();
+
+                // Each sample is played on every channel
+                for frame in data.chunks_mut(channels) {
+                    let sample = queue.pop_front().unwrap_or(0.0);
+                    for value in frame.iter_mut() {
+                        *value = sample;
+                    }
+                }
+            },
+            |err| eprintln!("Audio stream error: {}", err),
+        ).map_err(|err| err.to_string())?;
+
+        stream.play().map_err(|err| err.to_string())?;
+
+        Ok(SystemSink { _stream: stream, queue, sample_rate: config.sample_rate.0 })
+    }
+}
+
+#[cfg(feature = "audio")]
+impl AudioSink for SystemSink {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn play(&mut self, samples: &[f32]) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.extend(samples);
+
+        // If emulation runs ahead of the audio device, drop the oldest samples
+        // rather than letting the latency build up. A few frames are kept buffered.
+        let max_len = self.sample_rate as usize / crate::REFRESH_RATE as usize * 4;
+        while queue.len() > max_len {
+            queue.pop_front();
+        }
+    }
+}
+
+// Opens the default audio sink for the build: the system's audio device if built
+// with the `audio` feature and one is available, otherwise nothing is played
+pub fn default_sink() -> Box<dyn AudioSink> {
+    #[cfg(feature = "audio")]
+    {
+        match SystemSink::new() {
+            Ok(sink) => return Box::new(sink),
+            Err(err) => eprintln!("Audio disabled: {}", err),
+        }
+    }
+
+    Box::new(NullSink)
+}
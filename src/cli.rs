@@ -0,0 +1,544 @@
+use std::str::FromStr;
+
+use chip8_core::{platform, rom, Platform, Quirks, RandomMode};
+
+use crate::headless::{self, DumpFormat, KeyScript};
+use crate::{DEFAULT_CLOCK_SPEED, PALETTE, REFRESH_RATE};
+
+pub const USAGE: &str = "\
+Usage:
+  chip8-emulator [run] <rom> [options]     run a rom in a window, or headless
+  chip8-emulator disasm <rom> [--quirks <preset>] [--load-address <addr>]
+                                           print a listing of a rom, for the
+                                           platform it seems written for unless
+                                           --quirks is given
+  chip8-emulator info <rom> [--database <dir>]
+                                           show a rom's size, hashes, platform and
+                                           what the rom database knows about it
+  chip8-emulator bench <rom> [--quirks <preset>] [--load-address <addr>]
+                     [--clock-hz <n>] [--frames <n>]
+                                           measure how fast a rom runs
+  chip8-emulator assemble <source> [-o <rom>]
+                                           build a rom from assembly source
+
+Options for run:
+  --quirks <preset>        interpreter to mimic: vip, chip48, schip, xochip or
+                           default (also accepted as the argument after the rom).
+                           Overrides the rom database, as do --clock-hz and
+                           --palette
+  --key-wait press|release whether Fx0A completes when a key is pressed, or once
+                           it is released as on the COSMAC VIP. Overrides the
+                           preset's choice
+  --load-address <addr>    hex address to load and start the rom at (200 by
+                           default, 600 for ETI-660 programs)
+  --clock-hz <n>           instructions per second (540 by default)
+  --no-database            don't look the rom up in the rom database
+  --database <dir>         look roms up in a copy of the chip-8-database instead
+                           of the bundled one
+  --scale <n>              window scale: 1, 2, 4, 8 (default), 16 or 32
+  --palette <colors>       2 or 4 comma separated hex colors for the background,
+                           first plane, second plane and both planes
+  --keymap <file>          keymap to use instead of the global one, and to save
+                           to after remapping with F2
+  --seed <n>               seed for random numbers, to repeat a run exactly
+  --random xorshift|vip    random number generator
+  --trace stdout|<file>    log every executed instruction
+  --trace crash            print the last instructions executed if the cpu faults
+  --wav <file>             record the audio to a file instead of playing it
+  --debug                  start paused, controlled by commands on stdin
+  --gdb <port>             start paused, waiting for gdb to attach
+  --record <movie>         record the keypad input to a movie
+  --play <movie>           play back a movie, or check it with --headless
+  --headless               run without a window, then print the display
+  --frames <n>             with --headless, frames to run for (60 by default)
+  --cycles <n>             with --headless, instructions to run instead
+  --keys <script>          with --headless, keys to hold, e.g. \"60:4 90:\"
+  --dump ascii|pbm|hash    with --headless, how to print the display";
+
+// What to do, as given on the command line
+pub enum Command {
+    Run(Box<RunArgs>),
+    // Without a preset, the platform is detected from the rom
+    Disasm { rom: String, preset: Option<(Platform, Quirks)>, load_address: u16 },
+    Info { rom: String, database: Database },
+    Bench { rom: String, platform: Platform, quirks: Quirks, load_address: u16, clock_hz: u32, frames: u64 },
+    Assemble { source: String, output: Option<String> },
+    Help,
+}
+
+// Where rom settings are looked up
+pub enum Database {
+    Bundled,
+    Dir(String),
+    Disabled,
+}
+
+// Options left as `None` come from the rom database, if the rom is in it, or
+// take their defaults otherwise. Some only apply to the window
+#[cfg_attr(not(feature = "window"), allow(dead_code))]
+pub struct RunArgs {
+    pub rom: String,
+    pub preset: Option<(Platform, Quirks)>,
+    // Overrides the preset's `key_wait_release` quirk
+    pub key_wait_release: Option<bool>,
+    pub load_address: u16,
+    pub clock_hz: Option<u32>,
+    pub database: Database,
+    pub scale: u32,
+    pub palette: Option<[u32; 4]>,
+    pub seed: Option<u64>,
+    pub random_mode: RandomMode,
+    pub trace: Option<String>,
+    pub wav: Option<String>,
+    pub debug: bool,
+    pub gdb_port: Option<u16>,
+    pub keymap: Option<String>,
+    pub record: Option<String>,
+    pub play: Option<String>,
+    pub headless: Option<headless::Options>,
+}
+
+// Parses the arguments following the program name, returning a message
+// explaining what is wrong with them if they can't be parsed
+pub fn parse(mut args: Vec<String>) -> Result<Command, String> {
+    if take_flag(&mut args, "--help") || take_flag(&mut args, "-h") {
+        return Ok(Command::Help);
+    }
+
+    let command = match args.first().map(String::as_str) {
+        None | Some("help") => return Ok(Command::Help),
+        Some("run") | Some("disasm") | Some("info") | Some("bench") | Some("assemble") => args.remove(0),
+        // A rom on its own is run
+        Some(_) => "run".to_string(),
+    };
+
+    let command = match command.as_str() {
+        "run" => Command::Run(Box::new(parse_run(&mut args)?)),
+        "disasm" => {
+            let load_address = parse_load_address(&mut args)?;
+            let (rom, preset) = take_rom_and_preset(&mut args)?;
+            Command::Disasm { rom, preset, load_address }
+        },
+        "info" => {
+            let database = parse_database(&mut args)?;
+            Command::Info { rom: take_rom(&mut args)?, database }
+        },
+        "bench" => {
+            let clock_hz = parse_clock_hz(&mut args)?.unwrap_or(DEFAULT_CLOCK_SPEED);
+            let frames = parse_option(&mut args, "--frames")?.unwrap_or(60 * REFRESH_RATE as u64);
+            let load_address = parse_load_address(&mut args)?;
+            let (rom, preset) = take_rom_and_preset(&mut args)?;
+            let (platform, quirks) = preset.unwrap_or((Platform::Chip8, Quirks::default()));
+            Command::Bench { rom, platform, quirks, load_address, clock_hz, frames }
+        },
+        _ => {
+            let output = take_option(&mut args, "-o")?;
+            if args.is_empty() {
+                return Err("Please provide a source file to assemble".to_string());
+            }
+            Command::Assemble { source: args.remove(0), output }
+        },
+    };
+
+    match args.first() {
+        Some(arg) if arg.starts_with('-') => Err(format!("Unknown option '{}'", arg)),
+        Some(arg) => Err(format!("Unexpected argument '{}'", arg)),
+        None => Ok(command),
+    }
+}
+
+fn parse_run(args: &mut Vec<String>) -> Result<RunArgs, String> {
+    let clock_hz = parse_clock_hz(args)?;
+    let load_address = parse_load_address(args)?;
+    let database = parse_database(args)?;
+
+    let key_wait_release = match take_option(args, "--key-wait")?.as_deref() {
+        Some("press") => Some(false),
+        Some("release") => Some(true),
+        Some(mode) => return Err(format!("Unknown key wait '{}', expected one of: press, release", mode)),
+        None => None,
+    };
+
+    let scale = match parse_option(args, "--scale")? {
+        None => 8,
+        Some(scale) if [1, 2, 4, 8, 16, 32].contains(&scale) => scale,
+        Some(scale) => return Err(format!("Invalid scale {}, expected one of: 1, 2, 4, 8, 16, 32", scale)),
+    };
+
+    let palette = match take_option(args, "--palette")? {
+        Some(colors) => Some(parse_palette(&colors)?),
+        None => None,
+    };
+
+    let random_mode = match take_option(args, "--random")?.as_deref() {
+        Some("xorshift") | None => RandomMode::Xorshift,
+        Some("vip") => RandomMode::CosmacVip,
+        Some(name) => return Err(format!("Unknown random mode '{}', expected one of: xorshift, vip", name)),
+    };
+
+    let debug = take_flag(args, "--debug");
+    let gdb_port = parse_option(args, "--gdb")?;
+    let keymap = take_option(args, "--keymap")?;
+    let record = take_option(args, "--record")?;
+    let play = take_option(args, "--play")?;
+    let headless = parse_headless(args)?;
+
+    if debug && gdb_port.is_some() {
+        return Err("--debug and --gdb can't be used together".to_string());
+    }
+    if headless.is_some() && (debug || gdb_port.is_some()) {
+        return Err("--debug and --gdb can't be used with --headless".to_string());
+    }
+    if record.is_some() && play.is_some() {
+        return Err("--record and --play can't be used together".to_string());
+    }
+    if (record.is_some() || play.is_some()) && (debug || gdb_port.is_some()) {
+        return Err("--record and --play can't be used with --debug or --gdb".to_string());
+    }
+    if record.is_some() && headless.is_some() {
+        return Err("--record can't be used with --headless".to_string());
+    }
+    if headless.is_none() && !cfg!(feature = "window") {
+        return Err("This build has no window, run the rom with --headless".to_string());
+    }
+
+    let seed = parse_option(args, "--seed")?;
+    let trace = take_option(args, "--trace")?;
+    let wav = take_option(args, "--wav")?;
+    let (rom, preset) = take_rom_and_preset(args)?;
+
+    Ok(RunArgs {
+        rom,
+        preset,
+        key_wait_release,
+        load_address,
+        clock_hz,
+        database,
+        scale,
+        palette,
+        seed,
+        random_mode,
+        trace,
+        wav,
+        debug,
+        gdb_port,
+        keymap,
+        record,
+        play,
+        headless,
+    })
+}
+
+// The options only used with `--headless`, which are an error without it. The
+// clock speed is filled in once the rom has been looked up
+fn parse_headless(args: &mut Vec<String>) -> Result<Option<headless::Options>, String> {
+    let headless = take_flag(args, "--headless");
+    let frames = parse_option(args, "--frames")?;
+    let cycles = parse_option(args, "--cycles")?;
+    let keys = take_option(args, "--keys")?;
+    let dump = take_option(args, "--dump")?;
+
+    if !headless {
+        return match (frames, cycles, &keys, &dump) {
+            (None, None, None, None) => Ok(None),
+            _ => Err("--frames, --cycles, --keys and --dump can only be used with --headless".to_string()),
+        };
+    }
+
+    Ok(Some(headless::Options {
+        duration: match (frames, cycles) {
+            (Some(_), Some(_)) => return Err("--frames and --cycles can't be used together".to_string()),
+            (_, Some(cycles)) => headless::Duration::Cycles(cycles),
+            (Some(frames), None) => headless::Duration::Frames(frames),
+            (None, None) => headless::Duration::Frames(REFRESH_RATE as u64),
+        },
+        clock_hz: DEFAULT_CLOCK_SPEED,
+        keys: match keys {
+            Some(script) => KeyScript::parse(&script).map_err(|err| format!("Invalid key script: {}", err))?,
+            None => KeyScript::empty(),
+        },
+        dump: match dump {
+            Some(format) => DumpFormat::parse(&format)
+                .ok_or("Unknown dump format, expected one of: ascii, pbm, hash")?,
+            None => DumpFormat::Ascii,
+        },
+    }))
+}
+
+// Takes the rom along with `--quirks <preset>`, or the preset given after the
+// rom as in earlier versions. Called once all the other options have been
+// taken, so their values aren't mistaken for either
+fn take_rom_and_preset(args: &mut Vec<String>) -> Result<(String, Option<(Platform, Quirks)>), String> {
+    let name = take_option(args, "--quirks")?;
+    let rom = take_rom(args)?;
+    let name = match name {
+        Some(name) => Some(name),
+        None if args.first().is_some_and(|arg| !arg.starts_with('-')) => Some(args.remove(0)),
+        None => None,
+    };
+
+    let preset = match name {
+        Some(name) => Some(platform::preset(&name).ok_or(format!(
+            "Unknown interpreter '{}', expected one of: vip, chip48, schip, xochip, default",
+            name,
+        ))?),
+        None => None,
+    };
+
+    Ok((rom, preset))
+}
+
+fn parse_clock_hz(args: &mut Vec<String>) -> Result<Option<u32>, String> {
+    match parse_option(args, "--clock-hz")? {
+        Some(0) => Err("Invalid clock speed, expected a number of instructions per second".to_string()),
+        clock_hz => Ok(clock_hz),
+    }
+}
+
+fn parse_database(args: &mut Vec<String>) -> Result<Database, String> {
+    let disabled = take_flag(args, "--no-database");
+    match take_option(args, "--database")? {
+        Some(_) if disabled => Err("--database and --no-database can't be used together".to_string()),
+        Some(dir) => Ok(Database::Dir(dir)),
+        None if disabled => Ok(Database::Disabled),
+        None => Ok(Database::Bundled),
+    }
+}
+
+// Parses `600` or `0x600`. Whether the rom fits there is checked once it is read
+fn parse_load_address(args: &mut Vec<String>) -> Result<u16, String> {
+    match take_option(args, "--load-address")? {
+        Some(addr) => {
+            let digits = addr.trim_start_matches("0x");
+            u16::from_str_radix(digits, 16)
+                .map_err(|_| format!("Invalid load address '{}', expected a hex address like 600", addr))
+        },
+        None => Ok(rom::DEFAULT_LOAD_ADDRESS),
+    }
+}
+
+// Parses `000000,ecf0f1` or `000000,ecf0f1,e74c3c,f1c40f`. With two colors the
+// other planes keep their default colors
+fn parse_palette(colors: &str) -> Result<[u32; 4], String> {
+    let colors = colors.split(',')
+        .map(|color| {
+            let digits = color.trim().trim_start_matches('#');
+            match u32::from_str_radix(digits, 16) {
+                Ok(color) if digits.len() == 6 => Ok(color),
+                _ => Err(format!("Invalid color '{}', expected 6 hex digits like ecf0f1", color)),
+            }
+        })
+        .collect::<Result<Vec<u32>, String>>()?;
+
+    let mut palette = PALETTE;
+    match colors.len() {
+        2 | 4 => palette[..colors.len()].copy_from_slice(&colors),
+        len => return Err(format!("Expected 2 or 4 palette colors, got {}", len)),
+    }
+
+    Ok(palette)
+}
+
+// The rom is the first argument left over once the options have been taken
+fn take_rom(args: &mut Vec<String>) -> Result<String, String> {
+    match args.iter().position(|arg| !arg.starts_with('-')) {
+        Some(idx) => Ok(args.remove(idx)),
+        None => Err("Please provide a rom file".to_string()),
+    }
+}
+
+// Removes `--name` from the arguments, returning whether it was present
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    match args.iter().position(|arg| arg == name) {
+        Some(idx) => {
+            args.remove(idx);
+            true
+        },
+        None => false,
+    }
+}
+
+// Removes `--name <value>` from the arguments, returning the value
+fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>, String> {
+    match args.iter().position(|arg| arg == name) {
+        Some(idx) if idx + 1 < args.len() => Ok(args.drain(idx..idx + 2).nth(1)),
+        Some(_) => Err(format!("{} needs a value", name)),
+        None => Ok(None),
+    }
+}
+
+// Like `take_option`, parsing the value
+fn parse_option<T: FromStr>(args: &mut Vec<String>, name: &str) -> Result<Option<T>, String> {
+    match take_option(args, name)? {
+        Some(value) => value.parse()
+            .map(Some)
+            .map_err(|_| format!("Invalid value '{}' for {}", value, name)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(args: &str) -> Result<Command, String> {
+        parse(args.split_whitespace().map(String::from).collect())
+    }
+
+    fn run(args: &str) -> RunArgs {
+        match parse_str(args) {
+            Ok(Command::Run(args)) => *args,
+            Ok(_) => panic!("'{}' isn't a run", args),
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    fn error(args: &str) -> String {
+        parse_str(args).err().unwrap_or_else(|| panic!("'{}' should fail to parse", args))
+    }
+
+    #[test]
+    fn subcommands() {
+        assert!(matches!(parse_str(""), Ok(Command::Help)));
+        assert!(matches!(parse_str("help"), Ok(Command::Help)));
+        assert!(matches!(parse_str("game.ch8 --help"), Ok(Command::Help)));
+        assert!(matches!(parse_str("-h"), Ok(Command::Help)));
+
+        assert_eq!(run("run game.ch8 --headless").rom, "game.ch8");
+        assert!(matches!(
+            parse_str("disasm game.ch8"),
+            Ok(Command::Disasm { rom, preset: None, load_address: 0x200 }) if rom == "game.ch8"
+        ));
+        assert!(matches!(
+            parse_str("info game.ch8 --no-database"),
+            Ok(Command::Info { rom, database: Database::Disabled }) if rom == "game.ch8"
+        ));
+        assert!(matches!(
+            parse_str("bench game.ch8 --clock-hz 1000 --frames 10"),
+            Ok(Command::Bench { clock_hz: 1000, frames: 10, platform: Platform::Chip8, .. })
+        ));
+        assert!(matches!(
+            parse_str("assemble game.8o -o game.ch8"),
+            Ok(Command::Assemble { source, output: Some(output) }) if source == "game.8o" && output == "game.ch8"
+        ));
+
+        // A rom on its own is run
+        assert_eq!(run("game.ch8 --headless").rom, "game.ch8");
+    }
+
+    #[test]
+    fn rom_and_preset() {
+        let schip = platform::preset("schip").unwrap();
+
+        let args = run("game.ch8 schip --headless");
+        assert_eq!(args.rom, "game.ch8");
+        assert_eq!(args.preset, Some(schip));
+
+        let args = run("--headless --quirks schip game.ch8");
+        assert_eq!(args.rom, "game.ch8");
+        assert_eq!(args.preset, Some(schip));
+
+        // Option values aren't mistaken for the rom or the preset
+        let args = run("--clock-hz 600 --seed 5 game.ch8 --headless");
+        assert_eq!(args.rom, "game.ch8");
+        assert_eq!(args.preset, None);
+        assert_eq!(args.clock_hz, Some(600));
+        assert_eq!(args.seed, Some(5));
+
+        assert!(matches!(
+            parse_str("disasm game.ch8 xochip"),
+            Ok(Command::Disasm { preset: Some((Platform::XoChip, _)), .. })
+        ));
+
+        assert_eq!(
+            error("game.ch8 gameboy --headless"),
+            "Unknown interpreter 'gameboy', expected one of: vip, chip48, schip, xochip, default"
+        );
+        assert_eq!(error("game.ch8 schip extra --headless"), "Unexpected argument 'extra'");
+        assert_eq!(error("--headless"), "Please provide a rom file");
+        assert_eq!(error("game.ch8 --headless --fast"), "Unknown option '--fast'");
+        assert_eq!(error("game.ch8 --headless --seed"), "--seed needs a value");
+    }
+
+    #[test]
+    fn conflicting_options() {
+        assert_eq!(error("game.ch8 --debug --gdb 1234"), "--debug and --gdb can't be used together");
+        assert_eq!(error("game.ch8 --headless --debug"), "--debug and --gdb can't be used with --headless");
+        assert_eq!(error("game.ch8 --headless --gdb 1234"), "--debug and --gdb can't be used with --headless");
+        assert_eq!(error("game.ch8 --headless --record a.c8m"), "--record can't be used with --headless");
+        assert_eq!(error("game.ch8 --record a.c8m --play b.c8m"), "--record and --play can't be used together");
+        assert_eq!(error("game.ch8 --play a.c8m --debug"), "--record and --play can't be used with --debug or --gdb");
+        assert_eq!(error("game.ch8 --headless --frames 5 --cycles 5"), "--frames and --cycles can't be used together");
+        assert_eq!(
+            error("game.ch8 --dump hash"),
+            "--frames, --cycles, --keys and --dump can only be used with --headless"
+        );
+        assert_eq!(
+            error("game.ch8 --no-database --database db --headless"),
+            "--database and --no-database can't be used together"
+        );
+
+        // Playing a movie back headless checks it
+        assert_eq!(run("game.ch8 --headless --play a.c8m").play.as_deref(), Some("a.c8m"));
+    }
+
+    #[test]
+    fn load_addresses() {
+        assert_eq!(run("game.ch8 --headless").load_address, 0x200);
+        assert_eq!(run("game.ch8 --headless --load-address 600").load_address, 0x600);
+        assert_eq!(run("game.ch8 --headless --load-address 0x600").load_address, 0x600);
+
+        for addr in ["xyz", "10000", "-1", ""] {
+            let args = format!("game.ch8 --headless --load-address {}", addr);
+            let expected = match addr {
+                "" => "--load-address needs a value".to_string(),
+                _ => format!("Invalid load address '{}', expected a hex address like 600", addr),
+            };
+            assert_eq!(error(&args), expected);
+        }
+    }
+
+    #[test]
+    fn clock_speeds() {
+        assert_eq!(run("game.ch8 --headless").clock_hz, None);
+        assert_eq!(run("game.ch8 --headless --clock-hz 1000").clock_hz, Some(1000));
+        assert!(matches!(parse_str("bench game.ch8"), Ok(Command::Bench { clock_hz: DEFAULT_CLOCK_SPEED, .. })));
+
+        assert_eq!(
+            error("game.ch8 --headless --clock-hz 0"),
+            "Invalid clock speed, expected a number of instructions per second"
+        );
+        assert_eq!(error("game.ch8 --headless --clock-hz fast"), "Invalid value 'fast' for --clock-hz");
+        assert_eq!(error("game.ch8 --headless --clock-hz -5"), "Invalid value '-5' for --clock-hz");
+        assert_eq!(error("game.ch8 --headless --clock-hz 4294967296"), "Invalid value '4294967296' for --clock-hz");
+    }
+
+    #[test]
+    fn headless_options() {
+        let options = run("game.ch8 --headless").headless.unwrap();
+        assert_eq!(options.duration, headless::Duration::Frames(60));
+        assert_eq!(options.dump, DumpFormat::Ascii);
+
+        let options = run("game.ch8 --headless --cycles 100 --dump hash").headless.unwrap();
+        assert_eq!(options.duration, headless::Duration::Cycles(100));
+        assert_eq!(options.dump, DumpFormat::Hash);
+
+        assert_eq!(error("game.ch8 --headless --dump png"), "Unknown dump format, expected one of: ascii, pbm, hash");
+        assert_eq!(error("game.ch8 --headless --keys 60"), "Invalid key script: expected FRAME:KEYS, got '60'");
+    }
+
+    #[test]
+    #[cfg(not(feature = "window"))]
+    fn builds_without_a_window_only_run_headless() {
+        assert_eq!(error("game.ch8"), "This build has no window, run the rom with --headless");
+        assert_eq!(run("game.ch8 --headless").rom, "game.ch8");
+    }
+
+    #[test]
+    fn palettes() {
+        assert_eq!(parse_palette("000000,ffffff"), Ok([0x000000, 0xFFFFFF, PALETTE[2], PALETTE[3]]));
+        assert_eq!(parse_palette("#000000, #ffffff,111111,222222"), Ok([0x000000, 0xFFFFFF, 0x111111, 0x222222]));
+        assert_eq!(parse_palette("000000"), Err("Expected 2 or 4 palette colors, got 1".to_string()));
+        assert_eq!(parse_palette("000,fff"), Err("Invalid color '000', expected 6 hex digits like ecf0f1".to_string()));
+    }
+}
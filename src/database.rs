@@ -0,0 +1,413 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use chip8_core::rom::Sha1;
+use chip8_core::{LoadStoreIncrement, Platform, Quirks};
+
+use crate::PALETTE;
+
+// The ROMs in the `roms` folder, in the format of the community CHIP-8
+// database (https://github.com/chip-8/chip-8-database), which `--database`
+// loads a full copy of instead
+const PROGRAMS: &str = include_str!("../database/programs.json");
+const HASHES: &str = include_str!("../database/sha1-hashes.json");
+const PLATFORMS: &str = include_str!("../database/platforms.json");
+
+// A button on a gamepad-like layout, which the database maps to keypad keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    Up,
+    Down,
+    Left,
+    Right,
+    A,
+    B,
+}
+
+// What the database knows about a rom, with the platform already resolved to
+// one this emulator supports. The buttons and palette only apply to the window
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "window"), allow(dead_code))]
+pub struct RomInfo {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub release: Option<String>,
+    pub platform: Platform,
+    pub quirks: Quirks,
+    // Instructions per frame
+    pub tickrate: Option<u32>,
+    pub buttons: Vec<(Button, u8)>,
+    pub palette: Option<[u32; 4]>,
+}
+
+pub struct Database {
+    programs: Vec<Program>,
+    // Index into `programs` for each rom's hash
+    hashes: HashMap<String, usize>,
+    platforms: Vec<PlatformEntry>,
+}
+
+#[derive(Deserialize)]
+struct Program {
+    title: String,
+    #[serde(default)]
+    authors: Vec<String>,
+    release: Option<String>,
+    roms: HashMap<String, RomEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RomEntry {
+    platforms: Vec<String>,
+    tickrate: Option<u32>,
+    #[serde(default)]
+    keys: HashMap<String, u8>,
+    colors: Option<Colors>,
+    // Quirks that differ from those of the platform, by platform
+    #[serde(default)]
+    quirky_platforms: HashMap<String, QuirkFlags>,
+}
+
+#[derive(Deserialize)]
+struct Colors {
+    #[serde(default)]
+    pixels: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct PlatformEntry {
+    id: String,
+    quirks: QuirkFlags,
+}
+
+// Quirks as the database names them. A rom's overrides only list some of them
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+struct QuirkFlags {
+    shift: Option<bool>,
+    memory_increment_by_x: Option<bool>,
+    memory_leave_i_unchanged: Option<bool>,
+    wrap: Option<bool>,
+    jump: Option<bool>,
+    vblank: Option<bool>,
+    logic: Option<bool>,
+}
+
+impl QuirkFlags {
+    // Applies the flags which are set on top of `quirks`
+    fn apply(&self, quirks: &mut Quirks) {
+        if let Some(shift) = self.shift {
+            quirks.shift = shift;
+        }
+        if self.memory_leave_i_unchanged == Some(true) {
+            quirks.load_store_increment = LoadStoreIncrement::Unchanged;
+        } else if self.memory_increment_by_x == Some(true) {
+            quirks.load_store_increment = LoadStoreIncrement::ByX;
+        } else if self.memory_leave_i_unchanged.is_some() || self.memory_increment_by_x.is_some() {
+            quirks.load_store_increment = LoadStoreIncrement::ByXPlusOne;
+        }
+        if let Some(wrap) = self.wrap {
+            quirks.clipping = !wrap;
+        }
+        if let Some(jump) = self.jump {
+            quirks.jump_with_vx = jump;
+        }
+        if let Some(vblank) = self.vblank {
+            quirks.display_wait = vblank;
+        }
+        if let Some(logic) = self.logic {
+            quirks.vf_reset = logic;
+        }
+    }
+}
+
+impl Database {
+    // The database built into the emulator
+    pub fn bundled() -> Database {
+        Database::parse(PROGRAMS, HASHES, PLATFORMS).expect("bundled rom database is invalid")
+    }
+
+    // Loads `programs.json`, `sha1-hashes.json` and `platforms.json` from a
+    // copy of the community database
+    pub fn load(dir: &Path) -> Result<Database, String> {
+        let read = |name: &str| {
+            let path = dir.join(name);
+            std::fs::read_to_string(&path).map_err(|err| format!("Couldn't read {}: {}", path.display(), err))
+        };
+
+        Database::parse(&read("programs.json")?, &read("sha1-hashes.json")?, &read("platforms.json")?)
+    }
+
+    fn parse(programs: &str, hashes: &str, platforms: &str) -> Result<Database, String> {
+        let parse_error = |name: &str, err: serde_json::Error| format!("Invalid {}: {}", name, err);
+
+        Ok(Database {
+            programs: serde_json::from_str(programs).map_err(|err| parse_error("programs.json", err))?,
+            hashes: serde_json::from_str(hashes).map_err(|err| parse_error("sha1-hashes.json", err))?,
+            platforms: serde_json::from_str(platforms).map_err(|err| parse_error("platforms.json", err))?,
+        })
+    }
+
+    // Looks up a rom by its hash. Roms only listed for platforms this emulator
+    // doesn't support, like MEGA-CHIP, aren't found
+    pub fn lookup(&self, sha1: Sha1) -> Option<RomInfo> {
+        let sha1 = sha1.to_string();
+        let program = self.programs.get(*self.hashes.get(&sha1)?)?;
+        let rom = program.roms.get(&sha1)?;
+
+        let (id, platform) = rom.platforms.iter()
+            .find_map(|id| platform_for_id(id).map(|platform| (id, platform)))?;
+
+        let mut quirks = Quirks::default();
+        if let Some(entry) = self.platforms.iter().find(|entry| &entry.id == id) {
+            entry.quirks.apply(&mut quirks);
+        }
+        if let Some(overrides) = rom.quirky_platforms.get(id) {
+            overrides.apply(&mut quirks);
+        }
+
+        let mut buttons: Vec<(Button, u8)> = rom.keys.iter()
+            .filter_map(|(name, key)| Some((button_for_name(name)?, *key)))
+            .filter(|(_, key)| *key < 16)
+            .collect();
+        buttons.sort_by_key(|(button, _)| *button as u8);
+
+        // Colors past the fourth are for XO-CHIP variants with more planes
+        let palette = rom.colors.as_ref().and_then(|colors| {
+            let colors = colors.pixels.iter()
+                .map(|color| parse_color(color))
+                .collect::<Option<Vec<u32>>>()?;
+            let mut palette = PALETTE;
+            match colors.len() {
+                0 | 1 => return None,
+                len => palette[..len.min(4)].copy_from_slice(&colors[..len.min(4)]),
+            }
+            Some(palette)
+        });
+
+        Some(RomInfo {
+            title: program.title.clone(),
+            authors: program.authors.clone(),
+            release: program.release.clone(),
+            platform,
+            quirks,
+            tickrate: rom.tickrate.filter(|tickrate| *tickrate > 0),
+            buttons,
+            palette,
+        })
+    }
+}
+
+fn platform_for_id(id: &str) -> Option<Platform> {
+    match id {
+        "originalChip8" | "hybridVIP" | "modernChip8" | "chip48" => Some(Platform::Chip8),
+        "superchip1" | "superchip" => Some(Platform::SuperChip),
+        "xochip" => Some(Platform::XoChip),
+        _ => None,
+    }
+}
+
+fn button_for_name(name: &str) -> Option<Button> {
+    match name {
+        "up" => Some(Button::Up),
+        "down" => Some(Button::Down),
+        "left" => Some(Button::Left),
+        "right" => Some(Button::Right),
+        "a" => Some(Button::A),
+        "b" => Some(Button::B),
+        _ => None,
+    }
+}
+
+// e.g. `#ecf0f1`
+fn parse_color(color: &str) -> Option<u32> {
+    let digits = color.strip_prefix('#')?;
+    match u32::from_str_radix(digits, 16) {
+        Ok(color) if digits.len() == 6 => Some(color),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chip8_core::rom::Rom;
+
+    // Roms in one program, each hashed as its letter repeated 40 times
+    const PROGRAMS: &str = r##"[{
+        "title": "Test",
+        "authors": ["Someone"],
+        "roms": {
+            "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa": {"platforms": ["megachip8"]},
+            "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb": {"platforms": ["megachip8", "xochip"], "tickrate": 0},
+            "cccccccccccccccccccccccccccccccccccccccc": {
+                "platforms": ["chip48"],
+                "tickrate": 30,
+                "keys": {"up": 2, "a": 16, "b": 5, "left": 255, "select": 3},
+                "quirkyPlatforms": {
+                    "chip48": {"memoryLeaveIUnchanged": true, "wrap": true, "vblank": true},
+                    "superchip": {"shift": false}
+                }
+            },
+            "dddddddddddddddddddddddddddddddddddddddd": {
+                "platforms": ["superchip"],
+                "colors": {"pixels": ["#000000", "#ff0000", "#00ff00"]}
+            },
+            "eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee": {
+                "platforms": ["originalChip8"],
+                "colors": {"pixels": ["#000000", "#ffffff", "#111111", "#222222", "#333333"]}
+            },
+            "ffffffffffffffffffffffffffffffffffffffff": {
+                "platforms": ["originalChip8"],
+                "colors": {"pixels": ["#000000"]}
+            },
+            "0000000000000000000000000000000000000000": {
+                "platforms": ["originalChip8"],
+                "colors": {"pixels": ["#000000", "red"]}
+            }
+        }
+    }]"##;
+
+    fn database() -> Database {
+        let hashes = "abcdef0".chars()
+            .map(|c| format!("\"{}\": 0", c.to_string().repeat(40)))
+            .collect::<Vec<_>>()
+            .join(",");
+        Database::parse(PROGRAMS, &format!("{{{}}}", hashes), super::PLATFORMS).unwrap()
+    }
+
+    fn hash(c: char) -> Sha1 {
+        let byte = u8::from_str_radix(&c.to_string().repeat(2), 16).unwrap();
+        Sha1([byte; 20])
+    }
+
+    fn flags(increment_by_x: Option<bool>, leave_i_unchanged: Option<bool>) -> QuirkFlags {
+        QuirkFlags {
+            memory_increment_by_x: increment_by_x,
+            memory_leave_i_unchanged: leave_i_unchanged,
+            ..QuirkFlags::default()
+        }
+    }
+
+    #[test]
+    fn load_store_increment() {
+        use LoadStoreIncrement::*;
+
+        let applied = |flags: QuirkFlags, start: LoadStoreIncrement| {
+            let mut quirks = Quirks { load_store_increment: start, ..Quirks::default() };
+            flags.apply(&mut quirks);
+            quirks.load_store_increment
+        };
+
+        assert_eq!(applied(flags(Some(false), Some(false)), Unchanged), ByXPlusOne);
+        assert_eq!(applied(flags(Some(true), Some(false)), Unchanged), ByX);
+        assert_eq!(applied(flags(Some(false), Some(true)), ByX), Unchanged);
+        // Leaving I unchanged wins over incrementing it
+        assert_eq!(applied(flags(Some(true), Some(true)), ByXPlusOne), Unchanged);
+        // Either flag being false on its own still means the VIP's behaviour
+        assert_eq!(applied(flags(Some(false), None), ByX), ByXPlusOne);
+        assert_eq!(applied(flags(None, Some(false)), Unchanged), ByXPlusOne);
+        assert_eq!(applied(flags(None, None), ByX), ByX);
+    }
+
+    #[test]
+    fn wrap_is_the_opposite_of_clipping() {
+        let mut quirks = Quirks { clipping: true, ..Quirks::default() };
+        QuirkFlags { wrap: Some(true), ..QuirkFlags::default() }.apply(&mut quirks);
+        assert!(!quirks.clipping);
+        QuirkFlags { wrap: Some(false), ..QuirkFlags::default() }.apply(&mut quirks);
+        assert!(quirks.clipping);
+        QuirkFlags::default().apply(&mut quirks);
+        assert!(quirks.clipping);
+    }
+
+    #[test]
+    fn bundled_roms() {
+        let database = Database::bundled();
+
+        let ibm = Rom::read("roms/ibm_logo.ch8").unwrap();
+        let info = database.lookup(ibm.sha1()).unwrap();
+        assert_eq!(info.title, "IBM Logo");
+        assert_eq!(info.platform, Platform::Chip8);
+        assert_eq!(info.quirks.load_store_increment, LoadStoreIncrement::ByXPlusOne);
+        assert!(info.quirks.clipping && info.quirks.display_wait && info.quirks.vf_reset);
+        assert!(!info.quirks.shift && !info.quirks.jump_with_vx);
+
+        let tetris = Rom::read("roms/tetris.ch8").unwrap();
+        let info = database.lookup(tetris.sha1()).unwrap();
+        assert_eq!(info.platform, Platform::Chip8);
+        assert_eq!(info.quirks.load_store_increment, LoadStoreIncrement::ByX);
+        assert!(info.quirks.shift && info.quirks.jump_with_vx && info.quirks.clipping);
+        assert_eq!(info.buttons, [(Button::Down, 7), (Button::Left, 5), (Button::Right, 6), (Button::A, 4)]);
+        assert_eq!(info.palette, None);
+
+        assert!(database.lookup(Sha1::of(b"not a rom")).is_none());
+    }
+
+    #[test]
+    fn platforms() {
+        let database = database();
+
+        // Only listed for platforms this emulator doesn't support
+        assert!(database.lookup(hash('a')).is_none());
+
+        let info = database.lookup(hash('b')).unwrap();
+        assert_eq!(info.platform, Platform::XoChip);
+        assert!(!info.quirks.clipping);
+        assert_eq!(info.tickrate, None);
+
+        let info = database.lookup(hash('d')).unwrap();
+        assert_eq!(info.platform, Platform::SuperChip);
+        assert_eq!(info.quirks.load_store_increment, LoadStoreIncrement::Unchanged);
+    }
+
+    #[test]
+    fn rom_quirks_override_the_platform() {
+        let info = database().lookup(hash('c')).unwrap();
+        assert_eq!(info.title, "Test");
+        assert_eq!(info.authors, ["Someone"]);
+        assert_eq!(info.platform, Platform::Chip8);
+        assert_eq!(info.tickrate, Some(30));
+
+        // chip48's quirks, with the rom's overrides for chip48 but not those
+        // for other platforms
+        assert_eq!(info.quirks.load_store_increment, LoadStoreIncrement::Unchanged);
+        assert!(!info.quirks.clipping);
+        assert!(info.quirks.display_wait);
+        assert!(info.quirks.shift && info.quirks.jump_with_vx);
+    }
+
+    #[test]
+    fn keys_off_the_keypad_are_dropped() {
+        let info = database().lookup(hash('c')).unwrap();
+        assert_eq!(info.buttons, [(Button::Up, 2), (Button::B, 5)]);
+    }
+
+    #[test]
+    fn palettes() {
+        let database = database();
+
+        // Three colors leave the fourth at its default
+        let info = database.lookup(hash('d')).unwrap();
+        assert_eq!(info.palette, Some([0x000000, 0xFF0000, 0x00FF00, PALETTE[3]]));
+
+        // Colors past the fourth are ignored
+        let info = database.lookup(hash('e')).unwrap();
+        assert_eq!(info.palette, Some([0x000000, 0xFFFFFF, 0x111111, 0x222222]));
+
+        assert_eq!(database.lookup(hash('f')).unwrap().palette, None);
+        assert_eq!(database.lookup(hash('0')).unwrap().palette, None);
+        assert_eq!(database.lookup(hash('c')).unwrap().palette, None);
+    }
+
+    #[test]
+    fn colors() {
+        assert_eq!(parse_color("#ecf0f1"), Some(0xECF0F1));
+        assert_eq!(parse_color("ecf0f1"), None);
+        assert_eq!(parse_color("#fff"), None);
+        assert_eq!(parse_color("#gggggg"), None);
+    }
+}
@@ -0,0 +1,471 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use chip8_core::{AccessKind, Cpu, CpuError, CycleOutcome};
+
+mod condition;
+
+use condition::Condition;
+
+const HELP: &str = "\
+Commands:
+  p, pause          pause execution
+  c, continue       resume execution
+  s, step [n]       execute n instructions (1 by default)
+  n, next           step, running called subroutines to completion
+  b, break <addr> [if <condition>]
+                    set a breakpoint, only stopping when the condition holds,
+                    or list them without an address, e.g. 'b 2a4 if V3 == 10 && I > 300'
+  d, delete <addr>  remove a breakpoint
+  watch <addr>      stop when an instruction writes to the address, or list
+                    watchpoints without an address
+  rwatch <addr>     stop when an instruction reads from the address
+  awatch <addr>     stop when an instruction reads or writes the address
+  unwatch <addr>    remove a watchpoint
+  r, regs           show the registers, stack, timers and keypad
+  h, help           show this message";
+
+// Controls execution of the cpu from outside, e.g. pausing it at breakpoints.
+// The main loop calls these around every cycle.
+pub trait Control {
+    // Handles any commands received since the last call
+    fn poll(&mut self, cpu: &mut Cpu);
+
+    // Called before each cycle, returning whether the cpu should execute it
+    fn can_cycle(&mut self, cpu: &Cpu) -> bool;
+
+    // Called after each cycle with its outcome
+    fn cycled(&mut self, cpu: &Cpu, outcome: &Result<CycleOutcome, CpuError>);
+
+    // Whether the program is paused, in which case the timers are stopped too
+    fn paused(&self) -> bool;
+
+    // Whether the program has been stopped for good, closing the emulator
+    fn killed(&self) -> bool {
+        false
+    }
+}
+
+// Controls execution from commands typed on stdin, which are read on a separate
+// thread so the window keeps updating while waiting for input
+pub struct Debugger {
+    commands: Receiver<String>,
+    paused: bool,
+    // Instructions left to execute before pausing again
+    steps: u32,
+    breakpoints: BTreeMap<u16, Option<Condition>>,
+    watchpoints: BTreeMap<u16, Watch>,
+    // Return address and stack depth of a call being stepped over
+    step_over: Option<(u16, u8)>,
+    // A breakpoint isn't hit again when resuming from it
+    resume_from: Option<u16>,
+}
+
+impl Debugger {
+    // Starts reading commands, with the cpu paused before its first instruction
+    pub fn new(cpu: &mut Cpu) -> Debugger {
+        let (sender, commands) = mpsc::channel();
+
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        println!("Debugger started, paused before the first instruction. Type 'help' for a list of commands.");
+        prompt();
+
+        Debugger::with_commands(cpu, commands)
+    }
+
+    // Runs the commands sent on `commands` instead of those typed on stdin
+    fn with_commands(cpu: &mut Cpu, commands: Receiver<String>) -> Debugger {
+        cpu.record_memory_accesses(true);
+
+        Debugger {
+            commands,
+            paused: true,
+            steps: 0,
+            breakpoints: BTreeMap::new(),
+            watchpoints: BTreeMap::new(),
+            step_over: None,
+            resume_from: None,
+        }
+    }
+
+    fn run_command(&mut self, line: &str, cpu: &Cpu) {
+        let (command, args) = split_word(line);
+        let (arg, rest) = match split_word(args) {
+            ("", _) => (None, None),
+            (arg, "") => (Some(arg), None),
+            (arg, rest) => (Some(arg), Some(rest)),
+        };
+
+        if command.is_empty() {
+            return;
+        }
+
+        match command {
+            "p" | "pause" => self.pause(cpu),
+            "c" | "continue" => {
+                self.paused = false;
+                self.step_over = None;
+                self.resume_from = Some(cpu.pc());
+            },
+            "s" | "step" => match arg.map(|n| n.parse()).unwrap_or(Ok(1)) {
+                Ok(steps) => {
+                    self.paused = true;
+                    self.steps = steps;
+                    self.resume_from = Some(cpu.pc());
+                },
+                Err(_) => println!("Invalid step count '{}'", arg.unwrap_or_default()),
+            },
+            "n" | "next" => {
+                let pc = cpu.pc();
+                match cpu.instruction_at(pc) {
+                    Some(instruction) if instruction.opcode & 0xF000 == 0x2000 => {
+                        self.paused = false;
+                        self.step_over = Some((pc.wrapping_add(instruction.len), cpu.sp()));
+                    },
+                    _ => {
+                        self.paused = true;
+                        self.steps = 1;
+                    },
+                }
+                self.resume_from = Some(pc);
+            },
+            "b" | "break" => match (arg.map(parse_addr), rest) {
+                (Some(Some(addr)), None) => {
+                    self.breakpoints.insert(addr, None);
+                },
+                (Some(Some(addr)), Some(rest)) => {
+                    let condition = match rest.strip_prefix("if ") {
+                        Some(condition) => Condition::parse(condition),
+                        None => Err("expected 'if' before the condition".to_string()),
+                    };
+
+                    match condition {
+                        Ok(condition) => {
+                            self.breakpoints.insert(addr, Some(condition));
+                        },
+                        Err(err) => println!("Invalid condition: {}", err),
+                    }
+                },
+                (Some(None), _) => println!("Invalid address '{}'", arg.unwrap_or_default()),
+                (None, _) => {
+                    println!("Breakpoints:");
+                    for (addr, condition) in &self.breakpoints {
+                        match condition {
+                            Some(condition) => println!("  {:04x} if {}", addr, condition),
+                            None => println!("  {:04x}", addr),
+                        }
+                    }
+                },
+            },
+            "d" | "delete" => match arg.and_then(parse_addr) {
+                Some(addr) => {
+                    self.breakpoints.remove(&addr);
+                },
+                None => println!("Expected an address, e.g. 'delete 2a4'"),
+            },
+            "watch" | "rwatch" | "awatch" => match arg.map(parse_addr) {
+                Some(Some(addr)) => {
+                    let watch = match command {
+                        "watch" => Watch::Write,
+                        "rwatch" => Watch::Read,
+                        _ => Watch::Access,
+                    };
+                    self.watchpoints.insert(addr, watch);
+                },
+                Some(None) => println!("Invalid address '{}'", arg.unwrap_or_default()),
+                None => {
+                    println!("Watchpoints:");
+                    for (addr, watch) in &self.watchpoints {
+                        println!("  {:04x} ({:?})", addr, watch);
+                    }
+                },
+            },
+            "unwatch" => match arg.and_then(parse_addr) {
+                Some(addr) => {
+                    self.watchpoints.remove(&addr);
+                },
+                None => println!("Expected an address, e.g. 'unwatch 300'"),
+            },
+            "r" | "regs" => print_state(cpu),
+            "h" | "help" => println!("{}", HELP),
+            _ => println!("Unknown command '{}', type 'help' for a list of commands", command),
+        }
+    }
+
+    fn pause(&mut self, cpu: &Cpu) {
+        self.paused = true;
+        self.steps = 0;
+        self.step_over = None;
+
+        print_state(cpu);
+    }
+}
+
+impl Control for Debugger {
+    fn poll(&mut self, cpu: &mut Cpu) {
+        while let Ok(line) = self.commands.try_recv() {
+            self.run_command(line.trim(), cpu);
+            prompt();
+        }
+    }
+
+    fn can_cycle(&mut self, cpu: &Cpu) -> bool {
+        if self.paused() {
+            return false;
+        }
+
+        let pc = cpu.pc();
+        let resuming = self.resume_from.take() == Some(pc);
+
+        let hit = match self.breakpoints.get(&pc) {
+            Some(Some(condition)) => condition.holds(cpu),
+            Some(None) => true,
+            None => false,
+        };
+
+        if hit && !resuming {
+            println!("\nBreakpoint at {:04x}", pc);
+            self.pause(cpu);
+            prompt();
+            return false;
+        }
+
+        if self.step_over == Some((pc, cpu.sp())) {
+            println!();
+            self.pause(cpu);
+            prompt();
+            return false;
+        }
+
+        true
+    }
+
+    fn cycled(&mut self, cpu: &Cpu, outcome: &Result<CycleOutcome, CpuError>) {
+        // Waiting for a key or the display doesn't count as a step
+        let executed = match outcome {
+            Ok(CycleOutcome::AwaitingKeyPress) | Ok(CycleOutcome::AwaitingRefresh) => false,
+            Ok(_) => true,
+            Err(_) => {
+                self.paused = true;
+                self.steps = 0;
+                return;
+            },
+        };
+
+        let watched = cpu.memory_accesses().iter().find_map(|access| {
+            self.watchpoints.iter()
+                .find(|(addr, watch)| access.contains(**addr) && watch.matches(access.kind))
+                .map(|(addr, _)| (*addr, access))
+        });
+
+        if let Some((addr, access)) = watched {
+            let kind = if access.kind == AccessKind::Write { "Write" } else { "Read" };
+            println!("\nWatchpoint: {} of {:04x} by the instruction at {:04x}", kind, addr, access.pc);
+            self.pause(cpu);
+            prompt();
+            return;
+        }
+
+        if executed && self.steps > 0 {
+            self.steps -= 1;
+            if self.steps == 0 {
+                println!();
+                self.pause(cpu);
+                prompt();
+            }
+        }
+    }
+    fn paused(&self) -> bool {
+        self.paused && self.steps == 0
+    }
+}
+
+// Which accesses to an address stop execution
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Watch {
+    Read,
+    Write,
+    Access,
+}
+
+impl Watch {
+    fn matches(self, kind: AccessKind) -> bool {
+        match kind {
+            AccessKind::Fetch => false,
+            AccessKind::Read => self != Watch::Write,
+            AccessKind::Write => self != Watch::Read,
+        }
+    }
+}
+
+fn print_state(cpu: &Cpu) {
+    let regs: Vec<String> = cpu.regs().iter()
+        .enumerate()
+        .map(|(idx, val)| format!("V{:X}={:02x}", idx, val))
+        .collect();
+    println!("{}", regs.join(" "));
+
+    let stack: Vec<String> = cpu.stack().iter().map(|addr| format!("{:04x}", addr)).collect();
+    println!(
+        "I={:04x} PC={:04x} SP={:02x} DT={:02x} ST={:02x} stack=[{}]",
+        cpu.i(), cpu.pc(), cpu.sp(), cpu.delay_timer(), cpu.sound_timer(), stack.join(" "),
+    );
+
+    let keys: Vec<String> = cpu.keypad().pressed_keys().iter().map(|key| format!("{:X}", key)).collect();
+    println!("keys=[{}]", keys.join(" "));
+
+    let mnemonic = cpu.instruction_at(cpu.pc())
+        .map(|instruction| instruction.mnemonic())
+        .unwrap_or_else(|| "???".to_string());
+    println!("{:04x}: {}", cpu.pc(), mnemonic);
+}
+
+fn prompt() {
+    print!("(debug) ");
+    let _ = io::stdout().flush();
+}
+
+// Splits off the first word of `line`, returning it and the rest of the line
+fn split_word(line: &str) -> (&str, &str) {
+    let line = line.trim();
+    match line.find(char::is_whitespace) {
+        Some(idx) => (&line[..idx], line[idx..].trim()),
+        None => (line, ""),
+    }
+}
+
+// Parses an address in hex, with or without a `0x` prefix
+fn parse_addr(addr: &str) -> Option<u16> {
+    let digits = addr.strip_prefix("0x").unwrap_or(addr);
+    u16::from_str_radix(digits, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chip8_core::{Platform, Quirks};
+
+    fn start(platform: Platform, program: &[u8]) -> (Debugger, Cpu) {
+        let mut cpu = Cpu::new(platform, Quirks::default());
+        cpu.load_binary(program).unwrap();
+        let (_, commands) = mpsc::channel();
+        (Debugger::with_commands(&mut cpu, commands), cpu)
+    }
+
+    #[test]
+    fn words() {
+        assert_eq!(split_word("  b  2a4 if V3 == 10 "), ("b", "2a4 if V3 == 10"));
+        assert_eq!(split_word("regs"), ("regs", ""));
+        assert_eq!(split_word(""), ("", ""));
+
+        assert_eq!(parse_addr("2a4"), Some(0x2A4));
+        assert_eq!(parse_addr("0x2A4"), Some(0x2A4));
+        assert_eq!(parse_addr("ffff"), Some(0xFFFF));
+        assert_eq!(parse_addr("10000"), None);
+        assert_eq!(parse_addr("v3"), None);
+    }
+
+    #[test]
+    fn stepping() {
+        let (mut debugger, cpu) = start(Platform::Chip8, &[0x00, 0xE0]);
+        assert!(debugger.paused());
+
+        debugger.run_command("step 3", &cpu);
+        assert_eq!(debugger.steps, 3);
+        assert!(!debugger.paused());
+
+        debugger.run_command("p", &cpu);
+        assert!(debugger.paused());
+
+        debugger.run_command("s", &cpu);
+        assert_eq!(debugger.steps, 1);
+
+        // A bad count leaves the debugger as it was
+        debugger.run_command("s x", &cpu);
+        assert_eq!(debugger.steps, 1);
+
+        debugger.run_command("c", &cpu);
+        assert!(!debugger.paused);
+        assert_eq!(debugger.resume_from, Some(0x200));
+    }
+
+    #[test]
+    fn breakpoints() {
+        let (mut debugger, cpu) = start(Platform::Chip8, &[0x00, 0xE0]);
+
+        debugger.run_command("b 2a4", &cpu);
+        debugger.run_command("break 0x300 if V3 == 10", &cpu);
+        assert_eq!(debugger.breakpoints.keys().copied().collect::<Vec<_>>(), [0x2A4, 0x300]);
+        assert!(debugger.breakpoints[&0x2A4].is_none());
+        assert!(debugger.breakpoints[&0x300].is_some());
+
+        // Neither a bad address, a missing 'if' nor a bad condition add one
+        debugger.run_command("b 2zz", &cpu);
+        debugger.run_command("b 400 V3 == 10", &cpu);
+        debugger.run_command("b 500 if V3 ==", &cpu);
+        assert_eq!(debugger.breakpoints.len(), 2);
+
+        debugger.run_command("d 2a4", &cpu);
+        debugger.run_command("delete", &cpu);
+        assert_eq!(debugger.breakpoints.keys().copied().collect::<Vec<_>>(), [0x300]);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let (mut debugger, cpu) = start(Platform::Chip8, &[0x00, 0xE0]);
+
+        debugger.run_command("watch 300", &cpu);
+        debugger.run_command("rwatch 301", &cpu);
+        debugger.run_command("awatch 302", &cpu);
+        debugger.run_command("watch nowhere", &cpu);
+        assert_eq!(debugger.watchpoints[&0x300], Watch::Write);
+        assert_eq!(debugger.watchpoints[&0x301], Watch::Read);
+        assert_eq!(debugger.watchpoints[&0x302], Watch::Access);
+        assert_eq!(debugger.watchpoints.len(), 3);
+
+        debugger.run_command("unwatch 301", &cpu);
+        assert!(!debugger.watchpoints.contains_key(&0x301));
+
+        assert!(Watch::Write.matches(AccessKind::Write));
+        assert!(!Watch::Write.matches(AccessKind::Read));
+        assert!(Watch::Read.matches(AccessKind::Read));
+        assert!(!Watch::Read.matches(AccessKind::Write));
+        assert!(Watch::Access.matches(AccessKind::Read) && Watch::Access.matches(AccessKind::Write));
+        assert!(!Watch::Access.matches(AccessKind::Fetch));
+    }
+
+    #[test]
+    fn next_steps_over_calls() {
+        // CALL 206, CLS
+        let (mut debugger, cpu) = start(Platform::Chip8, &[0x22, 0x06, 0x00, 0xE0]);
+        debugger.run_command("next", &cpu);
+        assert!(!debugger.paused());
+        assert_eq!(debugger.step_over, Some((0x202, 0)));
+
+        // Anything else is a single step
+        let (mut debugger, mut cpu) = start(Platform::Chip8, &[0x22, 0x06, 0x00, 0xE0]);
+        cpu.set_pc(0x202);
+        debugger.run_command("n", &cpu);
+        assert_eq!(debugger.step_over, None);
+        assert_eq!(debugger.steps, 1);
+    }
+
+    #[test]
+    fn next_over_a_call_at_the_top_of_memory() {
+        let (mut debugger, mut cpu) = start(Platform::XoChip, &[0x00, 0xE0]);
+        cpu.memory_mut()[0xFFFE..].copy_from_slice(&[0x22, 0x00]);
+        cpu.set_pc(0xFFFE);
+
+        debugger.run_command("n", &cpu);
+        assert_eq!(debugger.step_over, Some((0x0000, 0)));
+    }
+}
@@ -0,0 +1,323 @@
+use std::fmt;
+
+use chip8_core::Cpu;
+
+// A condition on the cpu state for a breakpoint, e.g. `V3 == 0x10 && I > 300`.
+//
+// Operands are registers (V0-VF, I, PC, SP, DT, ST), the byte of memory at an
+// address (`[300]`), and numbers in hex with an optional `0x` prefix. They can
+// be compared with `==`, `!=`, `<`, `<=`, `>` and `>=`, combined with `&&` and
+// `||`, and grouped with parentheses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    Number(u16),
+    Reg(usize),
+    I,
+    Pc,
+    Sp,
+    Dt,
+    St,
+    Memory(Box<Expr>),
+    Compare(Box<Expr>, Comparison, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Condition {
+    pub fn parse(source: &str) -> Result<Condition, String> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens: &tokens, pos: 0 };
+
+        let expr = parser.or()?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("unexpected '{}'", token));
+        }
+
+        Ok(Condition { source: source.trim().to_string(), expr })
+    }
+
+    pub fn holds(&self, cpu: &Cpu) -> bool {
+        evaluate(&self.expr, cpu) != 0
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+fn evaluate(expr: &Expr, cpu: &Cpu) -> u16 {
+    match expr {
+        Expr::Number(value) => *value,
+        Expr::Reg(idx) => cpu.regs()[*idx] as u16,
+        Expr::I => cpu.i(),
+        Expr::Pc => cpu.pc(),
+        Expr::Sp => cpu.sp() as u16,
+        Expr::Dt => cpu.delay_timer() as u16,
+        Expr::St => cpu.sound_timer() as u16,
+        Expr::Memory(addr) => {
+            let addr = evaluate(addr, cpu) as usize;
+            cpu.memory().get(addr).copied().unwrap_or(0) as u16
+        },
+        Expr::Compare(lhs, comparison, rhs) => {
+            let (lhs, rhs) = (evaluate(lhs, cpu), evaluate(rhs, cpu));
+            let result = match comparison {
+                Comparison::Eq => lhs == rhs,
+                Comparison::Ne => lhs != rhs,
+                Comparison::Lt => lhs < rhs,
+                Comparison::Le => lhs <= rhs,
+                Comparison::Gt => lhs > rhs,
+                Comparison::Ge => lhs >= rhs,
+            };
+            result as u16
+        },
+        Expr::And(lhs, rhs) => (evaluate(lhs, cpu) != 0 && evaluate(rhs, cpu) != 0) as u16,
+        Expr::Or(lhs, rhs) => (evaluate(lhs, cpu) != 0 || evaluate(rhs, cpu) != 0) as u16,
+    }
+}
+
+// Splits the source into words and operators
+fn tokenize(source: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' => (),
+            '(' | ')' | '[' | ']' => tokens.push(c.to_string()),
+            '=' | '!' | '<' | '>' | '&' | '|' => {
+                let mut op = c.to_string();
+                if let Some(next) = chars.peek().filter(|next| matches!(next, '=' | '&' | '|')) {
+                    op.push(*next);
+                    chars.next();
+                }
+                tokens.push(op);
+            },
+            c if c.is_ascii_alphanumeric() => {
+                let mut word = c.to_string();
+                while let Some(next) = chars.peek().filter(|next| next.is_ascii_alphanumeric()) {
+                    word.push(*next);
+                    chars.next();
+                }
+                tokens.push(word);
+            },
+            _ => return Err(format!("unexpected '{}'", c)),
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: &'a [String],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Result<&'a str, String> {
+        let token = self.peek().ok_or("unexpected end of condition")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        match self.next()? {
+            token if token == expected => Ok(()),
+            token => Err(format!("expected '{}', got '{}'", expected, token)),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.peek() == Some("||") {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.comparison()?;
+        while self.peek() == Some("&&") {
+            self.pos += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.comparison()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let lhs = self.operand()?;
+
+        let comparison = match self.peek() {
+            Some("==") => Comparison::Eq,
+            Some("!=") => Comparison::Ne,
+            Some("<") => Comparison::Lt,
+            Some("<=") => Comparison::Le,
+            Some(">") => Comparison::Gt,
+            Some(">=") => Comparison::Ge,
+            _ => return Ok(lhs),
+        };
+        self.pos += 1;
+
+        Ok(Expr::Compare(Box::new(lhs), comparison, Box::new(self.operand()?)))
+    }
+
+    fn operand(&mut self) -> Result<Expr, String> {
+        let token = self.next()?;
+
+        match token.to_uppercase().as_str() {
+            "(" => {
+                let expr = self.or()?;
+                self.expect(")")?;
+                Ok(expr)
+            },
+            "[" => {
+                let addr = self.or()?;
+                self.expect("]")?;
+                Ok(Expr::Memory(Box::new(addr)))
+            },
+            "I" => Ok(Expr::I),
+            "PC" => Ok(Expr::Pc),
+            "SP" => Ok(Expr::Sp),
+            "DT" => Ok(Expr::Dt),
+            "ST" => Ok(Expr::St),
+            upper => {
+                if let Some(reg) = parse_register(upper) {
+                    return Ok(Expr::Reg(reg));
+                }
+
+                let digits = upper.strip_prefix("0X").unwrap_or(upper);
+                u16::from_str_radix(digits, 16)
+                    .map(Expr::Number)
+                    .map_err(|_| format!("expected a register or number, got '{}'", token))
+            },
+        }
+    }
+}
+
+// Parses `V0` to `V15`, or `VA` to `VF`
+fn parse_register(name: &str) -> Option<usize> {
+    let idx = name.strip_prefix('V')?;
+
+    let reg = if idx.len() == 1 {
+        usize::from_str_radix(idx, 16).ok()?
+    } else {
+        idx.parse().ok()?
+    };
+
+    if reg < 16 {
+        Some(reg)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chip8_core::{Platform, Quirks};
+
+    fn expr(source: &str) -> Expr {
+        Condition::parse(source).unwrap_or_else(|err| panic!("{}", err)).expr
+    }
+
+    fn error(source: &str) -> String {
+        Condition::parse(source).expect_err("condition should fail to parse")
+    }
+
+    fn compare(lhs: Expr, comparison: Comparison, rhs: Expr) -> Expr {
+        Expr::Compare(Box::new(lhs), comparison, Box::new(rhs))
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let (a, b, c) = (Expr::Reg(1), Expr::Reg(2), Expr::Reg(3));
+        let and = |lhs: &Expr, rhs: &Expr| Expr::And(Box::new(lhs.clone()), Box::new(rhs.clone()));
+        let or = |lhs: &Expr, rhs: &Expr| Expr::Or(Box::new(lhs.clone()), Box::new(rhs.clone()));
+
+        assert_eq!(expr("V1 || V2 && V3"), or(&a, &and(&b, &c)));
+        assert_eq!(expr("V1 && V2 || V3"), or(&and(&a, &b), &c));
+        assert_eq!(expr("(V1 || V2) && V3"), and(&or(&a, &b), &c));
+    }
+
+    #[test]
+    fn operands() {
+        assert_eq!(expr("V10"), Expr::Reg(10));
+        assert_eq!(expr("VA"), Expr::Reg(10));
+        assert_eq!(expr("vf"), Expr::Reg(15));
+        assert_eq!(expr("V15"), Expr::Reg(15));
+        assert_eq!(expr("i"), Expr::I);
+        assert_eq!(expr("PC != sp"), compare(Expr::Pc, Comparison::Ne, Expr::Sp));
+        assert_eq!(expr("DT <= ST"), compare(Expr::Dt, Comparison::Le, Expr::St));
+
+        // Numbers are always hex
+        assert_eq!(expr("10"), Expr::Number(0x10));
+        assert_eq!(expr("0x2A4"), Expr::Number(0x2A4));
+        assert_eq!(expr("ff"), Expr::Number(0xFF));
+
+        assert_eq!(expr("[300]"), Expr::Memory(Box::new(Expr::Number(0x300))));
+        assert_eq!(expr("[I] >= 1"), compare(Expr::Memory(Box::new(Expr::I)), Comparison::Ge, Expr::Number(1)));
+    }
+
+    #[test]
+    fn holds() {
+        let mut cpu = Cpu::new(Platform::Chip8, Quirks::default());
+        cpu.regs_mut()[3] = 0x10;
+        cpu.regs_mut()[10] = 0x2A;
+        cpu.set_i(0x300);
+        cpu.memory_mut()[0x300] = 0x7F;
+
+        let holds = |source: &str| Condition::parse(source).unwrap().holds(&cpu);
+        assert!(holds("V3 == 10"));
+        assert!(holds("V10 == 2a && VA == 0x2a"));
+        assert!(holds("[I] == 7f"));
+        assert!(holds("[300] > V3"));
+        assert!(holds("V3 == 0 || I == 300"));
+        assert!(!holds("V3 == 10 && I < 300"));
+        assert!(!holds("V3 != 10"));
+        assert!(holds("V3"));
+        assert!(!holds("V0"));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error(""), "unexpected end of condition");
+        assert_eq!(error("V3 =="), "unexpected end of condition");
+        assert_eq!(error("V3 = 10"), "unexpected '='");
+        assert_eq!(error("V3 == 10 &"), "unexpected '&'");
+        assert_eq!(error("V3 == 10 $"), "unexpected '$'");
+        assert_eq!(error("V16 == 0"), "expected a register or number, got 'V16'");
+        assert_eq!(error("V3 == xyz"), "expected a register or number, got 'xyz'");
+        assert_eq!(error("(V3 == 10"), "unexpected end of condition");
+        assert_eq!(error("[300) == 1"), "expected ']', got ')'");
+        assert_eq!(error("V3 == 10)"), "unexpected ')'");
+    }
+
+    #[test]
+    fn displays_its_source() {
+        assert_eq!(Condition::parse("  V3 == 10 ").unwrap().to_string(), "V3 == 10");
+    }
+}
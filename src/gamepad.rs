@@ -0,0 +1,532 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use chip8_core::keypad::KEY_COUNT;
+
+use crate::database::Button;
+use crate::window::InputBackend;
+
+// The buttons gamepads are read for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GamepadButton {
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+    // The bottom action button, e.g. A on an Xbox controller
+    South,
+    // The right action button, e.g. B on an Xbox controller
+    East,
+    // The left action button, e.g. X on an Xbox controller
+    West,
+    // The top action button, e.g. Y on an Xbox controller
+    North,
+    LeftTrigger,
+    RightTrigger,
+    Select,
+    Start,
+}
+
+impl GamepadButton {
+    // Every button, in the order above
+    pub const ALL: [GamepadButton; 12] = [
+        GamepadButton::DPadUp,
+        GamepadButton::DPadDown,
+        GamepadButton::DPadLeft,
+        GamepadButton::DPadRight,
+        GamepadButton::South,
+        GamepadButton::East,
+        GamepadButton::West,
+        GamepadButton::North,
+        GamepadButton::LeftTrigger,
+        GamepadButton::RightTrigger,
+        GamepadButton::Select,
+        GamepadButton::Start,
+    ];
+
+    // Looks up a button by its name, e.g. `DPadUp` or `south`, ignoring case
+    pub fn from_name(name: &str) -> Option<GamepadButton> {
+        GamepadButton::ALL.iter().copied().find(|button| format!("{:?}", button).eq_ignore_ascii_case(name))
+    }
+}
+
+// Identifies a gamepad for as long as it is plugged in
+pub type GamepadId = usize;
+
+// Something that happened to a gamepad. Only gilrs and the tests send them
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(not(feature = "gamepad"), allow(dead_code))]
+pub enum GamepadEvent {
+    Connected { id: GamepadId, name: String },
+    Disconnected { id: GamepadId },
+    Pressed { id: GamepadId, button: GamepadButton },
+    Released { id: GamepadId, button: GamepadButton },
+}
+
+// Where gamepad events come from, e.g. a gamepad library
+pub trait GamepadSource {
+    // The events since the last poll, oldest first
+    fn poll(&mut self) -> Vec<GamepadEvent>;
+}
+
+// Which keypad key each gamepad button presses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GamepadBindings {
+    keys: HashMap<GamepadButton, u8>,
+}
+
+impl GamepadBindings {
+    // Makes `button` press `key`, replacing the key it pressed before. Keys
+    // outside of `0`-`F` are ignored
+    pub fn bind(&mut self, button: GamepadButton, key: u8) {
+        if (key as usize) < KEY_COUNT {
+            self.keys.insert(button, key);
+        }
+    }
+
+    // The key `button` presses, if it is bound
+    pub fn key(&self, button: GamepadButton) -> Option<u8> {
+        self.keys.get(&button).copied()
+    }
+}
+
+impl Default for GamepadBindings {
+    // Octo's convention: the D-pad presses 5, 7, 8 and 9, which are laid out
+    // like WASD on the keypad, and the South and East buttons press 6 and 4
+    fn default() -> GamepadBindings {
+        let keys = [
+            (GamepadButton::DPadUp, 0x5),
+            (GamepadButton::DPadLeft, 0x7),
+            (GamepadButton::DPadDown, 0x8),
+            (GamepadButton::DPadRight, 0x9),
+            (GamepadButton::South, 0x6),
+            (GamepadButton::East, 0x4),
+        ];
+
+        GamepadBindings { keys: keys.iter().copied().collect() }
+    }
+}
+
+// A gamepad being plugged in or unplugged, by name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Hotplug {
+    Connected(String),
+    Disconnected(String),
+}
+
+// Every connected gamepad, with the buttons each is holding down
+pub struct Gamepads {
+    source: Box<dyn GamepadSource>,
+    bindings: GamepadBindings,
+    // Buttons held on each connected gamepad
+    held: HashMap<GamepadId, Vec<GamepadButton>>,
+    names: HashMap<GamepadId, String>,
+}
+
+impl Gamepads {
+    pub fn new(source: Box<dyn GamepadSource>, bindings: GamepadBindings) -> Gamepads {
+        Gamepads {
+            source,
+            bindings,
+            held: HashMap::new(),
+            names: HashMap::new(),
+        }
+    }
+
+    // Reads the events since the last poll, returning the gamepads plugged in
+    // or unplugged. Buttons held on an unplugged gamepad are released
+    pub fn poll(&mut self) -> Vec<Hotplug> {
+        let mut hotplugs = Vec::new();
+
+        for event in self.source.poll() {
+            match event {
+                GamepadEvent::Connected { id, name } => {
+                    hotplugs.push(Hotplug::Connected(name.clone()));
+                    self.held.insert(id, Vec::new());
+                    self.names.insert(id, name);
+                },
+                GamepadEvent::Disconnected { id } => {
+                    let name = self.names.remove(&id).unwrap_or_else(|| format!("gamepad {}", id));
+                    hotplugs.push(Hotplug::Disconnected(name));
+                    self.held.remove(&id);
+                },
+                GamepadEvent::Pressed { id, button } => {
+                    let held = self.held.entry(id).or_default();
+                    if !held.contains(&button) {
+                        held.push(button);
+                    }
+                },
+                GamepadEvent::Released { id, button } => {
+                    if let Some(held) = self.held.get_mut(&id) {
+                        held.retain(|held| *held != button);
+                    }
+                },
+            }
+        }
+
+        hotplugs
+    }
+}
+
+impl InputBackend for Gamepads {
+    // The keys pressed by the buttons held on every gamepad, in ascending order
+    fn keys_pressed(&mut self) -> Vec<u8> {
+        let mut keys: Vec<u8> = self.held.values()
+            .flatten()
+            .filter_map(|button| self.bindings.key(*button))
+            .collect();
+        keys.sort_unstable();
+        keys.dedup();
+        keys
+    }
+}
+
+// A source no gamepads are ever plugged into, for builds without gamepads or
+// when they can't be read
+struct NoGamepads;
+
+impl GamepadSource for NoGamepads {
+    fn poll(&mut self) -> Vec<GamepadEvent> {
+        Vec::new()
+    }
+}
+
+// Reads the gamepads connected to the computer
+#[cfg(feature = "gamepad")]
+pub struct GilrsGamepads {
+    gilrs: gilrs::Gilrs,
+    // Gamepads already connected on startup, which gilrs doesn't send
+    // `Connected` events for
+    initial: Vec<GamepadEvent>,
+}
+
+#[cfg(feature = "gamepad")]
+impl GilrsGamepads {
+    pub fn new() -> Result<GilrsGamepads, String> {
+        let gilrs = gilrs::Gilrs::new().map_err(|err| err.to_string())?;
+        let initial = gilrs.gamepads()
+            .map(|(id, gamepad)| GamepadEvent::Connected { id: id.into(), name: gamepad.name().to_string() })
+            .collect();
+
+        Ok(GilrsGamepads { gilrs, initial })
+    }
+
+    fn button(button: gilrs::Button) -> Option<GamepadButton> {
+        match button {
+            gilrs::Button::DPadUp => Some(GamepadButton::DPadUp),
+            gilrs::Button::DPadDown => Some(GamepadButton::DPadDown),
+            gilrs::Button::DPadLeft => Some(GamepadButton::DPadLeft),
+            gilrs::Button::DPadRight => Some(GamepadButton::DPadRight),
+            gilrs::Button::South => Some(GamepadButton::South),
+            gilrs::Button::East => Some(GamepadButton::East),
+            gilrs::Button::West => Some(GamepadButton::West),
+            gilrs::Button::North => Some(GamepadButton::North),
+            gilrs::Button::LeftTrigger => Some(GamepadButton::LeftTrigger),
+            gilrs::Button::RightTrigger => Some(GamepadButton::RightTrigger),
+            gilrs::Button::Select => Some(GamepadButton::Select),
+            gilrs::Button::Start => Some(GamepadButton::Start),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "gamepad")]
+impl GamepadSource for GilrsGamepads {
+    fn poll(&mut self) -> Vec<GamepadEvent> {
+        let mut events = std::mem::take(&mut self.initial);
+
+        while let Some(gilrs::Event { id, event, .. }) = self.gilrs.next_event() {
+            let name = self.gilrs.gamepad(id).name().to_string();
+            let id: GamepadId = id.into();
+
+            events.extend(match event {
+                gilrs::EventType::Connected => Some(GamepadEvent::Connected { id, name }),
+                gilrs::EventType::Disconnected => Some(GamepadEvent::Disconnected { id }),
+                gilrs::EventType::ButtonPressed(button, _) => {
+                    Self::button(button).map(|button| GamepadEvent::Pressed { id, button })
+                },
+                gilrs::EventType::ButtonReleased(button, _) => {
+                    Self::button(button).map(|button| GamepadEvent::Released { id, button })
+                },
+                _ => None,
+            });
+        }
+
+        events
+    }
+}
+
+// Opens the gamepads for the build: those connected to the computer if built
+// with the `gamepad` feature, otherwise a source none are ever plugged into
+pub fn default_source() -> Box<dyn GamepadSource> {
+    #[cfg(feature = "gamepad")]
+    {
+        match GilrsGamepads::new() {
+            Ok(gamepads) => return Box::new(gamepads),
+            Err(err) => eprintln!("Gamepads disabled: {}", err),
+        }
+    }
+
+    Box::new(NoGamepads)
+}
+
+// Starts from the default bindings, then applies the `[gamepad]` table of the
+// global keymap file, the rom's buttons from the database, and the table in the
+// rom's own keymap file, each replacing the buttons it lists
+pub fn load_bindings(global: Option<&Path>, buttons: &[(Button, u8)], rom_keymap: &Path) -> Result<GamepadBindings, String> {
+    let mut bindings = GamepadBindings::default();
+
+    if let Some(global) = global {
+        apply_file(&mut bindings, global)?;
+    }
+
+    bind_buttons(&mut bindings, buttons);
+    apply_file(&mut bindings, rom_keymap)?;
+
+    Ok(bindings)
+}
+
+// Binds the buttons the rom database lists, with A and B on the South and East
+// buttons
+fn bind_buttons(bindings: &mut GamepadBindings, buttons: &[(Button, u8)]) {
+    for (button, key) in buttons {
+        let gamepad_button = match button {
+            Button::Up => GamepadButton::DPadUp,
+            Button::Down => GamepadButton::DPadDown,
+            Button::Left => GamepadButton::DPadLeft,
+            Button::Right => GamepadButton::DPadRight,
+            Button::A => GamepadButton::South,
+            Button::B => GamepadButton::East,
+        };
+        bindings.bind(gamepad_button, *key);
+    }
+}
+
+// Applies the `[gamepad]` table of a keymap file, which may be missing
+fn apply_file(bindings: &mut GamepadBindings, path: &Path) -> Result<(), String> {
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(format!("Couldn't read {}: {}", path.display(), err)),
+    };
+
+    apply(bindings, &source).map_err(|message| format!("{}: {}", path.display(), message))
+}
+
+// Parses the `[gamepad]` table of a keymap file, like
+//
+//     [gamepad]
+//     DPadUp = "1"
+//     South = "A"
+fn apply(bindings: &mut GamepadBindings, source: &str) -> Result<(), String> {
+    let value: toml::Value = source.parse().map_err(|err: toml::de::Error| err.to_string())?;
+    let table = match value.get("gamepad") {
+        Some(toml::Value::Table(table)) => table,
+        Some(_) => return Err("'gamepad' should be a table".to_string()),
+        None => return Ok(()),
+    };
+
+    for (name, key) in table {
+        let button = GamepadButton::from_name(name).ok_or(format!("unknown gamepad button '{}'", name))?;
+
+        let key = match key {
+            toml::Value::String(key) if key.len() == 1 => u8::from_str_radix(key, 16).ok(),
+            toml::Value::Integer(key) if (0..16).contains(key) => Some(*key as u8),
+            _ => None,
+        };
+        let key = key.ok_or(format!("expected a keypad key from \"0\" to \"F\" for {}", name))?;
+
+        bindings.bind(button, key);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Gamepads plugged in and pressed by calling its methods, rather than by
+    // hardware. Events are delivered on the next poll
+    #[derive(Default)]
+    struct FakeGamepads {
+        events: Vec<GamepadEvent>,
+    }
+
+    impl FakeGamepads {
+        fn new() -> FakeGamepads {
+            FakeGamepads::default()
+        }
+
+        fn plug_in(&mut self, id: GamepadId, name: &str) {
+            self.events.push(GamepadEvent::Connected { id, name: name.to_string() });
+        }
+
+        fn unplug(&mut self, id: GamepadId) {
+            self.events.push(GamepadEvent::Disconnected { id });
+        }
+
+        fn press(&mut self, id: GamepadId, button: GamepadButton) {
+            self.events.push(GamepadEvent::Pressed { id, button });
+        }
+
+        fn release(&mut self, id: GamepadId, button: GamepadButton) {
+            self.events.push(GamepadEvent::Released { id, button });
+        }
+    }
+
+    impl GamepadSource for FakeGamepads {
+        fn poll(&mut self) -> Vec<GamepadEvent> {
+            std::mem::take(&mut self.events)
+        }
+    }
+
+    // Lets a test keep pressing buttons after handing the source over
+    impl GamepadSource for Rc<RefCell<FakeGamepads>> {
+        fn poll(&mut self) -> Vec<GamepadEvent> {
+            self.borrow_mut().poll()
+        }
+    }
+
+    fn gamepads(bindings: GamepadBindings) -> (Gamepads, Rc<RefCell<FakeGamepads>>) {
+        let fake = Rc::new(RefCell::new(FakeGamepads::new()));
+        (Gamepads::new(Box::new(fake.clone()), bindings), fake)
+    }
+
+    #[test]
+    fn default_bindings() {
+        let (mut gamepads, fake) = gamepads(GamepadBindings::default());
+        fake.borrow_mut().plug_in(0, "pad");
+        for button in GamepadButton::ALL.iter() {
+            fake.borrow_mut().press(0, *button);
+        }
+        gamepads.poll();
+
+        assert_eq!(gamepads.keys_pressed(), [0x4, 0x5, 0x6, 0x7, 0x8, 0x9]);
+    }
+
+    #[test]
+    fn a_roms_bindings_replace_the_defaults() {
+        let mut bindings = GamepadBindings::default();
+        bindings.bind(GamepadButton::South, 0xA);
+        bindings.bind(GamepadButton::Start, 0x0);
+        // Not a keypad key, so the button keeps its binding
+        bindings.bind(GamepadButton::East, 0x10);
+
+        assert_eq!(bindings.key(GamepadButton::South), Some(0xA));
+        assert_eq!(bindings.key(GamepadButton::Start), Some(0x0));
+        assert_eq!(bindings.key(GamepadButton::East), Some(0x4));
+        assert_eq!(bindings.key(GamepadButton::Select), None);
+
+        let (mut gamepads, fake) = gamepads(bindings);
+        fake.borrow_mut().plug_in(0, "pad");
+        fake.borrow_mut().press(0, GamepadButton::South);
+        fake.borrow_mut().press(0, GamepadButton::Start);
+        fake.borrow_mut().press(0, GamepadButton::Select);
+        gamepads.poll();
+
+        assert_eq!(gamepads.keys_pressed(), [0x0, 0xA]);
+    }
+
+    #[test]
+    fn button_names() {
+        assert_eq!(GamepadButton::from_name("DPadUp"), Some(GamepadButton::DPadUp));
+        assert_eq!(GamepadButton::from_name("south"), Some(GamepadButton::South));
+        assert_eq!(GamepadButton::from_name("A"), None);
+    }
+
+    #[test]
+    fn unplugging_releases_held_buttons() {
+        let (mut gamepads, fake) = gamepads(GamepadBindings::default());
+        fake.borrow_mut().plug_in(0, "left");
+        fake.borrow_mut().plug_in(1, "right");
+        fake.borrow_mut().press(0, GamepadButton::DPadUp);
+        fake.borrow_mut().press(1, GamepadButton::South);
+        assert_eq!(gamepads.poll(), [Hotplug::Connected("left".to_string()), Hotplug::Connected("right".to_string())]);
+        assert_eq!(gamepads.keys_pressed(), [0x5, 0x6]);
+
+        fake.borrow_mut().unplug(0);
+        assert_eq!(gamepads.poll(), [Hotplug::Disconnected("left".to_string())]);
+        assert_eq!(gamepads.keys_pressed(), [0x6]);
+
+        // Plugged back in, nothing is held until pressed again
+        fake.borrow_mut().plug_in(0, "left");
+        gamepads.poll();
+        assert_eq!(gamepads.keys_pressed(), [0x6]);
+    }
+
+    #[test]
+    fn keys_held_on_several_gamepads_are_pressed_once() {
+        let (mut gamepads, fake) = gamepads(GamepadBindings::default());
+        fake.borrow_mut().plug_in(0, "left");
+        fake.borrow_mut().plug_in(1, "right");
+        fake.borrow_mut().press(0, GamepadButton::DPadUp);
+        fake.borrow_mut().press(1, GamepadButton::DPadUp);
+        fake.borrow_mut().press(1, GamepadButton::DPadUp);
+        gamepads.poll();
+        assert_eq!(gamepads.keys_pressed(), [0x5]);
+
+        // The key stays down while either gamepad holds it
+        fake.borrow_mut().release(0, GamepadButton::DPadUp);
+        gamepads.poll();
+        assert_eq!(gamepads.keys_pressed(), [0x5]);
+
+        fake.borrow_mut().release(1, GamepadButton::DPadUp);
+        gamepads.poll();
+        assert!(gamepads.keys_pressed().is_empty());
+    }
+
+    fn parse(source: &str) -> GamepadBindings {
+        let mut bindings = GamepadBindings::default();
+        apply(&mut bindings, source).unwrap_or_else(|err| panic!("{}", err));
+        bindings
+    }
+
+    #[test]
+    fn keymap_files() {
+        let bindings = parse("[keys]\n1 = \"Q\"\n\n[gamepad]\nsouth = \"a\"\nStart = 15\nNorth = \"0\"\n");
+        assert_eq!(bindings.key(GamepadButton::South), Some(0xA));
+        assert_eq!(bindings.key(GamepadButton::Start), Some(0xF));
+        assert_eq!(bindings.key(GamepadButton::North), Some(0x0));
+        assert_eq!(bindings.key(GamepadButton::DPadUp), Some(0x5));
+
+        // Files with only keyboard keys change nothing
+        assert_eq!(parse("[keys]\n1 = \"Q\""), GamepadBindings::default());
+        assert_eq!(parse(""), GamepadBindings::default());
+    }
+
+    #[test]
+    fn the_roms_bindings_take_precedence() {
+        let mut bindings = GamepadBindings::default();
+        apply(&mut bindings, "[gamepad]\nSouth = \"1\"\nEast = \"2\"\nNorth = \"3\"").unwrap();
+        // From the rom database, which overrides the global file
+        bind_buttons(&mut bindings, &[(Button::A, 0xA), (Button::B, 0xB), (Button::Left, 0x4)]);
+        apply(&mut bindings, "[gamepad]\nSouth = \"C\"").unwrap();
+
+        assert_eq!(bindings.key(GamepadButton::South), Some(0xC));
+        assert_eq!(bindings.key(GamepadButton::East), Some(0xB));
+        assert_eq!(bindings.key(GamepadButton::North), Some(0x3));
+        assert_eq!(bindings.key(GamepadButton::DPadLeft), Some(0x4));
+        assert_eq!(bindings.key(GamepadButton::DPadUp), Some(0x5));
+    }
+
+    #[test]
+    fn invalid_bindings() {
+        let error = |source: &str| apply(&mut GamepadBindings::default(), source).unwrap_err();
+        assert_eq!(error("gamepad = 1"), "'gamepad' should be a table");
+        assert_eq!(error("[gamepad]\nTurbo = \"1\""), "unknown gamepad button 'Turbo'");
+        assert_eq!(error("[gamepad]\nSouth = \"G\""), "expected a keypad key from \"0\" to \"F\" for South");
+        assert_eq!(error("[gamepad]\nSouth = \"10\""), "expected a keypad key from \"0\" to \"F\" for South");
+        assert_eq!(error("[gamepad]\nSouth = 16"), "expected a keypad key from \"0\" to \"F\" for South");
+        assert!(!error("[gamepad").is_empty());
+    }
+
+    #[test]
+    fn missing_files_keep_the_defaults() {
+        let dir = std::env::temp_dir().join("chip8-emulator-no-such-dir");
+        let bindings = load_bindings(Some(&dir.join("keymap.toml")), &[], &dir.join("rom.keymap.toml")).unwrap();
+        assert_eq!(bindings, GamepadBindings::default());
+    }
+}
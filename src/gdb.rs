@@ -0,0 +1,533 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use chip8_core::{Cpu, CpuError, CycleOutcome};
+
+use crate::debugger::Control;
+
+// Describes the registers to gdb, in the order they appear in `g` packets
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chip8.core">
+    <reg name="v0" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="v1" bitsize="8" type="uint8"/>
+    <reg name="v2" bitsize="8" type="uint8"/>
+    <reg name="v3" bitsize="8" type="uint8"/>
+    <reg name="v4" bitsize="8" type="uint8"/>
+    <reg name="v5" bitsize="8" type="uint8"/>
+    <reg name="v6" bitsize="8" type="uint8"/>
+    <reg name="v7" bitsize="8" type="uint8"/>
+    <reg name="v8" bitsize="8" type="uint8"/>
+    <reg name="v9" bitsize="8" type="uint8"/>
+    <reg name="va" bitsize="8" type="uint8"/>
+    <reg name="vb" bitsize="8" type="uint8"/>
+    <reg name="vc" bitsize="8" type="uint8"/>
+    <reg name="vd" bitsize="8" type="uint8"/>
+    <reg name="ve" bitsize="8" type="uint8"/>
+    <reg name="vf" bitsize="8" type="uint8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="dt" bitsize="8" type="uint8"/>
+    <reg name="st" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+// Size in bytes of each register in TARGET_XML
+const REGISTER_SIZES: [usize; 21] = [1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 1, 1, 1];
+
+// Signals reported to gdb when execution stops
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+enum Event {
+    Connected(TcpStream),
+    Packet(String),
+    // gdb sends a bare 0x03 byte to interrupt a running program
+    Interrupt,
+    Disconnected,
+}
+
+// A server for the GDB remote serial protocol, so gdb can attach with
+// `target remote localhost:<port>`. Packets are read on a separate thread, and
+// handled between frames like the debugger's commands.
+//
+// Multi-byte registers are sent big-endian, like CHIP-8 memory, so gdb needs
+// `set endian big`.
+pub struct GdbServer {
+    events: Receiver<Event>,
+    client: Option<TcpStream>,
+    paused: bool,
+    stepping: bool,
+    breakpoints: BTreeSet<u16>,
+    // A breakpoint isn't hit again when resuming from it
+    resume_from: Option<u16>,
+    // The last reason execution stopped, reported by `?`
+    stop_reply: String,
+    // Set once gdb kills the program
+    killed: bool,
+}
+
+impl GdbServer {
+    // Starts listening on `port`, with the cpu paused until gdb attaches and
+    // continues it
+    pub fn listen(port: u16) -> io::Result<GdbServer> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        let (sender, events) = mpsc::channel();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream.and_then(|stream| Ok((stream.try_clone()?, stream))) {
+                    Ok((writer, reader)) => {
+                        if sender.send(Event::Connected(writer)).is_err() {
+                            break;
+                        }
+                        reader
+                    },
+                    Err(_) => continue,
+                };
+
+                if let Ok(writer) = stream.try_clone() {
+                    read_packets(BufReader::new(stream), writer, &sender);
+                }
+                if sender.send(Event::Disconnected).is_err() {
+                    break;
+                }
+            }
+        });
+
+        println!("Waiting for gdb to attach on port {}", port);
+
+        Ok(GdbServer {
+            events,
+            client: None,
+            paused: true,
+            stepping: false,
+            breakpoints: BTreeSet::new(),
+            resume_from: None,
+            stop_reply: format!("S{:02x}", SIGTRAP),
+            killed: false,
+        })
+    }
+
+    fn send(&mut self, data: &str) {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        let packet = format!("${}#{:02x}", data, checksum);
+
+        if let Some(client) = self.client.as_mut() {
+            if let Err(err) = client.write_all(packet.as_bytes()) {
+                eprintln!("GDB: failed to send packet: {}", err);
+            }
+        }
+    }
+
+    // Stops execution, telling gdb why
+    fn stop(&mut self, reply: String) {
+        self.paused = true;
+        self.stepping = false;
+        self.send(&reply);
+        self.stop_reply = reply;
+    }
+
+    fn resume(&mut self, cpu: &Cpu, stepping: bool) {
+        self.paused = false;
+        self.stepping = stepping;
+        self.resume_from = Some(cpu.pc());
+    }
+
+    // Handles a packet, returning the reply. `s` and `c` are only replied to once
+    // execution stops.
+    fn handle(&mut self, packet: &str, cpu: &mut Cpu) -> Option<String> {
+        let (command, args) = packet.split_at(packet.len().min(1));
+
+        let reply = match command {
+            "?" => self.stop_reply.clone(),
+            "q" if args.starts_with("Supported") => "PacketSize=1000;qXfer:features:read+".to_string(),
+            "q" if args.starts_with("Xfer:features:read:target.xml:") => {
+                match parse_range(&args["Xfer:features:read:target.xml:".len()..]) {
+                    Some((offset, end)) => {
+                        let start = offset.min(TARGET_XML.len());
+                        let end = end.min(TARGET_XML.len());
+                        let more = if end < TARGET_XML.len() { "m" } else { "l" };
+                        format!("{}{}", more, &TARGET_XML[start..end])
+                    },
+                    None => "E01".to_string(),
+                }
+            },
+            "q" if args == "Attached" => "1".to_string(),
+            "H" => "OK".to_string(),
+            "g" => (0..REGISTER_SIZES.len()).map(|reg| read_register(cpu, reg)).collect(),
+            "G" => match decode_hex(args) {
+                Some(bytes) if bytes.len() == REGISTER_SIZES.iter().sum::<usize>() => {
+                    let mut offset = 0;
+                    for (reg, size) in REGISTER_SIZES.iter().enumerate() {
+                        write_register(cpu, reg, &bytes[offset..offset + size]);
+                        offset += size;
+                    }
+                    "OK".to_string()
+                },
+                _ => "E01".to_string(),
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(reg) if reg < REGISTER_SIZES.len() => read_register(cpu, reg),
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let (reg, value) = args.split_at(args.find('=').unwrap_or(0));
+                let reg = usize::from_str_radix(reg, 16).ok().filter(|reg| *reg < REGISTER_SIZES.len());
+                match (reg, decode_hex(value.trim_start_matches('='))) {
+                    (Some(reg), Some(bytes)) if bytes.len() == REGISTER_SIZES[reg] => {
+                        write_register(cpu, reg, &bytes);
+                        "OK".to_string()
+                    },
+                    _ => "E01".to_string(),
+                }
+            },
+            "m" => match parse_range(args).and_then(|(addr, end)| cpu.memory().get(addr..end)) {
+                Some(bytes) => encode_hex(bytes),
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let (range, data) = args.split_at(args.find(':').unwrap_or(0));
+                let bytes = decode_hex(data.trim_start_matches(':'));
+                let memory = parse_range(range).and_then(|(addr, end)| cpu.memory_mut().get_mut(addr..end));
+
+                match (memory, bytes) {
+                    (Some(memory), Some(bytes)) if memory.len() == bytes.len() => {
+                        memory.copy_from_slice(&bytes);
+                        "OK".to_string()
+                    },
+                    _ => "E01".to_string(),
+                }
+            },
+            "Z" | "z" if args.starts_with("0,") => {
+                let addr = args[2..].split(',').next().and_then(|addr| u16::from_str_radix(addr, 16).ok());
+                match addr {
+                    Some(addr) if command == "Z" => {
+                        self.breakpoints.insert(addr);
+                        "OK".to_string()
+                    },
+                    Some(addr) => {
+                        self.breakpoints.remove(&addr);
+                        "OK".to_string()
+                    },
+                    None => "E01".to_string(),
+                }
+            },
+            "c" | "s" => {
+                if let Ok(addr) = u16::from_str_radix(args, 16) {
+                    cpu.set_pc(addr);
+                }
+                self.resume(cpu, command == "s");
+                return None;
+            },
+            "D" => {
+                self.detach(cpu);
+                "OK".to_string()
+            },
+            // Killing the program closes the emulator, and gdb expects no reply
+            "k" => {
+                println!("GDB: killed");
+                self.breakpoints.clear();
+                self.paused = true;
+                self.killed = true;
+                self.client = None;
+                return None;
+            },
+            // An empty reply tells gdb the packet isn't supported
+            _ => String::new(),
+        };
+
+        Some(reply)
+    }
+
+    // Lets the program run freely once gdb is gone
+    fn detach(&mut self, cpu: &Cpu) {
+        self.breakpoints.clear();
+        self.resume(cpu, false);
+    }
+}
+
+impl Control for GdbServer {
+    fn poll(&mut self, cpu: &mut Cpu) {
+        while let Ok(event) = self.events.try_recv() {
+            match event {
+                Event::Connected(client) => {
+                    println!("GDB: attached");
+                    self.client = Some(client);
+                },
+                Event::Packet(packet) => {
+                    if let Some(reply) = self.handle(&packet, cpu) {
+                        self.send(&reply);
+                    }
+                },
+                Event::Interrupt if !self.paused => self.stop(format!("S{:02x}", SIGINT)),
+                Event::Interrupt => (),
+                Event::Disconnected => {
+                    println!("GDB: detached");
+                    self.client = None;
+                    self.detach(cpu);
+                },
+            }
+        }
+    }
+
+    fn can_cycle(&mut self, cpu: &Cpu) -> bool {
+        if self.paused {
+            return false;
+        }
+
+        let pc = cpu.pc();
+        let resuming = self.resume_from.take() == Some(pc);
+
+        if self.breakpoints.contains(&pc) && !resuming {
+            self.stop(format!("S{:02x}", SIGTRAP));
+            return false;
+        }
+
+        true
+    }
+
+    fn cycled(&mut self, _cpu: &Cpu, outcome: &Result<CycleOutcome, CpuError>) {
+        match outcome {
+            Ok(CycleOutcome::Exited) => {
+                self.paused = true;
+                self.send("W00");
+            },
+            Ok(CycleOutcome::AwaitingKeyPress) | Ok(CycleOutcome::AwaitingRefresh) => (),
+            Ok(_) if self.stepping => self.stop(format!("S{:02x}", SIGTRAP)),
+            Ok(_) => (),
+            Err(CpuError::MemoryOutOfBounds { .. }) => self.stop(format!("S{:02x}", SIGSEGV)),
+            Err(_) => self.stop(format!("S{:02x}", SIGILL)),
+        }
+    }
+
+    fn paused(&self) -> bool {
+        self.paused
+    }
+
+    fn killed(&self) -> bool {
+        self.killed
+    }
+}
+
+// Reads `$packet#checksum` packets from gdb until it disconnects, acknowledging
+// each one on `writer`
+fn read_packets(reader: impl BufRead, mut writer: impl Write, events: &Sender<Event>) {
+    let mut bytes = reader.bytes();
+    while let Some(Ok(byte)) = bytes.next() {
+        let event = match byte {
+            b'$' => {
+                // The checksum covers the bytes as sent, before unescaping them
+                let mut data = Vec::new();
+                let mut sum = 0u8;
+                loop {
+                    match bytes.next() {
+                        Some(Ok(b'#')) => break,
+                        // `}` escapes the next byte, which is XORed with 0x20
+                        Some(Ok(b'}')) => match bytes.next() {
+                            Some(Ok(byte)) => {
+                                sum = sum.wrapping_add(b'}').wrapping_add(byte);
+                                data.push(byte ^ 0x20);
+                            },
+                            _ => return,
+                        },
+                        Some(Ok(byte)) => {
+                            sum = sum.wrapping_add(byte);
+                            data.push(byte);
+                        },
+                        _ => return,
+                    }
+                }
+
+                let checksum: Vec<u8> = bytes.by_ref().take(2).filter_map(Result::ok).collect();
+                let valid = std::str::from_utf8(&checksum).ok()
+                    .and_then(|checksum| u8::from_str_radix(checksum, 16).ok())
+                    == Some(sum);
+
+                // Packets are acknowledged with `+`, or `-` to ask for a resend
+                if writer.write_all(if valid { b"+" } else { b"-" }).is_err() {
+                    return;
+                }
+                if !valid {
+                    continue;
+                }
+
+                Event::Packet(String::from_utf8_lossy(&data).into_owned())
+            },
+            0x03 => Event::Interrupt,
+            // Acknowledgements of our packets
+            _ => continue,
+        };
+
+        if events.send(event).is_err() {
+            return;
+        }
+    }
+}
+
+fn read_register(cpu: &Cpu, reg: usize) -> String {
+    match reg {
+        0..=15 => format!("{:02x}", cpu.regs()[reg]),
+        16 => format!("{:04x}", cpu.i()),
+        17 => format!("{:04x}", cpu.pc()),
+        18 => format!("{:02x}", cpu.sp()),
+        19 => format!("{:02x}", cpu.delay_timer()),
+        _ => format!("{:02x}", cpu.sound_timer()),
+    }
+}
+
+// Writes a register from its big-endian bytes. The stack pointer can't be written.
+fn write_register(cpu: &mut Cpu, reg: usize, bytes: &[u8]) {
+    let value = bytes.iter().fold(0u16, |value, byte| value << 8 | *byte as u16);
+
+    match reg {
+        0..=15 => cpu.regs_mut()[reg] = value as u8,
+        16 => cpu.set_i(value),
+        17 => cpu.set_pc(value),
+        19 => cpu.set_delay_timer(value as u8),
+        20 => cpu.set_sound_timer(value as u8),
+        _ => (),
+    }
+}
+
+// Parses `addr,length` in hex into the start and end of the range, failing if
+// the end overflows
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    let idx = range.find(',')?;
+    let start = usize::from_str_radix(&range[..idx], 16).ok()?;
+    let len = usize::from_str_radix(&range[idx + 1..], 16).ok()?;
+    Some((start, start.checked_add(len)?))
+}
+
+// Decodes pairs of hex digits, failing on an odd number of digits
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(hex.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chip8_core::{Platform, Quirks};
+
+    // Reads packets from `input`, returning what was read and the acknowledgements
+    fn read(input: &[u8]) -> (Vec<String>, String) {
+        let (sender, events) = mpsc::channel();
+        let mut acks = Vec::new();
+        read_packets(input, &mut acks, &sender);
+        drop(sender);
+
+        let events = events.iter()
+            .map(|event| match event {
+                Event::Packet(packet) => packet,
+                Event::Interrupt => "^C".to_string(),
+                Event::Connected(_) | Event::Disconnected => panic!("unexpected event"),
+            })
+            .collect();
+
+        (events, String::from_utf8(acks).unwrap())
+    }
+
+    #[test]
+    fn packets() {
+        assert_eq!(read(b"$OK#9a"), (vec!["OK".to_string()], "+".to_string()));
+        assert_eq!(read(b"$#00"), (vec![String::new()], "+".to_string()));
+        assert_eq!(read(b"$g#67$?#3F"), (vec!["g".to_string(), "?".to_string()], "++".to_string()));
+
+        // Acknowledgements and anything outside a packet are skipped
+        assert_eq!(read(b"+-junk$g#67"), (vec!["g".to_string()], "+".to_string()));
+    }
+
+    #[test]
+    fn bad_checksums_ask_for_a_resend() {
+        assert_eq!(read(b"$OK#9b$g#67"), (vec!["g".to_string()], "-+".to_string()));
+        assert_eq!(read(b"$OK#zz"), (vec![], "-".to_string()));
+    }
+
+    #[test]
+    fn escapes() {
+        // `}` followed by 0x5d is an escaped `}`, and the checksum covers the
+        // bytes as sent rather than the unescaped ones
+        let checksum = |data: &[u8]| data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        let packet = format!("$M0,1:}}]#{:02x}", checksum(b"M0,1:}]"));
+        assert_eq!(read(packet.as_bytes()), (vec!["M0,1:}".to_string()], "+".to_string()));
+
+        let packet = format!("$M0,1:}}]#{:02x}", checksum(b"M0,1:}"));
+        assert_eq!(read(packet.as_bytes()), (vec![], "-".to_string()));
+
+        let packet = format!("$X0,2:}}\x03}}\x04#{:02x}", checksum(b"X0,2:}\x03}\x04"));
+        assert_eq!(read(packet.as_bytes()), (vec!["X0,2:#$".to_string()], "+".to_string()));
+    }
+
+    #[test]
+    fn interrupts() {
+        assert_eq!(read(b"\x03"), (vec!["^C".to_string()], String::new()));
+        assert_eq!(read(b"$c#63\x03"), (vec!["c".to_string(), "^C".to_string()], "+".to_string()));
+    }
+
+    #[test]
+    fn truncated_packets_are_dropped() {
+        assert_eq!(read(b"$g#67$OK"), (vec!["g".to_string()], "+".to_string()));
+        assert_eq!(read(b"$OK#9a$}"), (vec!["OK".to_string()], "+".to_string()));
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("200,10"), Some((0x200, 0x210)));
+        assert_eq!(parse_range("0,0"), Some((0, 0)));
+        assert_eq!(parse_range("200"), None);
+        assert_eq!(parse_range("200,"), None);
+        assert_eq!(parse_range("xyz,10"), None);
+
+        // The end used to overflow, panicking
+        let max = format!("{:x}", usize::MAX);
+        assert_eq!(parse_range(&format!("{},1", max)), None);
+        assert_eq!(parse_range(&format!("1,{}", max)), None);
+        assert_eq!(parse_range(&format!("{},0", max)), Some((usize::MAX, usize::MAX)));
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(decode_hex("00a0FF"), Some(vec![0x00, 0xA0, 0xFF]));
+        assert_eq!(decode_hex(""), Some(vec![]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(encode_hex(&[0x00, 0xA0, 0xFF]), "00a0ff");
+    }
+
+    #[test]
+    fn memory_reads_past_the_end() {
+        let (_, events) = mpsc::channel();
+        let mut server = GdbServer {
+            events,
+            client: None,
+            paused: true,
+            stepping: false,
+            breakpoints: BTreeSet::new(),
+            resume_from: None,
+            stop_reply: String::new(),
+            killed: false,
+        };
+        let mut cpu = Cpu::new(Platform::Chip8, Quirks::default());
+        cpu.load_binary(&[0x12, 0x34]).unwrap();
+
+        assert_eq!(server.handle("m200,2", &mut cpu), Some("1234".to_string()));
+        assert_eq!(server.handle("mfff,2", &mut cpu), Some("E01".to_string()));
+        assert_eq!(server.handle(&format!("m{:x},1", usize::MAX), &mut cpu), Some("E01".to_string()));
+        assert_eq!(server.handle(&format!("M{:x},1:00", usize::MAX), &mut cpu), Some("E01".to_string()));
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use chip8_core::audio::{AudioSink, Beeper};
+use chip8_core::movie::display_hash;
+use chip8_core::{Cpu, CycleOutcome, Movie};
+
+use crate::scheduler::FrameCycles;
+use crate::REFRESH_RATE;
+
+// How the final framebuffer is written out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    // One character per pixel
+    Ascii,
+    // Plain (P1) portable bitmap
+    Pbm,
+    // A 64-bit FNV-1a hash, for comparing against a known good run
+    Hash,
+}
+
+impl DumpFormat {
+    pub fn parse(name: &str) -> Option<DumpFormat> {
+        match name {
+            "ascii" => Some(DumpFormat::Ascii),
+            "pbm" => Some(DumpFormat::Pbm),
+            "hash" => Some(DumpFormat::Hash),
+            _ => None,
+        }
+    }
+}
+
+// How long to run for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Duration {
+    Frames(u64),
+    Cycles(u64),
+}
+
+// Keys to hold down, starting from a given frame until the next entry
+pub struct KeyScript {
+    entries: BTreeMap<u64, Vec<u8>>,
+}
+
+impl KeyScript {
+    // Parses a script of space or comma separated `FRAME:KEYS` entries, where
+    // KEYS are the hex digits of the keys held from that frame onwards. For
+    // example "60:5 90: 120:46" holds 5 from frame 60, releases it at frame 90,
+    // and holds 4 and 6 from frame 120.
+    pub fn parse(script: &str) -> Result<KeyScript, String> {
+        let mut entries = BTreeMap::new();
+
+        for entry in script.split([' ', ',']).filter(|e| !e.is_empty()) {
+            let (frame, keys) = match entry.find(':') {
+                Some(idx) => (&entry[..idx], &entry[idx + 1..]),
+                None => return Err(format!("expected FRAME:KEYS, got '{}'", entry)),
+            };
+
+            let frame: u64 = frame.parse()
+                .map_err(|_| format!("invalid frame number '{}'", frame))?;
+            let keys = keys.chars()
+                .map(|c| c.to_digit(16).map(|k| k as u8).ok_or(format!("invalid key '{}'", c)))
+                .collect::<Result<Vec<u8>, String>>()?;
+
+            entries.insert(frame, keys);
+        }
+
+        Ok(KeyScript { entries })
+    }
+
+    pub fn empty() -> KeyScript {
+        KeyScript { entries: BTreeMap::new() }
+    }
+
+    // The keys held during `frame`
+    fn keys_at(&self, frame: u64) -> &[u8] {
+        self.entries.range(..=frame)
+            .next_back()
+            .map(|(_, keys)| keys.as_slice())
+            .unwrap_or(&[])
+    }
+}
+
+pub struct Options {
+    pub duration: Duration,
+    pub clock_hz: u32,
+    pub keys: KeyScript,
+    pub dump: DumpFormat,
+}
+
+// Runs the cpu without a window, then prints the final framebuffer and register
+// state to stdout. Returns false if the cpu faulted.
+pub fn run(cpu: &mut Cpu, options: &Options, audio_sink: &mut dyn AudioSink) -> bool {
+    let (frames, mut cycles_left) = match options.duration {
+        Duration::Frames(frames) => (frames, u64::MAX),
+        Duration::Cycles(cycles) => (u64::MAX, cycles),
+    };
+
+    let mut frame_cycles = FrameCycles::new(options.clock_hz);
+    let mut beeper = Beeper::new(audio_sink.sample_rate());
+    let mut result = Ok(());
+
+    'frames: for frame in 0..frames {
+        // Input is sampled at the start of each frame, like the windowed frontend
+        cpu.update_keyboard(options.keys.keys_at(frame));
+
+        for _ in 0..frame_cycles.for_frame() {
+            if cycles_left == 0 {
+                break 'frames;
+            }
+            cycles_left -= 1;
+
+            match cpu.cycle() {
+                Ok(CycleOutcome::Exited) => break 'frames,
+                Ok(_) => (),
+                Err(err) => {
+                    result = Err(err);
+                    break 'frames;
+                },
+            }
+        }
+
+        let samples = beeper.frame(cpu.sound_active(), cpu.audio_pattern());
+        audio_sink.play(&samples);
+
+        cpu.timer_interrupt();
+    }
+
+    print!("{}", dump_display(cpu, options.dump));
+    print!("{}", dump_registers(cpu));
+
+    match result {
+        Ok(()) => true,
+        Err(err) => {
+            println!("fault: {}", err);
+            false
+        },
+    }
+}
+
+// Replays a movie, checking the display against each of its checkpoints and
+// reporting the first frame that doesn't match. As only checkpoints are
+// compared, the divergence happened some time after the previous checkpoint.
+// Returns false if the movie diverged or the cpu faulted.
+pub fn verify(cpu: &mut Cpu, movie: &Movie, audio_sink: &mut dyn AudioSink) -> bool {
+    let mut frame_cycles = FrameCycles::new(movie.clock_hz);
+    let mut beeper = Beeper::new(audio_sink.sample_rate());
+    let mut exited = false;
+    let mut last_match = None;
+
+    for (idx, frame) in movie.frames.iter().enumerate() {
+        frame.apply(cpu);
+
+        for _ in 0..frame_cycles.for_frame() {
+            if exited {
+                break;
+            }
+
+            match cpu.cycle() {
+                Ok(CycleOutcome::Exited) => exited = true,
+                Ok(_) => (),
+                Err(err) => {
+                    println!("fault at frame {}: {}", idx, err);
+                    return false;
+                },
+            }
+        }
+
+        let samples = beeper.frame(cpu.sound_active(), cpu.audio_pattern());
+        audio_sink.play(&samples);
+        cpu.timer_interrupt();
+
+        if !frame.matches(cpu) {
+            println!(
+                "diverged at frame {}: expected framebuffer {:016x}, got {:016x}",
+                idx, frame.checkpoint.unwrap_or_default(), display_hash(cpu),
+            );
+            match last_match {
+                Some(last_match) => println!("last matching checkpoint was frame {}", last_match),
+                None => println!("no checkpoints matched"),
+            }
+            print!("{}", dump_display(cpu, DumpFormat::Ascii));
+            return false;
+        }
+
+        if frame.checkpoint.is_some() {
+            last_match = Some(idx);
+        }
+    }
+
+    let checkpoints = movie.frames.iter().filter(|frame| frame.checkpoint.is_some()).count();
+    println!("movie verified: {} frames, {} checkpoints", movie.frames.len(), checkpoints);
+    true
+}
+
+// Runs `frames` frames as fast as possible without any input, then prints how
+// long they took. Returns false if the cpu faulted.
+pub fn bench(cpu: &mut Cpu, frames: u64, clock_hz: u32) -> bool {
+    let mut frame_cycles = FrameCycles::new(clock_hz);
+    let mut cycles = 0;
+    let mut frames_run = 0;
+    let start = Instant::now();
+
+    'frames: for frame in 0..frames {
+        for _ in 0..frame_cycles.for_frame() {
+            cycles += 1;
+
+            match cpu.cycle() {
+                Ok(CycleOutcome::Exited) => break 'frames,
+                Ok(_) => (),
+                Err(err) => {
+                    println!("fault at frame {}: {}", frame, err);
+                    return false;
+                },
+            }
+        }
+
+        cpu.timer_interrupt();
+        frames_run += 1;
+    }
+
+    let elapsed = start.elapsed().as_secs_f64();
+    let emulated = frames_run as f64 / REFRESH_RATE as f64;
+    println!("ran {} frames ({} cycles) in {:.3}s", frames_run, cycles, elapsed);
+    println!("{:.0} cycles per second, {:.1}x real time", cycles as f64 / elapsed, emulated / elapsed);
+    true
+}
+
+// The framebuffer in the given format, one line per row
+fn dump_display(cpu: &Cpu, format: DumpFormat) -> String {
+    let (width, height) = cpu.display_size();
+    let buffer = cpu.display_buffer();
+    let mut dump = String::new();
+
+    match format {
+        DumpFormat::Ascii => {
+            // Pixels are shown by the bitplanes they are set in
+            let chars = ['.', '#', '+', '@'];
+            for row in buffer.chunks(width) {
+                dump.extend(row.iter().map(|p| chars[*p as usize]));
+                dump.push('\n');
+            }
+        },
+        DumpFormat::Pbm => {
+            dump += &format!("P1\n{} {}\n", width, height);
+            for row in buffer.chunks(width) {
+                let line: Vec<&str> = row.iter().map(|p| if *p != 0 { "1" } else { "0" }).collect();
+                dump += &line.join(" ");
+                dump.push('\n');
+            }
+        },
+        DumpFormat::Hash => dump += &format!("framebuffer: {:016x}\n", display_hash(cpu)),
+    }
+
+    dump
+}
+
+fn dump_registers(cpu: &Cpu) -> String {
+    let regs: Vec<String> = cpu.regs().iter()
+        .enumerate()
+        .map(|(idx, val)| format!("V{:X}={:02x}", idx, val))
+        .collect();
+    format!(
+        "{}\nI={:04x} PC={:04x} SP={:02x} DT={:02x} ST={:02x}\n",
+        regs.join(" "), cpu.i(), cpu.pc(), cpu.sp(), cpu.delay_timer(), cpu.sound_timer(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chip8_core::{Platform, Quirks};
+
+    #[test]
+    fn key_scripts() {
+        let script = KeyScript::parse("60:5 90:,120:46").unwrap();
+        assert!(script.keys_at(0).is_empty());
+        assert!(script.keys_at(59).is_empty());
+        assert_eq!(script.keys_at(60), [0x5]);
+        assert_eq!(script.keys_at(89), [0x5]);
+        assert!(script.keys_at(90).is_empty());
+        assert_eq!(script.keys_at(120), [0x4, 0x6]);
+        assert_eq!(script.keys_at(u64::MAX), [0x4, 0x6]);
+
+        // Keys are hex digits in either case, and entries can come in any order
+        let script = KeyScript::parse("  10:aF  0:1 ").unwrap();
+        assert_eq!(script.keys_at(5), [0x1]);
+        assert_eq!(script.keys_at(10), [0xA, 0xF]);
+
+        assert!(KeyScript::parse("").unwrap().keys_at(0).is_empty());
+        assert!(KeyScript::empty().keys_at(100).is_empty());
+    }
+
+    #[test]
+    fn key_script_errors() {
+        let error = |script| KeyScript::parse(script).err().unwrap();
+        assert_eq!(error("60"), "expected FRAME:KEYS, got '60'");
+        assert_eq!(error("x:5"), "invalid frame number 'x'");
+        assert_eq!(error("-1:5"), "invalid frame number '-1'");
+        assert_eq!(error("60:5g"), "invalid key 'g'");
+    }
+
+    #[test]
+    fn dump_formats() {
+        assert_eq!(DumpFormat::parse("ascii"), Some(DumpFormat::Ascii));
+        assert_eq!(DumpFormat::parse("pbm"), Some(DumpFormat::Pbm));
+        assert_eq!(DumpFormat::parse("hash"), Some(DumpFormat::Hash));
+        assert_eq!(DumpFormat::parse("png"), None);
+
+        // Draws the font's 0, whose top row is 1111
+        let mut cpu = Cpu::new(Platform::Chip8, Quirks::default());
+        cpu.load_binary(&[0x60, 0x00, 0xF0, 0x29, 0xD0, 0x05]).unwrap();
+        for _ in 0..3 {
+            cpu.cycle().unwrap();
+        }
+
+        let ascii = dump_display(&cpu, DumpFormat::Ascii);
+        let rows: Vec<&str> = ascii.lines().collect();
+        assert_eq!(rows.len(), 32);
+        assert_eq!(rows[0], format!("####{}", ".".repeat(60)));
+        assert_eq!(rows[1], format!("#..#{}", ".".repeat(60)));
+        assert_eq!(rows[31], ".".repeat(64));
+
+        let pbm = dump_display(&cpu, DumpFormat::Pbm);
+        let rows: Vec<&str> = pbm.lines().collect();
+        assert_eq!(rows[..2], ["P1", "64 32"]);
+        assert_eq!(rows.len(), 34);
+        assert_eq!(rows[2], format!("1 1 1 1{}", " 0".repeat(60)));
+
+        assert_eq!(dump_display(&cpu, DumpFormat::Hash), format!("framebuffer: {:016x}\n", display_hash(&cpu)));
+    }
+
+    #[test]
+    fn register_dump() {
+        let mut cpu = Cpu::new(Platform::Chip8, Quirks::default());
+        cpu.load_binary(&[0x6A, 0x3C, 0xA1, 0x23]).unwrap();
+        cpu.cycle().unwrap();
+        cpu.cycle().unwrap();
+
+        assert_eq!(
+            dump_registers(&cpu),
+            "V0=00 V1=00 V2=00 V3=00 V4=00 V5=00 V6=00 V7=00 V8=00 V9=00 VA=3c VB=00 VC=00 VD=00 VE=00 VF=00\n\
+             I=0123 PC=0204 SP=00 DT=00 ST=00\n",
+        );
+    }
+}
@@ -0,0 +1,385 @@
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use minifb::Key;
+
+// The keypad's layout, row by row, which keys are listed and prompted in
+pub const KEYPAD_ORDER: [u8; 16] = [
+    0x1, 0x2, 0x3, 0xC,
+    0x4, 0x5, 0x6, 0xD,
+    0x7, 0x8, 0x9, 0xE,
+    0xA, 0x0, 0xB, 0xF,
+];
+
+// Every host key that can be mapped, named as in keymap files
+const HOST_KEYS: &[Key] = &[
+    Key::Key0, Key::Key1, Key::Key2, Key::Key3, Key::Key4, Key::Key5, Key::Key6, Key::Key7, Key::Key8, Key::Key9,
+    Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I, Key::J, Key::K, Key::L, Key::M,
+    Key::N, Key::O, Key::P, Key::Q, Key::R, Key::S, Key::T, Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z,
+    Key::F1, Key::F2, Key::F3, Key::F4, Key::F5, Key::F6, Key::F7, Key::F8, Key::F9, Key::F10, Key::F11,
+    Key::F12, Key::F13, Key::F14, Key::F15,
+    Key::Down, Key::Left, Key::Right, Key::Up,
+    Key::Apostrophe, Key::Backquote, Key::Backslash, Key::Comma, Key::Equal, Key::LeftBracket, Key::Minus,
+    Key::Period, Key::RightBracket, Key::Semicolon, Key::Slash,
+    Key::Backspace, Key::Delete, Key::End, Key::Enter, Key::Escape, Key::Home, Key::Insert, Key::Menu,
+    Key::PageDown, Key::PageUp, Key::Pause, Key::Space, Key::Tab,
+    Key::NumLock, Key::CapsLock, Key::ScrollLock,
+    Key::LeftShift, Key::RightShift, Key::LeftCtrl, Key::RightCtrl,
+    Key::NumPad0, Key::NumPad1, Key::NumPad2, Key::NumPad3, Key::NumPad4, Key::NumPad5, Key::NumPad6,
+    Key::NumPad7, Key::NumPad8, Key::NumPad9, Key::NumPadDot, Key::NumPadSlash, Key::NumPadAsterisk,
+    Key::NumPadMinus, Key::NumPadPlus, Key::NumPadEnter,
+    Key::LeftAlt, Key::RightAlt, Key::LeftSuper, Key::RightSuper,
+];
+
+// Which host keys press each of the 16 keypad keys
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keymap {
+    keys: [Vec<Key>; 16],
+}
+
+impl Keymap {
+    // Starts from the default layout, then applies the global keymap file and
+    // the rom's own, with each replacing the host keys of the keys it lists.
+    // Either file may be missing
+    pub fn load(global: Option<&Path>, rom_keymap: &Path) -> Result<Keymap, String> {
+        let mut keymap = Keymap::default();
+
+        for path in global.into_iter().chain(Some(rom_keymap)) {
+            let source = match std::fs::read_to_string(path) {
+                Ok(source) => source,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(format!("Couldn't read {}: {}", path.display(), err)),
+            };
+            keymap.apply(&source).map_err(|message| format!("{}: {}", path.display(), message))?;
+        }
+
+        Ok(keymap)
+    }
+
+    // Where the global keymap is kept, e.g. `~/.config/chip8-emulator/keymap.toml`
+    pub fn global_path() -> Option<PathBuf> {
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+
+        Some(config_dir.join("chip8-emulator").join("keymap.toml"))
+    }
+
+    // A rom's own keymap is kept next to it, e.g. `breakout.keymap.toml`
+    pub fn rom_path(rom: &Path) -> PathBuf {
+        rom.with_extension("keymap.toml")
+    }
+
+    // Parses a keymap file, like
+    //
+    //     [keys]
+    //     1 = "Key1"
+    //     4 = ["Q", "Left"]
+    fn apply(&mut self, source: &str) -> Result<(), String> {
+        let value: toml::Value = source.parse().map_err(|err: toml::de::Error| err.to_string())?;
+        let keys = match value.get("keys") {
+            Some(toml::Value::Table(keys)) => keys,
+            Some(_) => return Err("'keys' should be a table".to_string()),
+            None => return Ok(()),
+        };
+
+        for (name, host_keys) in keys {
+            let key = match u8::from_str_radix(name, 16) {
+                Ok(key) if name.len() == 1 => key,
+                _ => return Err(format!("'{}' isn't a keypad key, expected 0-F", name)),
+            };
+
+            let names = match host_keys {
+                toml::Value::String(name) => vec![name.as_str()],
+                toml::Value::Array(names) => names.iter()
+                    .map(|name| name.as_str().ok_or(format!("expected key names for keypad key {:X}", key)))
+                    .collect::<Result<Vec<&str>, String>>()?,
+                _ => return Err(format!("expected a key name or a list of them for keypad key {:X}", key)),
+            };
+
+            self.keys[key as usize] = names.iter()
+                .map(|name| host_key(name).ok_or(format!("unknown key '{}'", name)))
+                .collect::<Result<Vec<Key>, String>>()?;
+        }
+
+        Ok(())
+    }
+
+    // The keypad keys pressed by a host key
+    pub fn decode(&self, host_key: Key) -> impl Iterator<Item = u8> + '_ {
+        (0..16u8).filter(move |key| self.keys[*key as usize].contains(&host_key))
+    }
+
+    // Makes `host_key` the only key pressing `key`, and stops it pressing any
+    // other key
+    pub fn bind(&mut self, key: u8, host_key: Key) {
+        for host_keys in self.keys.iter_mut() {
+            host_keys.retain(|bound| *bound != host_key);
+        }
+        self.keys[key as usize] = vec![host_key];
+    }
+
+    // Writes the keymap to a file, keeping any other tables already in it, like
+    // the gamepad bindings
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let mut source = self.to_string();
+        let existing = std::fs::read_to_string(path).ok().and_then(|existing| existing.parse().ok());
+        if let Some(toml::Value::Table(mut others)) = existing {
+            others.remove("keys");
+            if !others.is_empty() {
+                let others = toml::to_string(&others).map_err(std::io::Error::other)?;
+                source = source + "\n" + &others;
+            }
+        }
+
+        std::fs::write(path, source)
+    }
+}
+
+impl std::fmt::Display for Keymap {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "# Host keys pressing each keypad key")?;
+        writeln!(f, "[keys]")?;
+        for key in KEYPAD_ORDER.iter() {
+            let names: Vec<String> = self.keys[*key as usize].iter()
+                .map(|host_key| format!("\"{:?}\"", host_key))
+                .collect();
+            writeln!(f, "{:X} = [{}]", key, names.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Keymap {
+    // The keys in the top left of a QWERTY keyboard, laid out like the keypad
+    fn default() -> Keymap {
+        let layout = [
+            Key::Key1, Key::Key2, Key::Key3, Key::Key4,
+            Key::Q, Key::W, Key::E, Key::R,
+            Key::A, Key::S, Key::D, Key::F,
+            Key::Z, Key::X, Key::C, Key::V,
+        ];
+
+        let mut keys: [Vec<Key>; 16] = Default::default();
+        for (key, host_key) in KEYPAD_ORDER.iter().zip(layout.iter()) {
+            keys[*key as usize] = vec![*host_key];
+        }
+
+        Keymap { keys }
+    }
+}
+
+// Looks up a host key by name, ignoring case
+fn host_key(name: &str) -> Option<Key> {
+    HOST_KEYS.iter().copied().find(|key| format!("{:?}", key).eq_ignore_ascii_case(name))
+}
+
+// The remapping screen, which prompts for a host key for each keypad key in
+// turn. Escape cancels, and Delete keeps the current host keys
+pub struct Remap {
+    keymap: Keymap,
+    next: usize,
+}
+
+impl Remap {
+    pub fn new(keymap: Keymap) -> Remap {
+        Remap { keymap, next: 0 }
+    }
+
+    // The keypad key being prompted for
+    pub fn prompted(&self) -> u8 {
+        KEYPAD_ORDER[self.next]
+    }
+
+    // Handles a host key press, returning the new keymap once every key has
+    // been prompted for
+    pub fn press(&mut self, host_key: Key) -> Option<Keymap> {
+        if host_key != Key::Delete {
+            self.keymap.bind(self.prompted(), host_key);
+        }
+
+        self.next += 1;
+        if self.next == KEYPAD_ORDER.len() {
+            Some(self.keymap.clone())
+        } else {
+            None
+        }
+    }
+
+    // e.g. `press a key for 5 (now W), Delete to keep it, Escape to cancel`
+    pub fn status(&self) -> String {
+        let current: Vec<String> = self.keymap.keys[self.prompted() as usize].iter()
+            .map(|host_key| format!("{:?}", host_key))
+            .collect();
+
+        let mut status = format!("press a key for {:X}", self.prompted());
+        if !current.is_empty() {
+            let _ = write!(status, " (now {})", current.join(", "));
+        }
+        status + ", Delete to keep it, Escape to cancel"
+    }
+
+    // Draws the keypad in a 64x32 display buffer, with the prompted key
+    // highlighted, using the glyphs of the interpreter's font
+    pub fn draw(&self, font: &[u8]) -> Vec<u8> {
+        const WIDTH: usize = 64;
+        const CELL_WIDTH: usize = 16;
+        const CELL_HEIGHT: usize = 8;
+
+        let mut buffer = vec![0; WIDTH * CELL_HEIGHT * 4];
+        for (idx, key) in KEYPAD_ORDER.iter().enumerate() {
+            let (left, top) = ((idx % 4) * CELL_WIDTH, (idx / 4) * CELL_HEIGHT);
+            let (background, foreground) = if *key == self.prompted() { (2, 0) } else { (0, 1) };
+
+            for y in 0..CELL_HEIGHT {
+                for x in 0..CELL_WIDTH {
+                    // Each glyph is 4x5, in the high nibble of 5 bytes
+                    let (glyph_x, glyph_y) = (x.wrapping_sub(6), y.wrapping_sub(1));
+                    let lit = glyph_x < 4 && glyph_y < 5
+                        && font[*key as usize * 5 + glyph_y] & (0x80 >> glyph_x) != 0;
+                    buffer[(top + y) * WIDTH + left + x] = if lit { foreground } else { background };
+                }
+            }
+        }
+
+        buffer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Keymap {
+        let mut keymap = Keymap::default();
+        keymap.apply(source).unwrap_or_else(|err| panic!("{}", err));
+        keymap
+    }
+
+    fn error(source: &str) -> String {
+        Keymap::default().apply(source).expect_err("keymap should fail to parse")
+    }
+
+    fn decode(keymap: &Keymap, host_key: Key) -> Vec<u8> {
+        keymap.decode(host_key).collect()
+    }
+
+    #[test]
+    fn default_layout() {
+        let keymap = Keymap::default();
+        assert_eq!(decode(&keymap, Key::Key1), [0x1]);
+        assert_eq!(decode(&keymap, Key::Key4), [0xC]);
+        assert_eq!(decode(&keymap, Key::Q), [0x4]);
+        assert_eq!(decode(&keymap, Key::X), [0x0]);
+        assert_eq!(decode(&keymap, Key::V), [0xF]);
+        assert!(decode(&keymap, Key::Space).is_empty());
+    }
+
+    #[test]
+    fn keymap_files() {
+        let keymap = parse("[keys]\n1 = \"Space\"\n4 = [\"q\", \"LEFT\"]\nf = []\n");
+        assert_eq!(keymap.keys[0x1], [Key::Space]);
+        assert_eq!(keymap.keys[0x4], [Key::Q, Key::Left]);
+        assert!(keymap.keys[0xF].is_empty());
+        // Keys that aren't listed keep their host keys
+        assert_eq!(keymap.keys[0x5], [Key::W]);
+
+        // Several keypad keys can share a host key
+        let keymap = parse("[keys]\n2 = \"Up\"\n8 = [\"Up\", \"S\"]");
+        assert_eq!(decode(&keymap, Key::Up), [0x2, 0x8]);
+        assert_eq!(decode(&keymap, Key::S), [0x8]);
+
+        // Files without a keys table, like those only binding a gamepad, change nothing
+        assert_eq!(parse("[gamepad]\nSouth = 5"), Keymap::default());
+        assert_eq!(parse(""), Keymap::default());
+    }
+
+    #[test]
+    fn rom_keymaps_apply_over_the_global_one() {
+        let mut keymap = Keymap::default();
+        keymap.apply("[keys]\n5 = \"Up\"\n8 = \"Down\"").unwrap();
+        keymap.apply("[keys]\n5 = [\"I\", \"K\"]").unwrap();
+
+        assert_eq!(keymap.keys[0x5], [Key::I, Key::K]);
+        assert_eq!(keymap.keys[0x8], [Key::Down]);
+        assert!(decode(&keymap, Key::Up).is_empty());
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("keys = 1"), "'keys' should be a table");
+        assert_eq!(error("[keys]\n10 = \"A\""), "'10' isn't a keypad key, expected 0-F");
+        assert_eq!(error("[keys]\ng = \"A\""), "'g' isn't a keypad key, expected 0-F");
+        assert_eq!(error("[keys]\n1 = 5"), "expected a key name or a list of them for keypad key 1");
+        assert_eq!(error("[keys]\na = [\"A\", 5]"), "expected key names for keypad key A");
+        assert_eq!(error("[keys]\n1 = \"Nope\""), "unknown key 'Nope'");
+        assert!(!error("[keys").is_empty());
+    }
+
+    #[test]
+    fn saved_keymaps_load_back() {
+        let mut keymap = parse("[keys]\n4 = [\"Q\", \"Left\"]");
+        keymap.bind(0xF, Key::NumPadPlus);
+        assert_eq!(parse(&keymap.to_string()), keymap);
+    }
+
+    #[test]
+    fn binding_moves_a_host_key() {
+        let mut keymap = parse("[keys]\n4 = [\"Q\", \"Left\"]");
+        keymap.bind(0x1, Key::Q);
+        assert_eq!(keymap.keys[0x1], [Key::Q]);
+        assert_eq!(keymap.keys[0x4], [Key::Left]);
+    }
+
+    #[test]
+    fn remapping() {
+        let mut remap = Remap::new(Keymap::default());
+        assert_eq!(remap.prompted(), 0x1);
+        assert_eq!(remap.status(), "press a key for 1 (now Key1), Delete to keep it, Escape to cancel");
+
+        // Q moves from 4 to 1
+        assert_eq!(remap.press(Key::Q), None);
+        assert_eq!(remap.prompted(), 0x2);
+
+        // Delete keeps 2's key
+        assert_eq!(remap.press(Key::Delete), None);
+        assert_eq!(remap.prompted(), 0x3);
+
+        assert_eq!(remap.press(Key::U), None);
+        assert_eq!(remap.press(Key::Delete), None);
+        assert_eq!(remap.prompted(), 0x4);
+        assert_eq!(remap.status(), "press a key for 4, Delete to keep it, Escape to cancel");
+
+        // The keymap is only returned once the last key has been prompted for
+        for _ in 0..11 {
+            assert_eq!(remap.press(Key::Delete), None);
+        }
+        assert_eq!(remap.prompted(), 0xF);
+        let keymap = remap.press(Key::K).unwrap();
+
+        assert_eq!(decode(&keymap, Key::Q), [0x1]);
+        assert_eq!(decode(&keymap, Key::Key2), [0x2]);
+        assert_eq!(decode(&keymap, Key::U), [0x3]);
+        assert_eq!(decode(&keymap, Key::Key4), [0xC]);
+        assert!(keymap.keys[0x4].is_empty());
+        assert_eq!(decode(&keymap, Key::K), [0xF]);
+        assert!(decode(&keymap, Key::V).is_empty());
+    }
+
+    #[test]
+    fn the_prompted_key_is_highlighted() {
+        let mut remap = Remap::new(Keymap::default());
+        remap.press(Key::Delete);
+
+        let buffer = remap.draw(&[0; 80]);
+        assert_eq!(buffer.len(), 64 * 32);
+        // 1 is the first cell and 2 the second
+        assert_eq!(buffer[0], 0);
+        assert_eq!(buffer[16], 2);
+    }
+}
@@ -1,70 +1,667 @@
-use std::fs::File;
-use std::io::Read;
+use std::path::{Path, PathBuf};
 
-use std::io::*;
-
-mod cpu;
-mod display;
+mod cli;
+mod database;
+mod headless;
+mod scheduler;
+// The windowed frontend, and the input and debugging it drives
+#[cfg(feature = "window")]
+mod audio;
+#[cfg(feature = "window")]
+mod debugger;
+#[cfg(feature = "window")]
+mod gamepad;
+#[cfg(feature = "window")]
+mod gdb;
+#[cfg(feature = "window")]
+mod keymap;
+#[cfg(feature = "window")]
 mod window;
 
-use cpu::Cpu;
-use window::Window;
-
-const WIDTH: usize = 64;
-const HEIGHT: usize = 32;
+use chip8_core::audio::{AudioSink, NullSink, WavSink};
+use chip8_core::trace::{FileTracer, RingBufferTracer, StdoutTracer};
+use chip8_core::{asm, disasm, movie, random, rom, Cpu, Movie, Platform, Quirks, Random, RandomMode, Rom, RomError};
+use cli::{Command, RunArgs};
+use database::{Database, RomInfo};
+#[cfg(feature = "window")]
+use chip8_core::audio::Beeper;
+#[cfg(feature = "window")]
+use chip8_core::{display, CycleOutcome, Rewind};
+#[cfg(feature = "window")]
+use debugger::{Control, Debugger};
+#[cfg(feature = "window")]
+use gamepad::{Gamepads, Hotplug};
+#[cfg(feature = "window")]
+use gdb::GdbServer;
+#[cfg(feature = "window")]
+use keymap::{Keymap, Remap};
+#[cfg(feature = "window")]
+use scheduler::{Mode, Scheduler};
+#[cfg(feature = "window")]
+use window::{Hotkey, InputBackend, Window};
 
 // in Hz
-const CLOCK_SPEED: u32 = 540;
+const DEFAULT_CLOCK_SPEED: u32 = 540;
 const REFRESH_RATE: u32 = 60;
 
+// Default colours for each combination of the display's two bitplanes
+const PALETTE: [u32; 4] = [
+    0x00000000, // background
+    0x00ECF0F1, // first plane (the only one used outside XO-CHIP)
+    0x00E74C3C, // second plane
+    0x00F1C40F, // both planes
+];
+
+// Number of instructions leading up to a fault that are shown
+const CRASH_TRACE_LEN: usize = 32;
+
+// Number of save state slots, selected with F6/F7
+#[cfg(feature = "window")]
+const SAVE_SLOTS: u8 = 10;
+
+// How far back gameplay can be rewound, in seconds
+#[cfg(feature = "window")]
+const REWIND_SECONDS: u32 = 30;
+
 fn main() -> std::io::Result<()> {
-    if std::env::args().len() < 2 {
-        println!(
-            "Please provide a rom file. Example usage:\n\n  \
-            cargo run --release roms/breakout.ch8"
-        );
+    let args = std::env::args().skip(1).collect();
+
+    match cli::parse(args) {
+        Ok(Command::Run(args)) => run(*args),
+        Ok(Command::Disasm { rom, preset, load_address }) => {
+            let rom = read_rom(&rom);
+            let (platform, quirks) = preset.unwrap_or_else(|| {
+                match disasm::detect_platform(rom.bytes(), load_address) {
+                    Platform::Chip8 => (Platform::Chip8, Quirks::default()),
+                    Platform::SuperChip => (Platform::SuperChip, Quirks::superchip()),
+                    Platform::XoChip => (Platform::XoChip, Quirks::xochip()),
+                }
+            });
+            print!("{}", disasm::disassemble(rom.bytes(), load_address, platform, &quirks));
+            Ok(())
+        },
+        Ok(Command::Info { rom, database }) => {
+            info(&rom, &database);
+            Ok(())
+        },
+        Ok(Command::Bench { rom, platform, quirks, load_address, clock_hz, frames }) => {
+            let rom = read_rom(&rom);
 
-        return Ok(())
+            let mut cpu = Cpu::new(platform, quirks);
+            cpu.set_random(Random::new(RandomMode::Xorshift, 0));
+            load_rom(&mut cpu, &rom, load_address);
+
+            let ok = headless::bench(&mut cpu, frames, clock_hz);
+            std::process::exit(if ok { 0 } else { 1 });
+        },
+        Ok(Command::Assemble { source, output }) => assemble(&source, output),
+        Ok(Command::Help) => {
+            println!("{}", cli::USAGE);
+            Ok(())
+        },
+        Err(message) => usage_error(&message),
+    }
+}
+
+// Runs a rom in a window, or headless
+fn run(mut args: RunArgs) -> std::io::Result<()> {
+    let RunArgs { mut load_address, mut random_mode, .. } = args;
+    let path = &args.rom;
+
+    let rom = read_rom(path);
+
+    // Settings given on the command line take precedence over the database's
+    let info = lookup_rom(&rom, &args.database);
+    let (mut platform, mut quirks) = args.preset
+        .or_else(|| info.as_ref().map(|info| (info.platform, info.quirks)))
+        .unwrap_or((Platform::Chip8, Quirks::default()));
+    if let Some(key_wait_release) = args.key_wait_release {
+        quirks.key_wait_release = key_wait_release;
+    }
+    let mut clock_hz = args.clock_hz
+        .or_else(|| info.as_ref().and_then(|info| info.tickrate).map(|tickrate| tickrate.saturating_mul(REFRESH_RATE)))
+        .unwrap_or(DEFAULT_CLOCK_SPEED);
+    if let Some(info) = &info {
+        eprintln!("Using the settings for {} from the rom database", describe(info));
     }
 
-    let path = std::env::args().nth(1).unwrap();
-    let mut f = File::open(&path)?;
+    let mut seed = args.seed.unwrap_or_else(random::seed_from_entropy);
+
+    // A movie replays with the settings it was recorded with
+    let playback = match &args.play {
+        Some(play_path) => {
+            let movie = match Movie::parse(&std::fs::read_to_string(play_path)?) {
+                Ok(movie) => movie,
+                Err(err) => fail(&format!("{}: {}", play_path, err)),
+            };
+            if !movie.matches_rom(rom.bytes()) {
+                fail(&format!("{} was recorded with a different rom", play_path));
+            }
+
+            platform = movie.platform;
+            quirks = movie.quirks;
+            clock_hz = movie.clock_hz;
+            load_address = movie.load_address;
+            random_mode = movie.random_mode;
+            seed = movie.seed;
+            Some(movie)
+        },
+        None => None,
+    };
+    let recording = args.record.as_ref()
+        .map(|_| Movie::new(rom.bytes(), platform, quirks, load_address, clock_hz, random_mode, seed));
+
+    let mut cpu = Cpu::new(platform, quirks);
+    cpu.set_random(Random::new(random_mode, seed));
+    load_rom(&mut cpu, &rom, load_address);
+
+    // With `--trace crash` the last few instructions are kept to show what led
+    // up to a fault. It stays empty otherwise
+    let crash_trace = RingBufferTracer::new(CRASH_TRACE_LEN);
+    match args.trace.as_deref() {
+        Some("stdout") => cpu.set_tracer(StdoutTracer),
+        Some("crash") => cpu.set_tracer(crash_trace.clone()),
+        Some(trace_path) => cpu.set_tracer(FileTracer::create(trace_path)?),
+        None => (),
+    }
+
+    if let Some(mut options) = args.headless.take() {
+        options.clock_hz = clock_hz;
+
+        let mut audio_sink: Box<dyn AudioSink> = match &args.wav {
+            Some(wav_path) => Box::new(WavSink::create(wav_path, 44100)?),
+            None => Box::new(NullSink),
+        };
+
+        let ok = match &playback {
+            Some(movie) => headless::verify(&mut cpu, movie, audio_sink.as_mut()),
+            None => headless::run(&mut cpu, &options, audio_sink.as_mut()),
+        };
+        if !ok {
+            dump_crash_trace(&crash_trace);
+        }
+
+        // Make sure the audio and trace are finished writing before exiting
+        finish_output(&mut cpu, audio_sink.as_mut());
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+
+    run_window(args, info, cpu, clock_hz, crash_trace, playback, recording)
+}
+
+// Runs the cpu in a window until it is closed, with the keyboard and gamepads
+// as input
+#[cfg(feature = "window")]
+fn run_window(
+    args: RunArgs,
+    info: Option<RomInfo>,
+    mut cpu: Cpu,
+    clock_hz: u32,
+    crash_trace: RingBufferTracer,
+    playback: Option<Movie>,
+    mut recording: Option<Movie>,
+) -> std::io::Result<()> {
+    let path = &args.rom;
+    let palette = args.palette
+        .or_else(|| info.as_ref().and_then(|info| info.palette))
+        .unwrap_or(PALETTE);
+    let mut audio_sink: Box<dyn AudioSink> = match &args.wav {
+        Some(wav_path) => Box::new(WavSink::create(wav_path, 44100)?),
+        None => audio::default_sink(),
+    };
+
+    // The global keymap, which remapping saves to, is overridden by the rom's
+    let keymap_path = args.keymap.as_ref().map(PathBuf::from).or_else(Keymap::global_path);
+    let keymap = Keymap::load(keymap_path.as_deref(), &Keymap::rom_path(Path::new(path)))
+        .unwrap_or_else(|message| fail(&message));
+
+    let mut window = Window::new(display::LORES_WIDTH, display::LORES_HEIGHT, args.scale, palette, keymap);
+    let buttons = info.as_ref().map(|info| info.buttons.as_slice()).unwrap_or_default();
+    window.bind_buttons(buttons);
+
+    // Gamepads are bound in the same files as the keyboard, under `[gamepad]`
+    let bindings = gamepad::load_bindings(keymap_path.as_deref(), buttons, &Keymap::rom_path(Path::new(path)))
+        .unwrap_or_else(|message| fail(&message));
+    let mut gamepads = Gamepads::new(gamepad::default_source(), bindings);
+
+    let mut beeper = Beeper::new(audio_sink.sample_rate());
+    let mut scheduler = Scheduler::new(clock_hz);
+
+    // The title shows the measured speed and the latest status message, and is
+    // only updated when it changes
+    let mut status = String::new();
+    let mut title = String::new();
+
+    // Once the cpu faults or the program exits it is halted, but the window is
+    // kept open showing the last frame so it can be inspected
+    let mut halted = false;
 
-    let mut buffer = Vec::new();
-    f.read_to_end(&mut buffer)?;
+    let mut control: Option<Box<dyn Control>> = match (args.debug, args.gdb_port) {
+        (true, _) => Some(Box::new(Debugger::new(&mut cpu))),
+        (false, Some(port)) => Some(Box::new(GdbServer::listen(port)?)),
+        (false, None) => None,
+    };
 
-    let mut cpu = Cpu::new();
-    cpu.load_binary(&buffer);
+    // Save states are written next to the rom, e.g. `breakout.state0`
+    let mut slot = 0;
 
-    let mut window = Window::new(WIDTH, HEIGHT);
-    window.set_title(&format!("CHIP-8 ({})", path));
+    // A snapshot is taken every frame, and restored one per frame when rewinding
+    let mut rewind = Rewind::new((REWIND_SECONDS * REFRESH_RATE) as usize);
 
-    let redraw_interval = (1000.0 / REFRESH_RATE as f64) as u64;
-    let cycles_per_refresh = CLOCK_SPEED / REFRESH_RATE;
+    // Movies need every frame to run in order at the same speed, so rewinding,
+    // loading states and changing the clock speed are disabled while recording
+    // or playing one back
+    let movie_active = recording.is_some() || playback.is_some();
+    let mut movie_frame = 0;
+
+    // While remapping keys the emulator is paused, and the remapping screen is
+    // shown instead of the display
+    let mut remap: Option<Remap> = None;
+    let mut resume_after_remap = false;
 
     while window.is_open() {
-        let now = std::time::Instant::now();
+        for hotplug in gamepads.poll() {
+            status = match hotplug {
+                Hotplug::Connected(name) => format!("connected {}", name),
+                Hotplug::Disconnected(name) => format!("disconnected {}", name),
+            };
+        }
+
+        if let Some(active) = remap.as_mut() {
+            let mut finished = None;
+            for host_key in window.get_host_keys_pressed() {
+                if host_key == minifb::Key::Escape {
+                    finished = Some("remapping cancelled".to_string());
+                } else if let Some(keymap) = active.press(host_key) {
+                    let saved = match &keymap_path {
+                        Some(keymap_path) => keymap.save(keymap_path)
+                            .map(|()| format!("keymap saved to {}", keymap_path.display()))
+                            .unwrap_or_else(|err| format!("failed to save keymap: {}", err)),
+                        None => "keymap not saved, there's no config directory".to_string(),
+                    };
+                    window.set_keymap(keymap);
+                    finished = Some(saved);
+                }
+
+                if finished.is_some() {
+                    break;
+                }
+            }
 
-        if let Some(keys) = window.get_keys_pressed() {
-            cpu.update_keyboard(&keys);
-            cpu.key_press_interrupt(keys[0]);
-        } else {
-            cpu.update_keyboard(&[]);
+            match finished {
+                Some(message) => {
+                    status = message;
+                    remap = None;
+                    if resume_after_remap {
+                        scheduler.toggle_pause();
+                    }
+                },
+                None => {
+                    // The font sits at the start of memory
+                    window.update(&active.draw(&cpu.memory()[..80]), display::LORES_WIDTH, display::LORES_HEIGHT);
+                    let new_title = format!("CHIP-8 - {}", active.status());
+                    if new_title != title {
+                        window.set_title(&new_title);
+                        title = new_title;
+                    }
+
+                    scheduler.sleep();
+                    continue;
+                },
+            }
         }
 
-        for _ in 0..cycles_per_refresh {
-            cpu.cycle();
+        for hotkey in window.get_hotkeys() {
+            let state_path = Path::new(path).with_extension(format!("state{}", slot));
+            status = match hotkey {
+                Hotkey::SaveState => match std::fs::write(&state_path, cpu.save_state()) {
+                    Ok(()) => format!("saved slot {}", slot),
+                    Err(err) => format!("failed to save slot {}: {}", slot, err),
+                },
+                Hotkey::LoadState if movie_active => "can't load states during a movie".to_string(),
+                Hotkey::LoadState => {
+                    let loaded = std::fs::read(&state_path)
+                        .map_err(|err| err.to_string())
+                        .and_then(|state| cpu.load_state(&state).map_err(|err| err.to_string()));
+
+                    match loaded {
+                        Ok(()) => {
+                            halted = false;
+                            format!("loaded slot {}", slot)
+                        },
+                        Err(err) => format!("failed to load slot {}: {}", slot, err),
+                    }
+                },
+                Hotkey::PreviousSlot => {
+                    slot = (slot + SAVE_SLOTS - 1) % SAVE_SLOTS;
+                    format!("slot {}", slot)
+                },
+                Hotkey::NextSlot => {
+                    slot = (slot + 1) % SAVE_SLOTS;
+                    format!("slot {}", slot)
+                },
+                Hotkey::Remap => {
+                    resume_after_remap = !scheduler.is_paused();
+                    if resume_after_remap {
+                        scheduler.toggle_pause();
+                    }
+                    remap = Some(Remap::new(window.keymap().clone()));
+                    String::new()
+                },
+                Hotkey::TogglePause => {
+                    scheduler.toggle_pause();
+                    String::new()
+                },
+                Hotkey::AdvanceFrame => {
+                    scheduler.advance_frame();
+                    String::new()
+                },
+                Hotkey::ToggleFastForward => {
+                    scheduler.toggle_mode(Mode::FastForward);
+                    String::new()
+                },
+                Hotkey::ToggleSlowMotion => {
+                    scheduler.toggle_mode(Mode::SlowMotion);
+                    String::new()
+                },
+                Hotkey::SpeedUp | Hotkey::SlowDown if movie_active => {
+                    "can't change speed during a movie".to_string()
+                },
+                // One instruction per frame more or less
+                Hotkey::SpeedUp => {
+                    scheduler.set_clock_hz(scheduler.clock_hz().saturating_add(REFRESH_RATE));
+                    String::new()
+                },
+                Hotkey::SlowDown => {
+                    scheduler.set_clock_hz(scheduler.clock_hz().saturating_sub(REFRESH_RATE).max(REFRESH_RATE));
+                    String::new()
+                },
+            };
+        }
+
+        if let Some(control) = control.as_mut() {
+            control.poll(&mut cpu);
+            if control.killed() {
+                break;
+            }
+        }
+
+        // Several frames are run back to back if the last iteration took too
+        // long, so the timers keep ticking at 60Hz
+        while scheduler.frame_due() {
+            // Input comes from the movie being played back while it lasts
+            let keys = match playback.as_ref().and_then(|movie| movie.frames.get(movie_frame)) {
+                Some(frame) => {
+                    frame.apply(&mut cpu);
+                    frame.keys.clone()
+                },
+                None => {
+                    let mut keys = window.keys_pressed();
+                    keys.extend(gamepads.keys_pressed());
+                    keys.sort_unstable();
+                    keys.dedup();
+                    cpu.update_keyboard(&keys);
+                    keys
+                },
+            };
+
+            let rewinding = window.is_rewinding() && !movie_active;
+            if rewinding {
+                match rewind.pop(&mut cpu) {
+                    Ok(true) => halted = false,
+                    Ok(false) => (),
+                    Err(err) => eprintln!("Failed to rewind: {}", err),
+                }
+            }
+
+            for _ in 0..scheduler.cycles_for_frame() {
+                if halted || rewinding {
+                    break;
+                }
+
+                if let Some(control) = control.as_mut() {
+                    if !control.can_cycle(&cpu) {
+                        break;
+                    }
+                }
+
+                let outcome = cpu.cycle();
+                if let Some(control) = control.as_mut() {
+                    control.cycled(&cpu, &outcome);
+                }
+
+                match outcome {
+                    Ok(CycleOutcome::Exited) => {
+                        status = "exited".to_string();
+                        halted = true;
+                    },
+                    Ok(_) => (),
+                    Err(err) => {
+                        eprintln!("CPU fault: {}", err);
+                        dump_crash_trace(&crash_trace);
+                        status = format!("halted: {}", err);
+                        halted = true;
+                    },
+                }
+            }
+
+            // Time stands still while paused in the debugger
+            let paused = control.as_ref().is_some_and(|control| control.paused());
+            if paused || rewinding {
+                continue;
+            }
+
+            let samples = beeper.frame(cpu.sound_active(), cpu.audio_pattern());
+            audio_sink.play(&samples);
+
+            cpu.timer_interrupt();
+
+            if !halted {
+                rewind.push(&cpu);
+            }
+
+            if let Some(movie) = recording.as_mut() {
+                movie.record(&keys, &cpu);
+            }
+
+            if let Some(movie) = playback.as_ref() {
+                match movie.frames.get(movie_frame) {
+                    Some(frame) if !frame.matches(&cpu) => {
+                        eprintln!("Movie diverged at frame {}", movie_frame);
+                        status = format!("movie diverged at frame {}", movie_frame);
+                    },
+                    Some(_) if movie_frame + 1 == movie.frames.len() => status = "movie finished".to_string(),
+                    _ => (),
+                }
+            }
+            movie_frame += 1;
         }
 
-        let cpu_elapsed = now.elapsed().as_millis() as u64;
+        let (width, height) = cpu.display_size();
+        window.update(cpu.display_buffer(), width, height);
+
+        let name = info.as_ref().map_or(path.as_str(), |info| info.title.as_str());
+        let new_title = window_title(name, &scheduler, &status);
+        if new_title != title {
+            window.set_title(&new_title);
+            title = new_title;
+        }
 
-        // Ensures opcodes are printed, for debugging
-        std::io::stdout().flush()?;
+        scheduler.sleep();
+    }
 
-        std::thread::sleep(std::time::Duration::from_millis(redraw_interval - cpu_elapsed));
-        cpu.timer_interrupt();
-        window.update(cpu.display_buffer());
+    finish_output(&mut cpu, audio_sink.as_mut());
+    if let (Some(movie), Some(record_path)) = (recording, args.record) {
+        std::fs::write(record_path, movie.to_string())?;
     }
 
     Ok(())
 }
+
+// Without the `window` feature only headless runs are possible, which the
+// command line parser checks
+#[cfg(not(feature = "window"))]
+fn run_window(
+    _args: RunArgs,
+    _info: Option<RomInfo>,
+    _cpu: Cpu,
+    _clock_hz: u32,
+    _crash_trace: RingBufferTracer,
+    _playback: Option<Movie>,
+    _recording: Option<Movie>,
+) -> std::io::Result<()> {
+    unreachable!("windowed run in a build without a window")
+}
+
+// Assembles a source file, writing the rom next to it unless `output` is given
+fn assemble(source: &str, output: Option<String>) -> std::io::Result<()> {
+    let source = Path::new(source);
+    let output = output.map(PathBuf::from).unwrap_or_else(|| source.with_extension("ch8"));
+
+    match asm::assemble_file(source, rom::DEFAULT_LOAD_ADDRESS) {
+        Ok(program) => std::fs::write(output, program),
+        Err(err) => fail(&err.to_string()),
+    }
+}
+
+// Prints a rom's size, hashes and the platform it seems to be written for,
+// along with its entry in the rom database
+fn info(path: &str, database: &cli::Database) {
+    let rom = read_rom(path);
+
+    println!("size: {} bytes", rom.len());
+    println!("sha1: {}", rom.sha1());
+    println!("hash: {:016x} (FNV-1a)", movie::fnv1a(rom.bytes()));
+    println!("platform: {}", disasm::detect_platform(rom.bytes(), rom::DEFAULT_LOAD_ADDRESS));
+
+    match lookup_rom(&rom, database) {
+        Some(info) => {
+            println!("title: {}", describe(&info));
+            println!("database platform: {}", info.platform);
+            if let Some(tickrate) = info.tickrate {
+                println!("tickrate: {} ipf", tickrate);
+            }
+        },
+        None => println!("title: unknown, not in the rom database"),
+    }
+}
+
+// Looks a rom up in the database, exiting if a database given on the command
+// line can't be loaded
+fn lookup_rom(rom: &Rom, database: &cli::Database) -> Option<RomInfo> {
+    let database = match database {
+        cli::Database::Bundled => Database::bundled(),
+        cli::Database::Dir(dir) => Database::load(Path::new(dir)).unwrap_or_else(|message| fail(&message)),
+        cli::Database::Disabled => return None,
+    };
+
+    database.lookup(rom.sha1())
+}
+
+// e.g. `Breakout by Carmelo Cortez (1979)`
+fn describe(info: &RomInfo) -> String {
+    let mut description = info.title.clone();
+    if !info.authors.is_empty() {
+        description += &format!(" by {}", info.authors.join(", "));
+    }
+    if let Some(release) = &info.release {
+        description += &format!(" ({})", release);
+    }
+    description
+}
+
+// Reads a rom, exiting with an explanation if it can't be
+fn read_rom(path: &str) -> Rom {
+    let rom = match Rom::read(path) {
+        Ok(rom) => rom,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => fail(&format!("ROM '{}' doesn't exist", path)),
+        Err(err) => fail(&format!("Couldn't read ROM '{}': {}", path, err)),
+    };
+
+    if rom.is_empty() {
+        fail(&format!("ROM '{}' is empty", path));
+    }
+
+    rom
+}
+
+// Loads a rom into memory, printing any warnings about it, or exits with an
+// explanation if it doesn't fit
+fn load_rom(cpu: &mut Cpu, rom: &Rom, load_address: u16) {
+    match cpu.load_binary_at(rom.bytes(), load_address) {
+        Ok(warnings) => {
+            for warning in warnings {
+                eprintln!("Warning: {}", warning);
+            }
+        },
+        Err(err @ RomError::TooLarge { platform, .. }) if platform != Platform::XoChip => {
+            fail(&format!("{} (XO-CHIP has 64K, try --quirks xochip)", err))
+        },
+        Err(err) => fail(&err.to_string()),
+    }
+}
+
+// e.g. `CHIP-8 (Breakout) - 9 ipf - 100% - saved slot 0`, named after the rom's
+// title in the database or its path otherwise
+#[cfg(feature = "window")]
+fn window_title(name: &str, scheduler: &Scheduler, status: &str) -> String {
+    let mut title = format!("CHIP-8 ({})", name);
+
+    let clock_hz = scheduler.clock_hz();
+    if clock_hz % REFRESH_RATE == 0 {
+        title += &format!(" - {} ipf", clock_hz / REFRESH_RATE);
+    } else {
+        title += &format!(" - {:.1} ipf", clock_hz as f64 / REFRESH_RATE as f64);
+    }
+
+    if scheduler.is_paused() {
+        title += " - paused";
+    } else {
+        match scheduler.mode() {
+            Mode::Normal => (),
+            Mode::FastForward => title += " - fast forward",
+            Mode::SlowMotion => title += " - slow motion",
+        }
+        if let Some(speed) = scheduler.speed() {
+            title += &format!(" - {:.0}%", speed * 100.0);
+        }
+    }
+
+    if !status.is_empty() {
+        title += &format!(" - {}", status);
+    }
+
+    title
+}
+
+// Finishes writing the audio and trace, reporting any errors along the way
+fn finish_output(cpu: &mut Cpu, audio_sink: &mut dyn AudioSink) {
+    if let Err(err) = audio_sink.finish() {
+        eprintln!("Failed to write audio: {}", err);
+    }
+    if let Err(err) = cpu.clear_tracer() {
+        eprintln!("Failed to write trace: {}", err);
+    }
+}
+
+fn dump_crash_trace(crash_trace: &RingBufferTracer) {
+    if crash_trace.entries().is_empty() {
+        return;
+    }
+
+    eprintln!("Last instructions executed:");
+    if let Err(err) = crash_trace.dump(&mut std::io::stderr()) {
+        eprintln!("Failed to write trace: {}", err);
+    }
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    eprintln!("Run with --help to see the usage");
+    std::process::exit(2);
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
@@ -0,0 +1,258 @@
+#[cfg(feature = "window")]
+use std::time::{Duration, Instant};
+
+use crate::REFRESH_RATE;
+
+// The most frames run at once to catch up after a stall, e.g. when the window
+// is being dragged. Any more are dropped, and emulation falls behind instead
+#[cfg(feature = "window")]
+const MAX_CATCH_UP_FRAMES: u32 = 6;
+
+// How many times longer each frame takes in slow motion
+#[cfg(feature = "window")]
+const SLOW_MOTION_FACTOR: u32 = 4;
+
+// How often the measured speed is updated
+#[cfg(feature = "window")]
+const MEASURE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Spreads a clock speed over 60Hz frames, carrying the remainder over between
+// frames so that clock speeds that aren't a multiple of 60Hz still average out.
+// Headless runs use it directly, so they run the same cycles as the window
+pub struct FrameCycles {
+    clock_hz: u32,
+    // In sixtieths of a cycle
+    remainder: u32,
+}
+
+impl FrameCycles {
+    pub fn new(clock_hz: u32) -> FrameCycles {
+        FrameCycles { clock_hz, remainder: 0 }
+    }
+
+    // Number of cycles to run in the next frame. The sum is widened so that
+    // clock speeds near `u32::MAX` don't overflow
+    pub fn for_frame(&mut self) -> u32 {
+        let total = self.remainder as u64 + self.clock_hz as u64;
+        self.remainder = (total % REFRESH_RATE as u64) as u32;
+        (total / REFRESH_RATE as u64) as u32
+    }
+}
+
+// How fast frames are run compared to real time
+#[cfg(feature = "window")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Normal,
+    // As many frames as fit in each display refresh
+    FastForward,
+    // `SLOW_MOTION_FACTOR` times slower than normal
+    SlowMotion,
+}
+
+// Paces emulation against real time. Frames fall due at exactly 60Hz however
+// long each loop iteration takes, with the cpu running at the clock speed
+// spread evenly over them and the timers ticking once per frame.
+//
+// Each iteration of the main loop calls `frame_due` until it returns false,
+// running a frame each time, then draws the display and calls `sleep`.
+#[cfg(feature = "window")]
+pub struct Scheduler {
+    cycles: FrameCycles,
+    mode: Mode,
+    paused: bool,
+    // Frames to run while paused, one per press of the frame advance hotkey
+    advance_frames: u32,
+    // When the next frame falls due
+    next_frame: Instant,
+    iteration_start: Instant,
+    iteration_frames: u32,
+    measure_start: Instant,
+    measured_frames: u32,
+    speed: Option<f64>,
+}
+
+#[cfg(feature = "window")]
+impl Scheduler {
+    pub fn new(clock_hz: u32) -> Scheduler {
+        let now = Instant::now();
+
+        Scheduler {
+            cycles: FrameCycles::new(clock_hz),
+            mode: Mode::Normal,
+            paused: false,
+            advance_frames: 0,
+            next_frame: now,
+            iteration_start: now,
+            iteration_frames: 0,
+            measure_start: now,
+            measured_frames: 0,
+            speed: None,
+        }
+    }
+
+    // Whether another frame should be run before drawing the display. If more
+    // than `MAX_CATCH_UP_FRAMES` are late the rest are dropped, which shows up
+    // as a drop in the measured speed
+    pub fn frame_due(&mut self) -> bool {
+        let now = Instant::now();
+
+        if self.paused {
+            if self.advance_frames == 0 {
+                return false;
+            }
+
+            self.advance_frames -= 1;
+            return self.run_frame();
+        }
+
+        match self.mode {
+            Mode::FastForward => {
+                // The first frame always runs, however slow it is
+                if self.iteration_frames > 0 && now - self.iteration_start >= self.frame_duration(Mode::Normal) {
+                    return false;
+                }
+
+                self.next_frame = now;
+                self.run_frame()
+            },
+            Mode::Normal | Mode::SlowMotion => {
+                if self.next_frame > now {
+                    return false;
+                }
+
+                let frame_duration = self.frame_duration(self.mode);
+                if self.iteration_frames == MAX_CATCH_UP_FRAMES {
+                    let behind = now - self.next_frame;
+                    let dropped = (behind.as_nanos() / frame_duration.as_nanos()) as u32 + 1;
+                    self.next_frame += frame_duration * dropped;
+                    return false;
+                }
+
+                self.next_frame += frame_duration;
+                self.run_frame()
+            },
+        }
+    }
+
+    // Number of cycles to run in the next frame
+    pub fn cycles_for_frame(&mut self) -> u32 {
+        self.cycles.for_frame()
+    }
+
+    // Sleeps until the next frame falls due, ending the iteration
+    pub fn sleep(&mut self) {
+        let now = Instant::now();
+        self.measure(now);
+
+        if self.mode != Mode::FastForward && !self.paused && self.next_frame > now {
+            std::thread::sleep(self.next_frame - now);
+        } else if self.paused {
+            // Stay responsive to hotkeys without spinning
+            std::thread::sleep(self.frame_duration(Mode::Normal));
+        }
+
+        self.iteration_start = Instant::now();
+        self.iteration_frames = 0;
+    }
+
+    pub fn clock_hz(&self) -> u32 {
+        self.cycles.clock_hz
+    }
+
+    pub fn set_clock_hz(&mut self, clock_hz: u32) {
+        self.cycles.clock_hz = clock_hz;
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    // Switches to `mode`, or back to normal if it is already in `mode`
+    pub fn toggle_mode(&mut self, mode: Mode) {
+        self.mode = if self.mode == mode { Mode::Normal } else { mode };
+        self.next_frame = Instant::now();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+        self.advance_frames = 0;
+
+        // Time spent paused isn't caught up on
+        self.next_frame = Instant::now();
+    }
+
+    // Runs a single frame while paused
+    pub fn advance_frame(&mut self) {
+        if self.paused {
+            self.advance_frames += 1;
+        }
+    }
+
+    // Emulated time as a fraction of real time over the last second, once a
+    // second has been measured
+    pub fn speed(&self) -> Option<f64> {
+        self.speed
+    }
+
+    fn run_frame(&mut self) -> bool {
+        self.iteration_frames += 1;
+        self.measured_frames += 1;
+        true
+    }
+
+    fn frame_duration(&self, mode: Mode) -> Duration {
+        let frame_duration = Duration::from_secs(1) / REFRESH_RATE;
+        match mode {
+            Mode::SlowMotion => frame_duration * SLOW_MOTION_FACTOR,
+            Mode::Normal | Mode::FastForward => frame_duration,
+        }
+    }
+
+    fn measure(&mut self, now: Instant) {
+        let elapsed = now - self.measure_start;
+        if elapsed >= MEASURE_INTERVAL {
+            let emulated = self.frame_duration(Mode::Normal) * self.measured_frames;
+            self.speed = Some(emulated.as_secs_f64() / elapsed.as_secs_f64());
+            self.measure_start = now;
+            self.measured_frames = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(clock_hz: u32, count: usize) -> Vec<u32> {
+        let mut cycles = FrameCycles::new(clock_hz);
+        (0..count).map(|_| cycles.for_frame()).collect()
+    }
+
+    #[test]
+    fn multiples_of_the_refresh_rate() {
+        assert_eq!(frames(540, 4), [9, 9, 9, 9]);
+        assert_eq!(frames(60, 3), [1, 1, 1]);
+    }
+
+    #[test]
+    fn remainders_carry_over() {
+        assert_eq!(frames(100, 6), [1, 2, 2, 1, 2, 2]);
+        assert_eq!(frames(100, 60).iter().sum::<u32>(), 100);
+        assert_eq!(frames(30, 4), [0, 1, 0, 1]);
+        assert_eq!(frames(1, 60).iter().sum::<u32>(), 1);
+        assert_eq!(frames(599, 60).iter().sum::<u32>(), 599);
+    }
+
+    #[test]
+    fn huge_clock_speeds() {
+        let per_frame = u32::MAX / REFRESH_RATE;
+        let cycles = frames(u32::MAX, 60);
+        assert!(cycles.iter().all(|n| *n == per_frame || *n == per_frame + 1));
+        assert_eq!(cycles.iter().map(|n| *n as u64).sum::<u64>(), u32::MAX as u64);
+    }
+}
@@ -1,15 +1,51 @@
-use minifb;
+use crate::database::Button;
+use crate::keymap::Keymap;
+
+// Emulator controls, as opposed to keys on the CHIP-8 keypad
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hotkey {
+    SaveState,
+    LoadState,
+    PreviousSlot,
+    NextSlot,
+    TogglePause,
+    AdvanceFrame,
+    ToggleFastForward,
+    ToggleSlowMotion,
+    SpeedUp,
+    SlowDown,
+    Remap,
+}
+
+// Somewhere the keypad keys held down come from, like the window's keyboard or
+// the gamepads
+pub trait InputBackend {
+    fn keys_pressed(&mut self) -> Vec<u8>;
+}
 
 pub struct Window {
     window: minifb::Window,
-    width: usize,
-    height: usize,
+    palette: [u32; 4],
+    keymap: Keymap,
+    // Arrow keys, space and enter mapped to keypad keys for the current rom
+    buttons: Vec<(minifb::Key, u8)>,
 }
 
 impl Window {
-    pub fn new(width: usize, height: usize) -> Window {
-        let mut options = minifb::WindowOptions::default();
-        options.scale = minifb::Scale::X8;
+    // `scale` is one of the scales checked by the command line parser
+    pub fn new(width: usize, height: usize, scale: u32, palette: [u32; 4], keymap: Keymap) -> Window {
+        let scale = match scale {
+            1 => minifb::Scale::X1,
+            2 => minifb::Scale::X2,
+            4 => minifb::Scale::X4,
+            16 => minifb::Scale::X16,
+            32 => minifb::Scale::X32,
+            _ => minifb::Scale::X8,
+        };
+        let options = minifb::WindowOptions {
+            scale,
+            ..minifb::WindowOptions::default()
+        };
 
         let window = minifb::Window::new(
             "",
@@ -18,20 +54,48 @@ impl Window {
             options,
         ).unwrap();
 
-        Window { window, width, height }
+        Window { window, palette, keymap, buttons: Vec::new() }
+    }
+
+    pub fn keymap(&self) -> &Keymap {
+        &self.keymap
+    }
+
+    pub fn set_keymap(&mut self, keymap: Keymap) {
+        self.keymap = keymap;
+    }
+
+    // Maps the arrow keys to the rom's directions, space to its A button and
+    // enter to its B button, on top of the usual keypad layout
+    pub fn bind_buttons(&mut self, buttons: &[(Button, u8)]) {
+        self.buttons = buttons.iter()
+            .map(|(button, key)| {
+                let host_key = match button {
+                    Button::Up => minifb::Key::Up,
+                    Button::Down => minifb::Key::Down,
+                    Button::Left => minifb::Key::Left,
+                    Button::Right => minifb::Key::Right,
+                    Button::A => minifb::Key::Space,
+                    Button::B => minifb::Key::Enter,
+                };
+                (host_key, *key)
+            })
+            .collect();
     }
 
     pub fn set_title(&mut self, title: &str) {
         self.window.set_title(title);
     }
 
-    pub fn update(&mut self, buffer: &[bool]) {
+    // The buffer is stretched to fit the window, so the display's resolution
+    // can change without resizing the window
+    pub fn update(&mut self, buffer: &[u8], width: usize, height: usize) {
         let buffer: Vec<u32> = buffer.iter()
-            .map(|x| if *x { 0x00ECF0F1 } else { 0 })
+            .map(|x| self.palette[*x as usize])
             .collect();
 
         self.window
-            .update_with_buffer(&buffer, self.width, self.height)
+            .update_with_buffer(&buffer, width, height)
             .unwrap();
     }
 
@@ -40,33 +104,56 @@ impl Window {
     }
 
     pub fn get_keys_pressed(&mut self) -> Option<Vec<u8>> {
+        let (keymap, buttons) = (&self.keymap, &self.buttons);
         self.window.get_keys()
-            .map(|keys| keys.iter().filter_map(|k| Self::decode_key(&k)).collect())
+            .map(|keys| {
+                keys.iter()
+                    .flat_map(|host_key| {
+                        let button_keys = buttons.iter().filter(move |(button_key, _)| button_key == host_key);
+                        keymap.decode(*host_key).chain(button_keys.map(|(_, key)| *key))
+                    })
+                    .collect()
+            })
             .filter(|keys: &Vec<u8>| !keys.is_empty())
     }
 
-    fn decode_key(key: &minifb::Key) -> Option<u8> {
+    // Rewinding lasts for as long as backspace is held
+    pub fn is_rewinding(&self) -> bool {
+        self.window.is_key_down(minifb::Key::Backspace)
+    }
+
+    // Hotkeys pressed since the last update, ignoring key repeats
+    pub fn get_hotkeys(&self) -> Vec<Hotkey> {
+        self.window.get_keys_pressed(minifb::KeyRepeat::No)
+            .map(|keys| keys.iter().filter_map(Self::decode_hotkey).collect())
+            .unwrap_or_default()
+    }
+
+    // Keys pressed since the last update, ignoring key repeats and the keymap
+    pub fn get_host_keys_pressed(&self) -> Vec<minifb::Key> {
+        self.window.get_keys_pressed(minifb::KeyRepeat::No).unwrap_or_default()
+    }
+
+    fn decode_hotkey(key: &minifb::Key) -> Option<Hotkey> {
         match key {
-            minifb::Key::Key1 => Some(0x1),
-            minifb::Key::Key2 => Some(0x2),
-            minifb::Key::Key3 => Some(0x3),
-            minifb::Key::Key4 => Some(0xC),
-
-            minifb::Key::Q => Some(0x4),
-            minifb::Key::W => Some(0x5),
-            minifb::Key::E => Some(0x6),
-            minifb::Key::R => Some(0xD),
-
-            minifb::Key::A => Some(0x7),
-            minifb::Key::S => Some(0x8),
-            minifb::Key::D => Some(0x9),
-            minifb::Key::F => Some(0xE),
-
-            minifb::Key::Z => Some(0xA),
-            minifb::Key::X => Some(0x0),
-            minifb::Key::C => Some(0xB),
-            minifb::Key::V => Some(0xF),
+            minifb::Key::F2 => Some(Hotkey::Remap),
+            minifb::Key::F5 => Some(Hotkey::SaveState),
+            minifb::Key::F6 => Some(Hotkey::PreviousSlot),
+            minifb::Key::F7 => Some(Hotkey::NextSlot),
+            minifb::Key::F9 => Some(Hotkey::LoadState),
+            minifb::Key::P => Some(Hotkey::TogglePause),
+            minifb::Key::N => Some(Hotkey::AdvanceFrame),
+            minifb::Key::Tab => Some(Hotkey::ToggleFastForward),
+            minifb::Key::M => Some(Hotkey::ToggleSlowMotion),
+            minifb::Key::Equal => Some(Hotkey::SpeedUp),
+            minifb::Key::Minus => Some(Hotkey::SlowDown),
             _ => None,
         }
     }
 }
+
+impl InputBackend for Window {
+    fn keys_pressed(&mut self) -> Vec<u8> {
+        self.get_keys_pressed().unwrap_or_default()
+    }
+}